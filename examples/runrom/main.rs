@@ -1,7 +1,14 @@
 use anyhow::{anyhow, Result};
 use egui::{Color32, Pos2, RichText, TextureId, Ui};
 use nes::NESBoard;
-use nes_rust::{cartidge::CartridgeData, cpu::*};
+use nes_rust::{
+    apu::wav::{WavChannels, WavSampleFormat, WavWriter},
+    cartidge::{mapper, CartridgeData},
+    cpu::*,
+    ppu::{debug, events},
+    region::Region,
+    video::{overscan::Overscan, png, scale::Scaler},
+};
 use std::sync::{
     atomic::{AtomicU16, AtomicU32, AtomicU8, AtomicUsize},
     Arc, RwLock,
@@ -60,6 +67,236 @@ fn draw_cpu(ui: &mut Ui, cpu: &Cpu) {
     });
 }
 
+/// Command line: `runrom <rom> [--wav <out.wav>] [--vgm <out.vgm>] [--seconds <n>]
+/// [--region ntsc|pal|dendy] [--palette <file.pal>] [--no-sprite-limit] [--crop-overscan]
+/// [--screenshot <out.png>] [--scale <scaler>]`
+/// Passing `--wav`, `--vgm` or `--screenshot` runs the rom headlessly instead of opening a window,
+/// `--screenshot` saving the last frame once it's done. `--scale` upscales screenshots, those taken
+/// with F12 included, with one of nearest2, nearest3, nearest4, scale2x, scale3x, hq2x, hq3x,
+/// xbr2x, xbr3x or aspect. The region
+/// defaults to the one in the rom's header, and the palette to the one of the header's ppu.
+/// `--no-sprite-limit` draws every sprite on a scanline instead of flickering past 8.
+/// `--crop-overscan` cuts off the top and bottom 8 lines and the left 8 pixels.
+struct Options {
+    rom_path: String,
+    wav_path: Option<String>,
+    vgm_path: Option<String>,
+    seconds: f64,
+    region: Option<Region>,
+    palette_path: Option<String>,
+    no_sprite_limit: bool,
+    crop_overscan: bool,
+    screenshot_path: Option<String>,
+    scale: Option<Scaler>,
+}
+
+impl Options {
+    fn from_args() -> Self {
+        let mut args = std::env::args();
+        _ = args.next();
+        let mut rom_path = None;
+        let mut wav_path = None;
+        let mut vgm_path = None;
+        let mut seconds = 10.0;
+        let mut region = None;
+        let mut palette_path = None;
+        let mut no_sprite_limit = false;
+        let mut crop_overscan = false;
+        let mut screenshot_path = None;
+        let mut scale = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--wav" => wav_path = Some(args.next().expect("--wav needs an output path")),
+                "--vgm" => vgm_path = Some(args.next().expect("--vgm needs an output path")),
+                "--seconds" => {
+                    seconds = args
+                        .next()
+                        .and_then(|s| s.parse().ok())
+                        .expect("--seconds needs a number")
+                }
+                "--palette" => {
+                    palette_path = Some(args.next().expect("--palette needs a .pal file path"))
+                }
+                "--region" => {
+                    region = match args.next().as_deref() {
+                        Some("ntsc") => Some(Region::Ntsc),
+                        Some("pal") => Some(Region::Pal),
+                        Some("dendy") => Some(Region::Dendy),
+                        _ => panic!("--region needs one of ntsc, pal or dendy"),
+                    }
+                }
+                "--no-sprite-limit" => no_sprite_limit = true,
+                "--crop-overscan" => crop_overscan = true,
+                "--screenshot" => {
+                    screenshot_path =
+                        Some(args.next().expect("--screenshot needs an output path"))
+                }
+                "--scale" => {
+                    scale = match args.next().as_deref() {
+                        Some("nearest2") => Some(Scaler::Nearest(2)),
+                        Some("nearest3") => Some(Scaler::Nearest(3)),
+                        Some("nearest4") => Some(Scaler::Nearest(4)),
+                        Some("scale2x") => Some(Scaler::Scale2x),
+                        Some("scale3x") => Some(Scaler::Scale3x),
+                        Some("hq2x") => Some(Scaler::Hq2x),
+                        Some("hq3x") => Some(Scaler::Hq3x),
+                        Some("xbr2x") => Some(Scaler::Xbr2x),
+                        Some("xbr3x") => Some(Scaler::Xbr3x),
+                        Some("aspect") => Some(Scaler::PixelAspect),
+                        _ => panic!("--scale needs one of nearest2, nearest3, nearest4, scale2x, scale3x, hq2x, hq3x, xbr2x, xbr3x or aspect"),
+                    }
+                }
+                _ => rom_path = Some(arg),
+            }
+        }
+        Self {
+            rom_path: rom_path.expect("Needs a rom path"),
+            wav_path,
+            vgm_path,
+            seconds,
+            region,
+            palette_path,
+            no_sprite_limit,
+            crop_overscan,
+            screenshot_path,
+            scale,
+        }
+    }
+}
+
+/// Runs the rom without a window or audio device for the requested number of seconds, writing
+/// its audio to a 16-bit stereo wav file, its APU register log to a vgm file and/or its last
+/// frame to a PNG file
+fn run_headless(options: &Options) -> Result<()> {
+    const SAMPLE_RATE: u32 = 44100;
+    let mut nes = load_board(options);
+    nes.set_audio_sample_rate(SAMPLE_RATE);
+    if options.vgm_path.is_some() {
+        nes.start_vgm_capture();
+    }
+    let mut wav = match &options.wav_path {
+        Some(path) => Some(WavWriter::create(
+            path,
+            SAMPLE_RATE,
+            WavChannels::Stereo,
+            WavSampleFormat::Pcm16,
+        )?),
+        None => None,
+    };
+
+    let total_samples = (options.seconds * f64::from(SAMPLE_RATE)) as usize;
+    let mut buffer = vec![0.0f32; 1024];
+    let mut written = 0;
+    while written < total_samples {
+        let wanted = (total_samples - written).min(buffer.len());
+        while nes.audio_samples_available() < wanted {
+            nes.clock(false);
+        }
+        let count = nes.read_audio_samples(&mut buffer[..wanted]);
+        if let Some(wav) = &mut wav {
+            wav.write_samples(&buffer[..count])?;
+        }
+        written += count;
+    }
+
+    if let (Some(wav), Some(path)) = (wav, &options.wav_path) {
+        wav.finish()?;
+        println!("Wrote {} seconds of audio to {}", options.seconds, path);
+    }
+    if let (Some(vgm), Some(path)) = (nes.stop_vgm_capture(), &options.vgm_path) {
+        std::fs::write(path, vgm)?;
+        println!("Wrote {} seconds of APU writes to {}", options.seconds, path);
+    }
+    if let Some(path) = &options.screenshot_path {
+        nes.save_screenshot(path, options.scale)?;
+        println!("Saved the frame after {} seconds to {}", options.seconds, path);
+    }
+    Ok(())
+}
+
+fn load_board(options: &Options) -> NESBoard {
+    let program_path = &options.rom_path;
+    let cpu = Cpu::new();
+
+    println!("Reading from file: {}", program_path);
+    let program = std::fs::read(program_path).expect("A valid path to a rom must be provided");
+    let cartridge_data = CartridgeData::decode(&program);
+    println!("Read Catridge: (Maybe Named) {:?}", cartridge_data.title);
+    println!("Program is {} bytes", program.len());
+    println!(
+        "Trainer Block: {:?} at {} bytes",
+        cartridge_data.trainer_range,
+        cartridge_data
+            .trainer_range
+            .clone()
+            .map(|r| r.len())
+            .unwrap_or(0)
+    );
+    println!(
+        "Program Rom Block: {:?} at {} bytes",
+        cartridge_data.prg_rom_range,
+        cartridge_data.prg_rom_range.len()
+    );
+    println!(
+        "Character Rom Block: {:?} at {} bytes",
+        cartridge_data.chr_rom_range,
+        cartridge_data
+            .chr_rom_range
+            .clone()
+            .map(|r| r.len())
+            .unwrap_or(0)
+    );
+    println!("Mapper: {}", cartridge_data.mapper);
+
+    // const RAM_SIZE: usize = 256 * 2048;
+    // const PROGRAM_RANGE: usize = 32768;
+    let internal_ram = vec![0u8; 2048];
+    let internal_vram = vec![0u8; 2048];
+
+    let program_rom = program[cartridge_data.prg_rom_range.clone()].to_vec();
+    let character_rom = cartridge_data
+        .chr_rom_range
+        .clone()
+        .map(|range| program[range].to_vec())
+        .unwrap_or_default();
+
+    let program_ram_size = cartridge_data.prg_ram_size;
+    println!("Cartidge WRam: {} bytes", program_ram_size);
+
+    let region = options
+        .region
+        .unwrap_or(cartridge_data.timing_mode.region());
+    println!("Region: {:?}", region);
+    println!("PPU: {:?}", cartridge_data.ppu_variant);
+    println!("Nametables: {:?}", cartridge_data.board_nametable_arrangement());
+
+    let mut board = NESBoard::new(
+        cpu,
+        internal_ram,
+        internal_vram,
+        program_rom,
+        character_rom,
+        program_ram_size,
+    );
+    board.set_region(region);
+    board.set_nametable_arrangement(cartridge_data.board_nametable_arrangement());
+    let mapper = mapper::from_number(cartridge_data.mapper);
+    if mapper.is_none() && cartridge_data.mapper != 0 {
+        println!("Mapper {} isn't emulated, running it as NROM", cartridge_data.mapper);
+    }
+    board.set_mapper(mapper);
+    board.set_ppu_variant(cartridge_data.ppu_variant);
+    board.set_remove_sprite_limit(options.no_sprite_limit);
+    if options.crop_overscan {
+        board.set_overscan(Overscan::tv());
+    }
+    if let Some(path) = &options.palette_path {
+        let palette = std::fs::read(path).expect("A valid path to a palette must be provided");
+        board.set_palette(&palette);
+    }
+    board
+}
+
 struct Gpu {
     queue: wgpu::Queue,
     device: wgpu::Device,
@@ -131,18 +368,84 @@ impl EguiIntegrator {
     }
 }
 
+/// A texture the debug views are rendered into and uploaded from every frame
+struct DebugTexture {
+    texture: wgpu::Texture,
+    id: TextureId,
+    width: usize,
+    height: usize,
+    buffer: Vec<u8>,
+}
+
+impl DebugTexture {
+    fn new(gpu: &Gpu, egui: &mut EguiIntegrator, width: usize, height: usize) -> Self {
+        let texture = gpu.device().create_texture(&wgpu::TextureDescriptor {
+            size: wgpu::Extent3d {
+                width: width as u32,
+                height: height as u32,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
+            label: Some("NES_debug_view"),
+            view_formats: &[],
+        });
+        let texture_view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let id = egui.renderer_mut().register_native_texture(
+            gpu.device(),
+            &texture_view,
+            wgpu::FilterMode::Nearest,
+        );
+        Self {
+            texture,
+            id,
+            width,
+            height,
+            buffer: vec![0; 4 * width * height],
+        }
+    }
+
+    fn upload(&self, gpu: &Gpu) {
+        gpu.queue().write_texture(
+            wgpu::TexelCopyTextureInfo {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+                aspect: wgpu::TextureAspect::All,
+            },
+            &self.buffer,
+            wgpu::TexelCopyBufferLayout {
+                offset: 0,
+                bytes_per_row: Some(4 * self.width as u32),
+                rows_per_image: Some(self.height as u32),
+            },
+            wgpu::Extent3d {
+                width: self.width as u32,
+                height: self.height as u32,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}
+
 struct App {
     nes: Arc<RwLock<NESBoard>>,
     egui: EguiIntegrator,
     frame_texture: wgpu::Texture,
     frame_texture_id: TextureId,
-    _pattern_table_1_texture: wgpu::Texture,
-    pattern_table_1_texture_id: TextureId,
-    _pattern_table_2_texture: wgpu::Texture,
-    pattern_table_2_texture_id: TextureId,
-    nametable_texture: wgpu::Texture,
-    nametable_texture_id: TextureId,
-    nametable_texture_buffer: Vec<u8>,
+    pattern_table_textures: [DebugTexture; 2],
+    nametables_texture: DebugTexture,
+    oam_sheet_texture: DebugTexture,
+    oam_layout_texture: DebugTexture,
+    palette_ram_texture: DebugTexture,
+    pattern_table_palette: u8,
+    show_scroll_window: bool,
+    event_map_texture: DebugTexture,
+    log_ppu_events: bool,
+    screenshot_scale: Option<Scaler>,
     clock_cpu: bool,
     run_frame: bool,
     last_time: std::time::Instant,
@@ -160,66 +463,9 @@ struct App {
 
 impl App {
     fn new(event_loop: &winit::event_loop::ActiveEventLoop) -> Self {
-        let cpu = Cpu::new();
-
-        let program_path = {
-            let mut args = std::env::args();
-            _ = args.next();
-            args.next().expect("Needs a rom path")
-        };
-        println!("Reading from file: {}", program_path);
-        let program = std::fs::read(program_path).expect("A valid path to a rom must be provided");
-        let cartridge_data = CartridgeData::decode(&program);
-        println!("Read Catridge: (Maybe Named) {:?}", cartridge_data.title);
-        println!("Program is {} bytes", program.len());
-        println!(
-            "Trainer Block: {:?} at {} bytes",
-            cartridge_data.trainer_range,
-            cartridge_data
-                .trainer_range
-                .clone()
-                .map(|r| r.len())
-                .unwrap_or(0)
-        );
-        println!(
-            "Program Rom Block: {:?} at {} bytes",
-            cartridge_data.prg_rom_range,
-            cartridge_data.prg_rom_range.len()
-        );
-        println!(
-            "Character Rom Block: {:?} at {} bytes",
-            cartridge_data.chr_rom_range,
-            cartridge_data
-                .chr_rom_range
-                .clone()
-                .map(|r| r.len())
-                .unwrap_or(0)
-        );
-        println!("Mapper: {}", cartridge_data.mapper);
-
-        // const RAM_SIZE: usize = 256 * 2048;
-        // const PROGRAM_RANGE: usize = 32768;
-        let internal_ram = vec![0u8; 2048];
-        let internal_vram = vec![0u8; 2048];
-
-        let program_rom = program[cartridge_data.prg_rom_range.clone()].to_vec();
-        let character_rom = cartridge_data
-            .chr_rom_range
-            .clone()
-            .map(|range| program[range].to_vec())
-            .unwrap_or_default();
-
-        let program_ram_size = cartridge_data.prg_ram_size;
-        println!("Cartidge WRam: {} bytes", program_ram_size);
-
-        let nes = Arc::new(RwLock::new(NESBoard::new(
-            cpu,
-            internal_ram,
-            internal_vram,
-            program_rom,
-            character_rom,
-            program_ram_size,
-        )));
+        let options = Options::from_args();
+        let nes = Arc::new(RwLock::new(load_board(&options)));
+        let screenshot_scale = options.scale;
 
         let gpu = pollster::block_on(App::create_gpu_struct(event_loop)).unwrap();
 
@@ -246,65 +492,40 @@ impl App {
             wgpu::FilterMode::Nearest,
         );
 
-        let pattern_table_descriptor = wgpu::TextureDescriptor {
-            size: wgpu::Extent3d {
-                width: 128,
-                height: 128,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            label: Some("NES_video_output"),
-            view_formats: &[],
-        };
-        let pattern_table_1_texture = gpu.device().create_texture(&pattern_table_descriptor);
-        let pattern_table_1_texture_view =
-            pattern_table_1_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let pattern_table_1_texture_id = egui.renderer_mut().register_native_texture(
-            gpu.device(),
-            &pattern_table_1_texture_view,
-            wgpu::FilterMode::Nearest,
+        let pattern_table_textures = [0, 1].map(|_| {
+            DebugTexture::new(
+                &gpu,
+                &mut egui,
+                debug::PATTERN_TABLE_WIDTH,
+                debug::PATTERN_TABLE_HEIGHT,
+            )
+        });
+        let nametables_texture = DebugTexture::new(
+            &gpu,
+            &mut egui,
+            debug::NAMETABLES_WIDTH,
+            debug::NAMETABLES_HEIGHT,
         );
-
-        let pattern_table_2_texture = gpu.device().create_texture(&pattern_table_descriptor);
-        let pattern_table_2_texture_view =
-            pattern_table_2_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let pattern_table_2_texture_id = egui.renderer_mut().register_native_texture(
-            gpu.device(),
-            &pattern_table_2_texture_view,
-            wgpu::FilterMode::Nearest,
+        let oam_sheet_texture = DebugTexture::new(
+            &gpu,
+            &mut egui,
+            debug::OAM_SHEET_WIDTH,
+            debug::OAM_SHEET_HEIGHT,
         );
-        Self::upload_nes_pattern_table_textures(
+        let oam_layout_texture = DebugTexture::new(
             &gpu,
-            &nes.read().expect("RW_LOCK_POISONED"),
-            &pattern_table_1_texture,
-            &pattern_table_2_texture,
+            &mut egui,
+            debug::OAM_LAYOUT_WIDTH,
+            debug::OAM_LAYOUT_HEIGHT,
         );
-
-        let nametable_texture = gpu.device().create_texture(&wgpu::TextureDescriptor {
-            size: wgpu::Extent3d {
-                width: 32,
-                height: 30,
-                depth_or_array_layers: 1,
-            },
-            mip_level_count: 1,
-            sample_count: 1,
-            dimension: wgpu::TextureDimension::D2,
-            format: wgpu::TextureFormat::Rgba8UnormSrgb,
-            usage: wgpu::TextureUsages::TEXTURE_BINDING | wgpu::TextureUsages::COPY_DST,
-            label: Some("NES_video_output"),
-            view_formats: &[],
-        });
-        let nametable_texture_view =
-            nametable_texture.create_view(&wgpu::TextureViewDescriptor::default());
-        let nametable_texture_id = egui.renderer_mut().register_native_texture(
-            gpu.device(),
-            &nametable_texture_view,
-            wgpu::FilterMode::Nearest,
+        let palette_ram_texture = DebugTexture::new(
+            &gpu,
+            &mut egui,
+            debug::PALETTE_RAM_WIDTH,
+            debug::PALETTE_RAM_HEIGHT,
         );
+        let scanlines = nes.read().expect("RW_LOCK_POISONED").region().scanlines_per_frame();
+        let event_map_texture = DebugTexture::new(&gpu, &mut egui, events::EVENT_MAP_WIDTH, scanlines);
 
         let freq = Arc::new(AtomicU32::new(440));
         let sound_fn = Arc::new(AtomicU8::new(0));
@@ -321,13 +542,16 @@ impl App {
             egui,
             frame_texture_id,
             frame_texture,
-            _pattern_table_1_texture: pattern_table_1_texture,
-            pattern_table_1_texture_id,
-            _pattern_table_2_texture: pattern_table_2_texture,
-            pattern_table_2_texture_id,
-            nametable_texture,
-            nametable_texture_id,
-            nametable_texture_buffer: vec![255; 4 * 32 * 30],
+            pattern_table_textures,
+            nametables_texture,
+            oam_sheet_texture,
+            oam_layout_texture,
+            palette_ram_texture,
+            pattern_table_palette: 0,
+            show_scroll_window: true,
+            event_map_texture,
+            log_ppu_events: false,
+            screenshot_scale,
             clock_cpu: false,
             run_frame: false,
             last_time: std::time::Instant::now(),
@@ -390,6 +614,9 @@ impl App {
         println!("{:?}", supported_config);
         let sample_rate = 44100.0;
         let mut sample_index = 0.0;
+        nes.write()
+            .expect("RW_LOCK_POISONED")
+            .set_audio_sample_rate(supported_config.sample_rate);
         let stream = device
             .build_output_stream(
                 &supported_config,
@@ -400,20 +627,17 @@ impl App {
                     // let sound_fn = sound_fn.clone();
                     // let h = h.clone();
                     // let fcycle = fcycle.clone();
-                    let mut sample_buffer: Vec<f64> = Vec::with_capacity(512);
+                    let mut sample_buffer: Vec<f32> = Vec::with_capacity(512);
                     move |data: &mut [f32], _oc: &cpal::OutputCallbackInfo| {
                         // Batch enough samples by clocking the emulator while write-locked
                         {
                             let total_needed_samples = data.len();
                             sample_buffer.resize(total_needed_samples, 0.0);
                             let mut _nes = nes.write().expect("QUIB_RW_LOCK_POISONED");
-                            for _needed_sample in sample_buffer.iter_mut() {
-                                *_needed_sample = loop {
-                                    if let Some(res) = _nes.clock(false) {
-                                        break res;
-                                    }
-                                }
+                            while _nes.audio_samples_available() < total_needed_samples {
+                                _nes.clock(false);
                             }
+                            _nes.read_audio_samples(&mut sample_buffer);
                         }
 
                         // println!("{}", data.len());
@@ -449,7 +673,7 @@ impl App {
                             //         0 => sample_sin(freq, time),
                             //         _ => sample_square(freq, time, h, fcycle),
                             //     };
-                            data[i] = f64::to_sample(volume * sample_buffer[i]); //.to_sample::<f32>();
+                            data[i] = f64::to_sample(volume * f64::from(sample_buffer[i])); //.to_sample::<f32>();
                             sample_index = (sample_index + 1.0) % sample_rate;
                         }
                     }
@@ -511,129 +735,6 @@ impl App {
         Ok(gpu)
     }
 
-    fn upload_nes_nametable_texture(
-        gpu: &Gpu,
-        nes: &NESBoard,
-        nametable_texture: &wgpu::Texture,
-        nametable_texture_buffer: &mut [u8],
-    ) {
-        let nametable = nes.nametable_memory(0);
-        for (i, b) in nametable.iter().enumerate().take(32 * 30) {
-            let i = i * 4;
-            nametable_texture_buffer[i] = *b & 0xf0;
-            nametable_texture_buffer[i + 1] = *b << 4;
-            nametable_texture_buffer[i + 2] = 0x00;
-            nametable_texture_buffer[i + 3] = 0xff;
-        }
-        gpu.queue().write_texture(
-            wgpu::TexelCopyTextureInfo {
-                texture: nametable_texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            // The actual pixel data
-            nametable_texture_buffer,
-            // The layout of the texture
-            wgpu::TexelCopyBufferLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * 32),
-                rows_per_image: Some(30),
-            },
-            wgpu::Extent3d {
-                width: 32,
-                height: 30,
-                depth_or_array_layers: 1,
-            },
-        );
-    }
-
-    fn upload_nes_pattern_table_textures(
-        gpu: &Gpu,
-        nes: &NESBoard,
-        pattern_table_1_texture: &wgpu::Texture,
-        pattern_table_2_texture: &wgpu::Texture,
-    ) {
-        let mut pt1_buff = vec![0xff; 128 * 128 * 4];
-        let mut pt2_buff = vec![0xff; 128 * 128 * 4];
-        let pattern_table = nes.pattern_table_memory();
-        let colors = [
-            0xff, 0x00, 0x00, 0x00, 0xff, 0x00, 0x00, 0x00, 0xff, 0x7f, 0x34, 0x00,
-        ];
-
-        let tiles_to_pixels = |buff: &mut [u8], pattern_table: &[u8]| {
-            for py in 0..128 {
-                for px in 0..128 {
-                    let tx = px / 8; // [0, 16)
-                    let ty = py / 8; // [0, 16)
-                    let t = (ty * 16 + tx) * 16; // 16 bytes per tile
-                    let to_l = py & 7;
-                    let to_h = to_l + 8;
-                    let bitmask = 0x80 >> (px & 7);
-                    let t_l = ((pattern_table[t + to_l] & bitmask) > 0) as u8;
-                    let t_h = ((pattern_table[t + to_h] & bitmask) > 0) as u8;
-
-                    let color_index = usize::from(((t_h << 1) | t_l) * 3);
-                    let r = colors[color_index];
-                    let g = colors[color_index + 1];
-                    let b = colors[color_index + 2];
-
-                    let p = (py * 128 + px) * 4;
-                    buff[p] = r;
-                    buff[p + 1] = g;
-                    buff[p + 2] = b;
-                    buff[p + 3] = 0xff;
-                }
-            }
-        };
-        let half = pattern_table.len() / 2;
-        tiles_to_pixels(&mut pt1_buff, &pattern_table[0..half]);
-        tiles_to_pixels(&mut pt2_buff, &pattern_table[half..]);
-
-        gpu.queue().write_texture(
-            wgpu::TexelCopyTextureInfo {
-                texture: pattern_table_1_texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            // The actual pixel data
-            &pt1_buff,
-            // The layout of the texture
-            wgpu::TexelCopyBufferLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * 128),
-                rows_per_image: Some(128),
-            },
-            wgpu::Extent3d {
-                width: 128,
-                height: 128,
-                depth_or_array_layers: 1,
-            },
-        );
-        gpu.queue().write_texture(
-            wgpu::TexelCopyTextureInfo {
-                texture: pattern_table_2_texture,
-                mip_level: 0,
-                origin: wgpu::Origin3d::ZERO,
-                aspect: wgpu::TextureAspect::All,
-            },
-            // The actual pixel data
-            &pt2_buff,
-            // The layout of the texture
-            wgpu::TexelCopyBufferLayout {
-                offset: 0,
-                bytes_per_row: Some(4 * 128),
-                rows_per_image: Some(128),
-            },
-            wgpu::Extent3d {
-                width: 128,
-                height: 128,
-                depth_or_array_layers: 1,
-            },
-        );
-    }
-
     fn draw(&mut self) -> Result<()> {
         let gpu = &self.gpu;
         let mut encoder = gpu
@@ -641,7 +742,9 @@ impl App {
             .create_command_encoder(&wgpu::CommandEncoderDescriptor {
                 label: Some("encoder"),
             });
+        let (video_width, video_height) = self.nes.read().expect("RW_LOCK_POISONED").video_size();
         {
+            // The cropped picture goes in the top left corner of the texture
             gpu.queue().write_texture(
                 wgpu::TexelCopyTextureInfo {
                     texture: &self.frame_texture,
@@ -654,12 +757,12 @@ impl App {
                 // The layout of the texture
                 wgpu::TexelCopyBufferLayout {
                     offset: 0,
-                    bytes_per_row: Some(4 * 256),
-                    rows_per_image: Some(240),
+                    bytes_per_row: Some(4 * video_width as u32),
+                    rows_per_image: Some(video_height as u32),
                 },
                 wgpu::Extent3d {
-                    width: 256,
-                    height: 240,
+                    width: video_width as u32,
+                    height: video_height as u32,
                     depth_or_array_layers: 1,
                 },
             );
@@ -692,6 +795,14 @@ impl App {
                 if ui.button("RESET").clicked() { nes.reset(); }
                 if ui.button("IRQ").clicked() { nes.irq(); }
                 if ui.button("NMI").clicked() { nes.nmi(); }
+                let mut remove_sprite_limit = nes.ppu().remove_sprite_limit();
+                if ui.checkbox(&mut remove_sprite_limit, "Remove Sprite Limit").changed() {
+                    nes.set_remove_sprite_limit(remove_sprite_limit);
+                }
+                let mut oam_glitches = nes.ppu().oam_glitches();
+                if ui.checkbox(&mut oam_glitches, "OAM Corruption/Decay").changed() {
+                    nes.set_oam_glitches(oam_glitches);
+                }
             }
             {
                 let mut m_sound_fn = self.sound_fn.load(std::sync::atomic::Ordering::Relaxed);
@@ -718,7 +829,36 @@ impl App {
             ui.separator();
             ui.collapsing("Debug Information", |ui| {
                 let nes = self.nes.read().expect("RW_LOCK_POISONED");
-                Self::upload_nes_nametable_texture(gpu, &nes, &self.nametable_texture, &mut self.nametable_texture_buffer);
+                let ppu = nes.ppu();
+                ui.label(RichText::new(ppu.state().to_string()).monospace());
+                ui.add(egui::Slider::new(&mut self.pattern_table_palette, 0..=7).text("Pattern Table Palette"));
+                ui.checkbox(&mut self.show_scroll_window, "Show Scroll Window");
+                for (table, texture) in self.pattern_table_textures.iter_mut().enumerate() {
+                    debug::render_pattern_table(ppu, &*nes, table as u8, self.pattern_table_palette, &mut texture.buffer);
+                    texture.upload(gpu);
+                }
+                debug::render_nametables(ppu, &*nes, self.show_scroll_window, &mut self.nametables_texture.buffer);
+                self.nametables_texture.upload(gpu);
+                debug::render_oam_sheet(ppu, &*nes, &mut self.oam_sheet_texture.buffer);
+                self.oam_sheet_texture.upload(gpu);
+                debug::render_oam_layout(ppu, &*nes, &mut self.oam_layout_texture.buffer);
+                self.oam_layout_texture.upload(gpu);
+                debug::render_palette_ram(ppu, &mut self.palette_ram_texture.buffer);
+                self.palette_ram_texture.upload(gpu);
+                if ui.button("Save Pattern Tables and Nametables").clicked() {
+                    let images = [
+                        ("pattern-table-0.png", &self.pattern_table_textures[0]),
+                        ("pattern-table-1.png", &self.pattern_table_textures[1]),
+                        ("nametables.png", &self.nametables_texture),
+                    ];
+                    for (path, texture) in images {
+                        match png::save_png(path, &texture.buffer, texture.width, texture.height) {
+                            Ok(()) => println!("Saved {}", path),
+                            Err(error) => println!("Couldn't save {}: {}", path, error),
+                        }
+                    }
+                }
+
                 let draw_texture = |ui: &mut Ui, texture_id: TextureId, x: f32, y: f32, width: f32, height: f32| {
                     let bounding_box = egui::Rect { min: Pos2 {x, y}, max: Pos2 { x: x + width, y: y + height } };
                     let uv = egui::Rect { min: Pos2 {x: 0.0, y: 0.0}, max: Pos2 {x: 1.0, y: 1.0} };
@@ -728,32 +868,100 @@ impl App {
                 let (x, y) = ui.next_widget_position().into();
                 let width = 256.0;
                 let height = 240.0;
-                draw_texture(ui, self.nametable_texture_id, x, y, width, height);
+                draw_texture(ui, self.nametables_texture.id, x, y, width, height);
 
                 let y = y + height;
-                let height = 256.0;
+                let height = 128.0;
+                draw_texture(ui, self.pattern_table_textures[0].id, x, y, 128.0, height);
+                draw_texture(ui, self.pattern_table_textures[1].id, x + 128.0, y, 128.0, height);
+
+                let y = y + height;
+                let height = 32.0;
+                draw_texture(ui, self.palette_ram_texture.id, x, y, width, height);
 
-                draw_texture(ui, self.pattern_table_1_texture_id, x, y, width, height);
+                let y = y + height;
+                let height = 240.0;
+                draw_texture(ui, self.oam_layout_texture.id, x, y, width, height);
 
                 let y = y + height;
                 let height = 256.0;
-                draw_texture(ui, self.pattern_table_2_texture_id, x, y, width, height);
+                draw_texture(ui, self.oam_sheet_texture.id, x, y, 128.0, height);
+            });
+            ui.collapsing("PPU Events", |ui| {
+                let mut nes = self.nes.write().expect("RW_LOCK_POISONED");
+                if ui.checkbox(&mut self.log_ppu_events, "Log PPU Events").changed() {
+                    nes.set_ppu_event_logging(self.log_ppu_events);
+                }
+                let log = nes.ppu_events();
+                ui.label(format!("{} events last frame", log.events().len()));
+                events::render_event_map(log, nes.region(), &mut self.event_map_texture.buffer);
+                self.event_map_texture.upload(gpu);
+
+                let size = egui::Vec2::new(self.event_map_texture.width as f32, self.event_map_texture.height as f32);
+                let (response, painter) = ui.allocate_painter(size, egui::Sense::hover());
+                let uv = egui::Rect { min: Pos2 {x: 0.0, y: 0.0}, max: Pos2 {x: 1.0, y: 1.0} };
+                painter.image(self.event_map_texture.id, response.rect, uv, Color32::WHITE);
+                if let Some(position) = response.hover_pos() {
+                    let dot = (position.x - response.rect.min.x) as usize;
+                    let scanline = (position.y - response.rect.min.y) as usize;
+                    let mut text = format!("Scanline {scanline}, dot {dot}");
+                    for event in log.events_at(scanline, dot) {
+                        text += &format!(
+                            "\n{:?} ${:0>4X} = ${:0>2X} at {}, {} from ${:0>4X}",
+                            event.kind, event.address, event.value, event.scanline, event.dot, event.pc
+                        );
+                    }
+                    response.on_hover_text(text);
+                }
+            });
+            ui.collapsing("Layers", |ui| {
+                // Only the picture changes, the game sees the ppu render as usual
+                let mut nes = self.nes.write().expect("RW_LOCK_POISONED");
+                let mut show_background = nes.ppu().background_visible();
+                if ui.checkbox(&mut show_background, "Background").changed() {
+                    nes.set_background_visible(show_background);
+                }
+                let mut show_sprites = nes.ppu().sprites_visible();
+                if ui.checkbox(&mut show_sprites, "Sprites").changed() {
+                    nes.set_sprites_visible(show_sprites);
+                }
+                let mut crop_overscan = nes.overscan() != Overscan::new();
+                if ui.checkbox(&mut crop_overscan, "Crop Overscan").changed() {
+                    nes.set_overscan(if crop_overscan { Overscan::tv() } else { Overscan::new() });
+                }
+                ui.label("Sprites shown, in OAM order");
+                egui::Grid::new("sprite_visibility").show(ui, |ui| {
+                    for index in 0..64u8 {
+                        let mut visible = nes.ppu().sprite_visible(index);
+                        if ui.checkbox(&mut visible, format!("{index}")).changed() {
+                            nes.set_sprite_visible(index, visible);
+                        }
+                        if index % 8 == 7 {
+                            ui.end_row();
+                        }
+                    }
+                });
             });
         });
         egui::CentralPanel::default().show(ctx, |ui| {
             ui.label("NES (6502) Emulator");
+            // Only the top left of the texture holds a picture when the overscan is cropped
+            let (video_width, video_height) = self.nes.read().expect("RW_LOCK_POISONED").video_size();
             ui.painter().image(
                 self.frame_texture_id,
                 egui::Rect {
                     min: Pos2 { x: 0.0, y: 0.0 },
                     max: Pos2 {
-                        x: 256.0 * 2.0,
-                        y: 240.0 * 2.0,
+                        x: video_width as f32 * 2.0,
+                        y: video_height as f32 * 2.0,
                     },
                 },
                 egui::Rect {
                     min: Pos2 { x: 0.0, y: 0.0 },
-                    max: Pos2 { x: 1.0, y: 1.0 },
+                    max: Pos2 {
+                        x: video_width as f32 / 256.0,
+                        y: video_height as f32 / 240.0,
+                    },
                 },
                 Color32::WHITE,
             );
@@ -929,6 +1137,16 @@ impl ApplicationHandler for App {
                         if n == NamedKey::Space && pressed {
                             self.clock_cpu = !self.clock_cpu;
                         }
+                        if n == NamedKey::F12 && pressed {
+                            let time = std::time::SystemTime::now()
+                                .duration_since(std::time::UNIX_EPOCH)
+                                .unwrap_or_default();
+                            let path = format!("screenshot-{}.png", time.as_millis());
+                            match nes.save_screenshot(&path, self.screenshot_scale) {
+                                Ok(()) => println!("Saved a screenshot to {}", path),
+                                Err(error) => println!("Couldn't save {}: {}", path, error),
+                            }
+                        }
                     }
                     _ => {}
                 }
@@ -981,6 +1199,11 @@ impl Default for AppShell {
 }
 
 fn main() -> Result<()> {
+    let options = Options::from_args();
+    if options.wav_path.is_some() || options.vgm_path.is_some() || options.screenshot_path.is_some() {
+        return run_headless(&options);
+    }
+
     let event_loop = EventLoop::new()?;
 
     let mut app = AppShell::default();
@@ -1,29 +1,53 @@
 use nes_rust::{
-    apu::{Apu, ApuPinout},
+    apu::{vgm::VgmRecorder, Apu, ApuPinout},
+    cartidge::{mapper::Mapper, NameTableArrangement},
     cpu::{Cpu, CpuPinout},
-    ppu::{Ppu, PpuPinout},
+    ppu::{
+        debug::PpuMemory,
+        events::{PpuEventKind, PpuEventLog},
+        Ppu, PpuBusAccess, PpuPinout, PpuStatus, PpuVariant,
+    },
+    region::Region,
+    video::{overscan::Overscan, palette::PaletteGenerator, png, scale::Scaler},
 };
+use std::{io, path::Path};
 
 const NES_CLOCK_TIME: u64 = 5_369_318;
 
 pub struct NESBoard {
     cpu: Cpu,
     cpu_pins: CpuPinout,
+    // Address of the instruction being executed, latched from the bus on opcode fetches
+    instruction_address: u16,
 
     apu: Apu,
     apu_pins: ApuPinout,
 
     ppu: Ppu,
     ppu_pins: PpuPinout,
-    ppu_address_latch: u8,
+    // A cpu access to a ppu register is waiting for the ppu to handle it
+    ppu_register_access: bool,
+    // Events of the frame in progress, when logging is on, and of the last complete frame
+    ppu_event_log: Option<PpuEventLog>,
+    ppu_events: PpuEventLog,
 
     ram: Vec<u8>,
     vram: Vec<u8>,
+    nametable_arrangement: NameTableArrangement,
     prg_rom: Vec<u8>,
     chr_rom: Vec<u8>,
     prg_ram: Vec<u8>,
-
+    // Cartridge hardware watching the ppu bus, for boards that count scanlines through A12
+    mapper: Option<Box<dyn Mapper + Send + Sync>>,
+    // Level of the mapper's IRQ output after the last ppu dot, to log when it's asserted
+    mapper_irq: bool,
+
+    // Raw ppu pixels of the last finished frame, their RGBA conversion and what's left of it
+    // after cropping the overscan
+    pixel_copy: Vec<u16>,
     video_copy: Vec<u8>,
+    overscan: Overscan,
+    cropped_video: Vec<u8>,
 
     // Raw input of the "controllers"
     controllers: [u8; 2],
@@ -35,7 +59,12 @@ pub struct NESBoard {
     dma_address: u8,
     dma_address_lo: u8,
 
-    internal_timer: u64,
+    region: Region,
+    // Master clocks left over after the last ppu dot
+    ppu_master_clocks: u32,
+
+    cpu_cycles: u64,
+    vgm_recorder: Option<VgmRecorder>,
 }
 
 impl NESBoard {
@@ -75,38 +104,43 @@ impl NESBoard {
             cpu_control: false,
             cpu_addr: 0,
             finished_frame: false,
+            extra_sprite_address: None,
+            extra_sprite_data: [0; 2],
         };
         let prg_ram = vec![0u8; ram_size];
-        // let palette_file = include_bytes!("../../src/ntscpalette.pal");
-        // let palette_file = include_bytes!("../../src/2C02G_wiki.pal");
-        let palette_file = include_bytes!("../../src/Composite_wiki.pal");
-        let system_palette: &[u8; 64 * 3] = palette_file
-            .first_chunk()
-            .expect("Palette file did not have 64 RGB entries");
-        ppu.set_palette(system_palette);
+        ppu.set_palette_colours(PaletteGenerator::new().generate());
+        let pixel_copy = vec![0; nes_rust::ppu::PIXEL_DATA_SIZE];
         let video_copy = vec![255; nes_rust::ppu::VIDEO_MEMORY_SIZE];
 
         let apu = Apu::new();
         let apu_pins = ApuPinout::new();
 
-        let internal_timer = 0;
         NESBoard {
             cpu,
             cpu_pins,
+            instruction_address: 0,
 
             apu,
             apu_pins,
 
             ppu,
             ppu_pins,
-            ppu_address_latch: 0,
+            ppu_register_access: false,
+            ppu_event_log: None,
+            ppu_events: PpuEventLog::new(),
 
             ram: internal_ram,
             vram: internal_vram,
+            nametable_arrangement: NameTableArrangement::VERTICAL,
             prg_rom,
             chr_rom,
             prg_ram,
+            mapper: None,
+            mapper_irq: false,
+            pixel_copy,
+            cropped_video: video_copy.clone(),
             video_copy,
+            overscan: Overscan::new(),
 
             controllers: [0; 2],
             controllers_copy: [0; 2],
@@ -118,7 +152,11 @@ impl NESBoard {
             dma_address: 0,
             dma_address_lo: 0,
 
-            internal_timer,
+            region: Region::Ntsc,
+            ppu_master_clocks: 0,
+
+            cpu_cycles: 0,
+            vgm_recorder: None,
         }
     }
 
@@ -146,6 +184,9 @@ impl NESBoard {
 
         self.cpu_pins.phi = phi;
         let _cycle_occured = self.cpu.clock(&mut self.cpu_pins);
+        if self.cpu_pins.sync {
+            self.instruction_address = self.cpu_pins.address_bus;
+        }
         if self.cpu_pins.address_rw && !phi {
             self.cpu_mem_read();
         } else if !self.cpu_pins.address_rw && phi {
@@ -173,6 +214,7 @@ impl NESBoard {
                 self.ppu_pins.cpu_rw = self.cpu_pins.address_rw;
                 self.ppu_pins.cpu_addr = addr as u8;
                 self.ppu_pins.cpu_control = true;
+                self.ppu_register_access = true;
                 // Data bus will be filled via the ppu_clock fn
             }
             0x4000..0x4020 => {
@@ -222,12 +264,19 @@ impl NESBoard {
                 self.ppu_pins.cpu_rw = self.cpu_pins.address_rw;
                 self.ppu_pins.cpu_addr = addr as u8;
                 self.ppu_pins.cpu_control = true;
+                self.ppu_register_access = true;
                 self.ppu_pins.cpu_data = self.cpu_pins.data_bus; // hand over data from cpu to ppu
             }
             0x4000..0x4020 => {
                 match addr {
                     0x4000..=0x4013 | 0x4015 | 0x4017 => {
                         // Apu addresses
+                        if let Some(recorder) = &mut self.vgm_recorder {
+                            let prg_rom = &self.prg_rom;
+                            recorder.record_write(self.cpu_cycles, addr, self.cpu_pins.data_bus, |address| {
+                                prg_rom[usize::from(address - 0x8000) % prg_rom.len()]
+                            });
+                        }
                         let addr = addr - 0x4000;
                         self.apu_pins.cpu_rw = self.cpu_pins.address_rw;
                         self.apu_pins.cpu_addr = addr as u8;
@@ -235,6 +284,10 @@ impl NESBoard {
                     }
                     0x4014 => {
                         // OAM DMA
+                        if let Some(log) = &mut self.ppu_event_log {
+                            let pc = self.instruction_address;
+                            log.record(&self.ppu, PpuEventKind::OamDma, addr, self.cpu_pins.data_bus, pc);
+                        }
                         self.dma_active = true;
                         self.dma_address = self.cpu_pins.data_bus;
                         self.dma_address_lo = 0;
@@ -259,23 +312,60 @@ impl NESBoard {
                 }
             }
             0x8000..=0xFFFF => {
-                // prg rom, no writes
+                // prg rom, writes only reach the mapper's registers
+                if let Some(mapper) = &mut self.mapper {
+                    let mut data = self.cpu_pins.data_bus;
+                    mapper.access_prg(addr, &mut data, false);
+                }
             }
         }
     }
 
     fn ppu_clock(&mut self) -> bool {
+        let sprite_0_hit = self.ppu.status().contains(PpuStatus::SPRITE_0_HIT);
         self.ppu.clock(&mut self.ppu_pins);
+        if let Some(log) = &mut self.ppu_event_log {
+            let pc = self.instruction_address;
+            if self.ppu_register_access {
+                let kind = if self.ppu_pins.cpu_rw {
+                    PpuEventKind::RegisterRead
+                } else {
+                    PpuEventKind::RegisterWrite
+                };
+                let address = 0x2000 | u16::from(self.ppu_pins.cpu_addr);
+                log.record(&self.ppu, kind, address, self.ppu_pins.cpu_data, pc);
+            }
+            if !sprite_0_hit && self.ppu.status().contains(PpuStatus::SPRITE_0_HIT) {
+                log.record(&self.ppu, PpuEventKind::Sprite0Hit, 0, 0, pc);
+            }
+            // A frame's events run from the pre-render line to the end of vblank
+            if self.ppu.scanline() == self.region.scanlines_per_frame() - 1 && self.ppu.dot() == 0 {
+                self.ppu_events = std::mem::take(log);
+            }
+        }
+        self.ppu_register_access = false;
+        if let Some(address) = self.ppu_pins.extra_sprite_address {
+            self.ppu_pins.extra_sprite_data = [self.peek(address), self.peek(address + 8)];
+        }
         self.cpu_pins.nmi &= self.ppu_pins.nmi;
-        if self.ppu_pins.ppu_ale {
-            self.ppu_address_latch = self.ppu_pins.ppu_address_data_low;
+        let bus_event = self.ppu.bus_event(&self.ppu_pins);
+        if let (Some(mapper), Some(event)) = (self.mapper.as_mut(), bus_event.as_ref()) {
+            mapper.observe_ppu_bus(event);
+        }
+        let mapper_irq = self.mapper.as_ref().is_some_and(|mapper| mapper.irq());
+        if mapper_irq && !self.mapper_irq {
+            if let Some(log) = &mut self.ppu_event_log {
+                log.record(&self.ppu, PpuEventKind::MapperIrq, 0, 0, self.instruction_address);
+            }
         }
-        let addr =
-            ((self.ppu_pins.ppu_address_high as usize) << 8) | self.ppu_address_latch as usize;
-        if self.ppu_pins.ppu_r || self.ppu_pins.ppu_w {
+        self.mapper_irq = mapper_irq;
+        self.cpu_pins.irq &= !mapper_irq;
+        if let Some(event) = bus_event.filter(|event| event.access != PpuBusAccess::Address) {
+            let addr = usize::from(event.address);
+            let read = event.access == PpuBusAccess::Read;
             match addr {
                 0x0000..0x2000 => {
-                    if self.ppu_pins.ppu_r {
+                    if read {
                         self.ppu_pins.ppu_address_data_low = self.chr_rom[addr];
                     } else {
                         dbg!(
@@ -286,10 +376,10 @@ impl NESBoard {
                     }
                 }
                 0x2000.. => {
-                    // internal NES vram or mapped by cartidge
-                    // For nametables
-                    let addr = (addr - 0x2000) % self.vram.len();
-                    if self.ppu_pins.ppu_r {
+                    // internal NES vram, or the cartridge's for four-screen boards, with
+                    // 0x3000..0x3EFF mirroring the nametables
+                    let addr = self.current_nametable_arrangement().fold(event.address);
+                    if read {
                         // TODO: Determine if this needs to occur during the ALE cycle or the next
                         // cycle - this depends on if the cpu expects the data the same cycle it
                         // enables the read line or the line after
@@ -298,12 +388,8 @@ impl NESBoard {
                         self.vram[addr] = self.ppu_pins.ppu_address_data_low;
                     }
                 }
-                // 0x3000.. => {
-                //     println!("\t!!!Writing to an RO portion of vram: 0x{:0>4X}({})!!!", addr, addr);
-                // 0x3000..0x3EFF => unused mirron of vram
                 // 0x3F00.. is the start of palette ram, but it's internal to the PPU and doesn't
                 // reach here
-                // }
             }
         }
 
@@ -318,59 +404,165 @@ impl NESBoard {
         self.ppu_pins.finished_frame
     }
 
-    fn apu_clock(&mut self) -> f64 {
-        self.apu.clock(&mut self.apu_pins)
+    fn apu_clock(&mut self) {
+        self.apu.clock(&mut self.apu_pins);
+        // A write only lasts for the cycle it was made in
+        self.apu_pins.cpu_rw = true;
     }
 
-    // Emulate one master clock cycle
-    pub fn clock(&mut self, _ready: bool) -> Option<f64> {
-        // 1 / (component hz) / min(all 1 / component hz) * 1000
-        // 1.0
-        const PPU_CLOCK_FREQ: u64 = 1000;
-        // 2.999999999...
-        const CPU_CLOCK_FREQ: u64 = 3000;
-        // 121.7532653
-        const APU_SAMPLE_FREQ: u64 = 121753;
+    // Emulate one cpu cycle along with the ppu dots that fit into it: 3 on NTSC and Dendy, 3.2 on
+    // average on PAL
+    pub fn clock(&mut self, _ready: bool) {
+        self.ppu_master_clocks += self.region.cpu_divider();
+        let dots = self.ppu_master_clocks / self.region.ppu_divider();
+        self.ppu_master_clocks %= self.region.ppu_divider();
 
-        let ff_1 = self.ppu_clock();
-        let ff_2 = self.ppu_clock();
+        let mut video_finished = false;
+        for _ in 1..dots {
+            video_finished |= self.ppu_clock();
+        }
 
         self.cpu_clock(false);
 
         // One ppu clock between phi1 and phi2 to handle reading from ppu
-        let ff_3 = self.ppu_clock();
+        video_finished |= self.ppu_clock();
 
         self.cpu_clock(true);
-        let maybe_sample = self.apu_clock();
-
-        self.internal_timer += CPU_CLOCK_FREQ;
+        self.apu_clock();
+        self.cpu_cycles += 1;
 
         // Reset inturrupt requests
         self.cpu_pins.reset = true;
         self.cpu_pins.irq = true;
         self.cpu_pins.nmi = true; // might be unnecessary as ppu manages nmi
 
-        // TODO: Generate audio sample in real-time
-        // Accumulate a time variable per nes-clock
-        // When accumulated time > RealtimePerSample,
-        //  Emit sample
-        let mut audio_sample = None;
-        if APU_SAMPLE_FREQ <= self.internal_timer {
-            self.internal_timer -= APU_SAMPLE_FREQ;
-            audio_sample = Some(maybe_sample);
+        if video_finished {
+            self.pixel_copy.copy_from_slice(self.ppu.pixel_data());
+            self.ppu
+                .palette()
+                .write_rgba(&self.pixel_copy, &mut self.video_copy);
+            self.overscan
+                .crop(&self.video_copy, 4, &mut self.cropped_video);
         }
+    }
 
-        let video_finished = ff_1 || ff_2 || ff_3;
-        if video_finished {
-            self.video_copy.copy_from_slice(self.ppu.video_data());
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Switches the cpu, ppu and apu to the timing of `region`; call before running
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.ppu_master_clocks = 0;
+        self.ppu.set_region(region);
+        let palette = match region {
+            Region::Ntsc => PaletteGenerator::new(),
+            Region::Pal | Region::Dendy => PaletteGenerator::pal(),
+        };
+        self.ppu.set_palette_colours(palette.generate());
+        self.apu.set_clock_rate(region.cpu_clock_rate());
+    }
+
+    /// Maps the nametables onto vram as `arrangement` does, adding the cartridge's extra vram for
+    /// four-screen boards. Mappers that control mirroring override it through
+    /// `Mapper::nametable_arrangement`.
+    pub fn set_nametable_arrangement(&mut self, arrangement: NameTableArrangement) {
+        self.nametable_arrangement = arrangement;
+        if self.vram.len() < arrangement.memory_size() {
+            self.vram.resize(arrangement.memory_size(), 0);
         }
-        audio_sample
+    }
+
+    /// The board's arrangement, or the one the mapper has switched to
+    fn current_nametable_arrangement(&self) -> NameTableArrangement {
+        let mapper = self.mapper.as_ref().and_then(|mapper| mapper.nametable_arrangement());
+        self.nametable_arrangement.with_mapper(mapper)
+    }
+
+    /// Draws every sprite on a scanline instead of just the first 8, see
+    /// `Ppu::set_remove_sprite_limit`
+    pub fn set_remove_sprite_limit(&mut self, remove: bool) {
+        self.ppu.set_remove_sprite_limit(remove);
+    }
+
+    /// Display only layer switches, see `Ppu::set_background_visible`
+    pub fn set_background_visible(&mut self, visible: bool) {
+        self.ppu.set_background_visible(visible);
+    }
+
+    pub fn set_sprites_visible(&mut self, visible: bool) {
+        self.ppu.set_sprites_visible(visible);
+    }
+
+    pub fn set_sprite_visible(&mut self, index: u8, visible: bool) {
+        self.ppu.set_sprite_visible(index, visible);
+    }
+
+    /// Attaches a mapper that sees every ppu bus access and cpu write to $8000-$FFFF. Memory is
+    /// still served by the board.
+    pub fn set_mapper(&mut self, mapper: Option<Box<dyn Mapper + Send + Sync>>) {
+        self.mapper = mapper;
+    }
+
+    /// Emulates OAM corruption and decay, see `Ppu::set_oam_glitches`. This overrides the
+    /// region's default, so it's kept when the region changes.
+    pub fn set_oam_glitches(&mut self, enabled: bool) {
+        self.ppu.set_oam_glitches(enabled);
+    }
+
+    /// Switches the ppu to `variant`, along with its palette for the RGB variants
+    pub fn set_ppu_variant(&mut self, variant: PpuVariant) {
+        self.ppu.set_variant(variant);
+        if variant.is_rgb() {
+            self.ppu.set_palette_colours(variant.palette());
+        }
+    }
+
+    /// Replaces the ppu's colours with the contents of a 64 or 512 colour `.pal` file
+    pub fn set_palette(&mut self, data: &[u8]) {
+        self.ppu.set_palette(data);
+    }
+
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.set_sample_rate(sample_rate);
+    }
+
+    pub fn audio_samples_available(&self) -> usize {
+        self.apu.samples_available()
+    }
+
+    pub fn read_audio_samples(&mut self, out: &mut [f32]) -> usize {
+        self.apu.read_samples_f32(out)
+    }
+
+    /// Begins capturing APU register writes for a VGM file, discarding any capture in progress
+    pub fn start_vgm_capture(&mut self) {
+        self.vgm_recorder = Some(VgmRecorder::new(self.region.cpu_clock_rate()));
+    }
+
+    /// Ends the capture, returning the serialized VGM file if one was running
+    pub fn stop_vgm_capture(&mut self) -> Option<Vec<u8>> {
+        self.vgm_recorder
+            .take()
+            .map(|recorder| recorder.finish(self.cpu_cycles))
     }
 
     pub fn dump_ppu(&self) {
         self.ppu.dump();
     }
 
+    /// Starts or stops logging ppu events for `ppu_events`
+    pub fn set_ppu_event_logging(&mut self, enabled: bool) {
+        if enabled != self.ppu_event_log.is_some() {
+            self.ppu_event_log = enabled.then(PpuEventLog::new);
+        }
+    }
+
+    /// Ppu events of the last complete frame
+    pub fn ppu_events(&self) -> &PpuEventLog {
+        &self.ppu_events
+    }
+
     pub fn cpu(&self) -> &Cpu {
         &self.cpu
     }
@@ -379,18 +571,41 @@ impl NESBoard {
         &self.ram
     }
 
+    /// The last frame as RGBA, `video_size` pixels with the overscan cropped off
     pub fn video_memory(&self) -> &[u8] {
-        &self.video_copy
+        &self.cropped_video
     }
 
-    pub fn pattern_table_memory(&self) -> &[u8] {
-        &self.chr_rom[0x0000..0x2000]
+    pub fn video_size(&self) -> (usize, usize) {
+        (self.overscan.width(), self.overscan.height())
     }
 
-    pub fn nametable_memory(&self, index: usize) -> &[u8] {
-        let start = 0x0400 * index;
-        let end = start + 0x0400;
-        &self.vram[start..end]
+    /// Saves the last frame as `video_memory` has it to a PNG file, upscaled by `scaler` if given
+    pub fn save_screenshot<P: AsRef<Path>>(&self, path: P, scaler: Option<Scaler>) -> io::Result<()> {
+        let (width, height) = self.video_size();
+        match scaler {
+            Some(scaler) => {
+                let mut scaled = Vec::new();
+                let (width, height) = scaler.scale(&self.cropped_video, width, height, &mut scaled);
+                png::save_png(path, &scaled, width, height)
+            }
+            None => png::save_png(path, &self.cropped_video, width, height),
+        }
+    }
+
+    pub fn overscan(&self) -> Overscan {
+        self.overscan
+    }
+
+    /// Crops `overscan` off the frames `video_memory` hands out from the next frame on
+    pub fn set_overscan(&mut self, overscan: Overscan) {
+        self.overscan = overscan;
+        self.overscan
+            .crop(&self.video_copy, 4, &mut self.cropped_video);
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
     }
 
     pub fn reset(&mut self) {
@@ -412,3 +627,121 @@ impl NESBoard {
         self.controllers[controller] = (self.controllers[controller] & !button_mask) | state_mask;
     }
 }
+
+impl PpuMemory for NESBoard {
+    fn peek(&self, address: u16) -> u8 {
+        let addr = usize::from(address);
+        match addr {
+            0x0000..0x2000 => self.chr_rom.get(addr).copied().unwrap_or(0),
+            _ => self.vram[self.current_nametable_arrangement().fold(address)],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::NESBoard;
+    use nes_rust::{
+        cartidge::mapper::{A12Filter, Mapper},
+        cpu::Cpu,
+        ppu::PpuBusEvent,
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Records the scanline of every A12 rise the MMC3's filter lets through
+    struct A12Counter {
+        filter: A12Filter,
+        rises: Arc<Mutex<Vec<usize>>>,
+    }
+
+    impl Mapper for A12Counter {
+        fn access_prg(&mut self, _address: u16, _data: &mut u8, _rw: bool) {}
+        fn access_chr(&mut self, _address: u16, _data: &mut u8, _rw: bool) {}
+        fn observe_ppu_bus(&mut self, event: &PpuBusEvent) {
+            if self.filter.observe(event) {
+                self.rises.lock().unwrap().push(event.scanline);
+            }
+        }
+    }
+
+    /// A board running `program` from $8000, with every vector pointing at its last instruction
+    fn board(program: &[u8]) -> NESBoard {
+        let mut prg_rom = vec![0xEA; 0x8000];
+        prg_rom[..program.len()].copy_from_slice(program);
+        let end = 0x8000 + program.len() as u16 - 3;
+        for vector in [0x7FFA, 0x7FFE] {
+            prg_rom[vector..vector + 2].copy_from_slice(&end.to_le_bytes());
+        }
+        prg_rom[0x7FFC..0x7FFE].copy_from_slice(&0x8000u16.to_le_bytes());
+        NESBoard::new(Cpu::new(), vec![0; 0x800], vec![0; 0x800], prg_rom, vec![0; 0x2000], 0)
+    }
+
+    /// Waits for vblank, then turns on rendering with the background at $0000 and 8x8 sprites at
+    /// $1000, followed by `setup`
+    fn rendering_program(setup: &[u8]) -> Vec<u8> {
+        #[rustfmt::skip]
+        let mut program = vec![
+            0x78,             // SEI
+            0x2C, 0x02, 0x20, // BIT $2002
+            0x10, 0xFB,       // BPL -5
+            0xA9, 0x08,       // LDA #$08
+            0x8D, 0x00, 0x20, // STA $2000
+            0xA9, 0x18,       // LDA #$18
+            0x8D, 0x01, 0x20, // STA $2001
+        ];
+        program.extend_from_slice(setup);
+        let end = 0x8000 + program.len() as u16;
+        program.push(0x4C); // JMP to itself
+        program.extend_from_slice(&end.to_le_bytes());
+        program
+    }
+
+    /// Clocks the board until the ppu is at the start of the next pre-render line
+    fn run_to_pre_render(board: &mut NESBoard) {
+        while board.ppu.scanline() == 261 {
+            board.clock(true);
+        }
+        while board.ppu.scanline() != 261 {
+            board.clock(true);
+        }
+    }
+
+    #[test]
+    fn mapper_sees_a12_rise_every_rendered_line() {
+        let rises = Arc::new(Mutex::new(Vec::new()));
+        let mut board = board(&rendering_program(&[]));
+        board.set_mapper(Some(Box::new(A12Counter {
+            filter: A12Filter::default(),
+            rises: rises.clone(),
+        })));
+        run_to_pre_render(&mut board);
+        run_to_pre_render(&mut board);
+        rises.lock().unwrap().clear();
+        run_to_pre_render(&mut board);
+
+        // The sprite fetches of the pre-render line and every visible line
+        let expected: Vec<usize> = [261].into_iter().chain(0..240).collect();
+        assert_eq!(*rises.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn mmc3_irq_is_raised_on_the_latched_line() {
+        #[rustfmt::skip]
+        let setup = [
+            0xA9, 0x07,       // LDA #$07
+            0x8D, 0x00, 0xC0, // STA $C000, the latch
+            0x8D, 0x01, 0xC0, // STA $C001, reload
+            0x8D, 0x01, 0xE0, // STA $E001, enable
+        ];
+        let mut board = board(&rendering_program(&setup));
+        board.set_mapper(nes_rust::cartidge::mapper::from_number(4));
+        // The program is set up by the end of the first vblank
+        run_to_pre_render(&mut board);
+        assert!(!board.mapper_irq);
+        while !board.mapper_irq {
+            board.clock(true);
+        }
+        // The pre-render line reloads the counter, 7 visible lines count it down
+        assert_eq!(board.ppu.scanline(), 6);
+    }
+}
@@ -0,0 +1,187 @@
+use std::f64::consts::PI;
+
+const PHASE_BITS: usize = 5;
+const PHASE_COUNT: usize = 1 << PHASE_BITS;
+const HALF_WIDTH: usize = 8;
+const KERNEL_WIDTH: usize = HALF_WIDTH * 2;
+/// Fraction of the output nyquist frequency passed by the step kernel
+const CUTOFF: f64 = 0.90;
+
+/// Band-limited step synthesis buffer, in the style of blargg's blip_buf.
+///
+/// Amplitude changes are added as deltas at a timestamp measured in source clocks (CPU cycles
+/// for the APU). Each delta is spread over a short windowed-sinc kernel so the step contains no
+/// energy above the output nyquist frequency, which removes the aliasing caused by picking every
+/// Nth raw sample. Reading integrates the deltas back into a waveform at the output sample rate.
+pub struct BlipBuffer {
+    clock_rate: f64,
+    sample_rate: f64,
+    /// Output samples per source clock
+    factor: f64,
+    /// Position, in output samples, of clock 0 of the frame being built
+    offset: f64,
+    deltas: Vec<f32>,
+    available: usize,
+    integrator: f32,
+    /// One band-limited impulse per sub-sample phase; entry PHASE_COUNT is a whole sample late
+    kernel: Box<[[f32; KERNEL_WIDTH]; PHASE_COUNT + 1]>,
+}
+
+impl BlipBuffer {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Self {
+        let mut buffer = Self {
+            clock_rate,
+            sample_rate: f64::from(sample_rate),
+            factor: 0.0,
+            offset: 0.0,
+            deltas: Vec::new(),
+            available: 0,
+            integrator: 0.0,
+            kernel: Box::new([[0.0; KERNEL_WIDTH]; PHASE_COUNT + 1]),
+        };
+        buffer.build_kernel();
+        buffer.set_rates(clock_rate, sample_rate);
+        buffer
+    }
+
+    fn build_kernel(&mut self) {
+        for (phase, taps) in self.kernel.iter_mut().enumerate() {
+            let fraction = phase as f64 / PHASE_COUNT as f64;
+            // The step lands between taps HALF_WIDTH - 1 and HALF_WIDTH, shifted by the phase
+            let center = (HALF_WIDTH - 1) as f64 + fraction;
+            let mut sum = 0.0;
+            let mut raw = [0.0f64; KERNEL_WIDTH];
+            for (i, tap) in raw.iter_mut().enumerate() {
+                let x = i as f64 - center;
+                let sinc = if x == 0.0 {
+                    1.0
+                } else {
+                    (PI * x * CUTOFF).sin() / (PI * x * CUTOFF)
+                };
+                // Blackman window spanning the whole kernel
+                let w = x / HALF_WIDTH as f64;
+                let window = if w.abs() >= 1.0 {
+                    0.0
+                } else {
+                    0.42 + 0.5 * (PI * w).cos() + 0.08 * (2.0 * PI * w).cos()
+                };
+                *tap = sinc * window;
+                sum += *tap;
+            }
+            // Every kernel must sum to exactly one so the integrated step settles on the delta
+            for (tap, raw) in taps.iter_mut().zip(raw) {
+                *tap = (raw / sum) as f32;
+            }
+        }
+    }
+
+    /// Changes the source clock and output sample rates. Pending samples are kept, but deltas
+    /// added afterwards are placed using the new ratio.
+    pub fn set_rates(&mut self, clock_rate: f64, sample_rate: u32) {
+        self.clock_rate = clock_rate;
+        self.sample_rate = f64::from(sample_rate);
+        self.factor = self.sample_rate / self.clock_rate;
+    }
+
+    pub fn clock_rate(&self) -> f64 {
+        self.clock_rate
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    /// Adds an amplitude change at `time` source clocks after the start of the current frame
+    pub fn add_delta(&mut self, time: u32, delta: f32) {
+        let position = self.offset + f64::from(time) * self.factor;
+        let whole = position.floor();
+        let phase = ((position - whole) * PHASE_COUNT as f64).round() as usize;
+        let start = whole as usize;
+        let end = start + KERNEL_WIDTH;
+        if self.deltas.len() < end {
+            self.deltas.resize(end, 0.0);
+        }
+        for (sample, tap) in self.deltas[start..end].iter_mut().zip(&self.kernel[phase]) {
+            *sample += delta * tap;
+        }
+    }
+
+    /// Ends the current frame after `duration` source clocks, making every output sample
+    /// before that point available for reading. Deltas of the next frame are timed from here.
+    pub fn end_frame(&mut self, duration: u32) {
+        self.offset += f64::from(duration) * self.factor;
+        self.available = self.offset.floor() as usize;
+        let needed = self.available + KERNEL_WIDTH;
+        if self.deltas.len() < needed {
+            self.deltas.resize(needed, 0.0);
+        }
+    }
+
+    pub fn samples_available(&self) -> usize {
+        self.available
+    }
+
+    /// Integrates up to `out.len()` finished samples into `out`, returning how many were written
+    pub fn read_samples(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.available);
+        for (sample, delta) in out.iter_mut().zip(&self.deltas[..count]) {
+            self.integrator += delta;
+            *sample = self.integrator;
+        }
+        self.deltas.drain(..count);
+        self.offset -= count as f64;
+        self.available -= count;
+        count
+    }
+
+    /// Drops all pending samples and deltas and returns the output to silence
+    pub fn clear(&mut self) {
+        self.offset = 0.0;
+        self.available = 0;
+        self.integrator = 0.0;
+        self.deltas.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BlipBuffer, KERNEL_WIDTH};
+    use crate::apu::NTSC_CPU_CLOCK_RATE;
+
+    #[test]
+    fn step_settles_after_the_kernel_width() {
+        let mut blip = BlipBuffer::new(NTSC_CPU_CLOCK_RATE, 44100);
+        // About 10.3 output samples in
+        blip.add_delta(420, 0.75);
+        blip.end_frame(4000);
+        let mut out = [0.0; 64];
+        let count = blip.read_samples(&mut out);
+        assert!(count > 10 + KERNEL_WIDTH);
+        // The kernel starts at the sample the delta lands in and is centred half its width later
+        assert!(out[..10].iter().all(|&sample| sample == 0.0));
+        let middle = out[10 + KERNEL_WIDTH / 2 - 1];
+        assert!(middle > 0.0 && middle < 0.75, "{middle}");
+        for &sample in &out[10 + KERNEL_WIDTH..count] {
+            assert!((sample - 0.75).abs() < 1e-5, "{sample}");
+        }
+    }
+
+    #[test]
+    fn samples_available_follows_the_sample_rate() {
+        for rate in [44100, 48000, 96000] {
+            let mut blip = BlipBuffer::new(NTSC_CPU_CLOCK_RATE, rate);
+            let mut read = 0;
+            let mut out = [0.0; 4096];
+            // One second of cpu clocks, in frames the size the apu uses
+            let mut remaining = NTSC_CPU_CLOCK_RATE as u32;
+            while remaining > 0 {
+                let frame = remaining.min(1024);
+                blip.end_frame(frame);
+                remaining -= frame;
+                read += blip.read_samples(&mut out);
+            }
+            assert!(read.abs_diff(rate as usize) <= 1, "{rate}: {read}");
+            assert_eq!(blip.samples_available(), 0);
+        }
+    }
+}
@@ -0,0 +1,122 @@
+use std::f32::consts::TAU;
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum FilterKind {
+    HighPass,
+    LowPass,
+}
+
+/// A first-order RC filter running at the output sample rate
+#[derive(Clone, Copy, Debug)]
+pub struct FirstOrderFilter {
+    kind: FilterKind,
+    alpha: f32,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl FirstOrderFilter {
+    pub fn new(kind: FilterKind, sample_rate: u32, cutoff: f32) -> Self {
+        let rc = 1.0 / (TAU * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        let alpha = match kind {
+            FilterKind::HighPass => rc / (rc + dt),
+            FilterKind::LowPass => dt / (rc + dt),
+        };
+        Self {
+            kind,
+            alpha,
+            previous_input: 0.0,
+            previous_output: 0.0,
+        }
+    }
+
+    pub fn high_pass(sample_rate: u32, cutoff: f32) -> Self {
+        Self::new(FilterKind::HighPass, sample_rate, cutoff)
+    }
+
+    pub fn low_pass(sample_rate: u32, cutoff: f32) -> Self {
+        Self::new(FilterKind::LowPass, sample_rate, cutoff)
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = match self.kind {
+            FilterKind::HighPass => {
+                self.alpha * (self.previous_output + input - self.previous_input)
+            }
+            FilterKind::LowPass => {
+                self.previous_output + self.alpha * (input - self.previous_output)
+            }
+        };
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+
+    pub fn reset(&mut self) {
+        self.previous_input = 0.0;
+        self.previous_output = 0.0;
+    }
+}
+
+/// The analog output stage of the NES: two high-pass filters (90Hz and 440Hz) followed by a
+/// 14kHz low-pass, as measured on the front-loading console.
+#[derive(Clone, Copy, Debug)]
+pub struct NesFilterChain {
+    filters: [FirstOrderFilter; 3],
+}
+
+impl NesFilterChain {
+    pub fn new(sample_rate: u32) -> Self {
+        Self {
+            filters: [
+                FirstOrderFilter::high_pass(sample_rate, 90.0),
+                FirstOrderFilter::high_pass(sample_rate, 440.0),
+                FirstOrderFilter::low_pass(sample_rate, 14000.0),
+            ],
+        }
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.filters
+            .iter_mut()
+            .fold(input, |sample, filter| filter.process(sample))
+    }
+
+    pub fn reset(&mut self) {
+        self.filters.iter_mut().for_each(FirstOrderFilter::reset);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FirstOrderFilter, NesFilterChain};
+
+    /// Output after a second of constant input
+    fn settle(mut process: impl FnMut(f32) -> f32, sample_rate: u32) -> f32 {
+        (0..sample_rate).fold(0.0, |_, _| process(1.0))
+    }
+
+    #[test]
+    fn high_passes_remove_dc() {
+        for cutoff in [90.0, 440.0] {
+            let mut filter = FirstOrderFilter::high_pass(44100, cutoff);
+            let first = filter.process(1.0);
+            assert!(first > 0.9);
+            assert!(settle(|sample| filter.process(sample), 44100).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn low_pass_keeps_dc() {
+        let mut filter = FirstOrderFilter::low_pass(44100, 14000.0);
+        assert!(filter.process(1.0) < 1.0);
+        assert!((settle(|sample| filter.process(sample), 44100) - 1.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn chain_removes_dc() {
+        let mut chain = NesFilterChain::new(48000);
+        assert!(settle(|sample| chain.process(sample), 48000).abs() < 1e-4);
+    }
+}
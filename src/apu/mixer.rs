@@ -0,0 +1,110 @@
+/// Approximates the non-linear DAC of the 2A03 the same way the hardware is usually described:
+/// both pulse channels share one resistor network and the triangle, noise and DMC share another.
+/// https://www.nesdev.org/wiki/APU_Mixer#Lookup_Table
+pub const PULSE_TABLE: [f32; 31] = build_pulse_table();
+pub const TND_TABLE: [f32; 203] = build_tnd_table();
+
+const fn build_pulse_table() -> [f32; 31] {
+    let mut table = [0.0; 31];
+    let mut n = 1;
+    while n < table.len() {
+        table[n] = 95.52 / (8128.0 / n as f32 + 100.0);
+        n += 1;
+    }
+    table
+}
+
+const fn build_tnd_table() -> [f32; 203] {
+    let mut table = [0.0; 203];
+    let mut n = 1;
+    while n < table.len() {
+        table[n] = 163.67 / (24329.0 / n as f32 + 100.0);
+        n += 1;
+    }
+    table
+}
+
+/// Output level of the pulse pair, each input being a 4-bit channel output
+pub fn pulse_out(pulse1: u8, pulse2: u8) -> f32 {
+    PULSE_TABLE[usize::from(pulse1 + pulse2)]
+}
+
+/// Output level of the triangle (4-bit), noise (4-bit) and dmc (7-bit) group
+pub fn tnd_out(triangle: u8, noise: u8, dmc: u8) -> f32 {
+    TND_TABLE[3 * usize::from(triangle) + 2 * usize::from(noise) + usize::from(dmc)]
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ApuChannel {
+    Pulse1,
+    Pulse2,
+    Triangle,
+    Noise,
+    Dmc,
+}
+
+impl ApuChannel {
+    pub const ALL: [ApuChannel; 5] = [
+        ApuChannel::Pulse1,
+        ApuChannel::Pulse2,
+        ApuChannel::Triangle,
+        ApuChannel::Noise,
+        ApuChannel::Dmc,
+    ];
+
+    pub fn index(self) -> usize {
+        self as usize
+    }
+
+    /// The level this channel would contribute to the mix if it were playing alone; useful for
+    /// rendering per-channel stems and oscilloscopes on the same scale as the full mix.
+    pub fn solo_level(self, output: u8) -> f32 {
+        match self {
+            ApuChannel::Pulse1 | ApuChannel::Pulse2 => pulse_out(output, 0),
+            ApuChannel::Triangle => tnd_out(output, 0, 0),
+            ApuChannel::Noise => tnd_out(0, output, 0),
+            ApuChannel::Dmc => tnd_out(0, 0, output),
+        }
+    }
+}
+
+/// Snapshot of a single channel, as seen by the mixer during the last cpu cycle
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct ChannelState {
+    /// Current DAC input: 0-15 for pulse, triangle and noise, 0-127 for the DMC
+    pub output: u8,
+    /// Volume the channel would play at while its waveform is high
+    pub volume: u8,
+    /// Raw timer period from the channel's registers
+    pub period: u16,
+    /// Enable bit from $4015
+    pub enabled: bool,
+    pub length_counter: u8,
+    /// Whether the channel is currently excluded from the mix by `Apu::set_channel_muted`/`solo`
+    pub muted: bool,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{pulse_out, tnd_out, PULSE_TABLE, TND_TABLE};
+
+    #[test]
+    fn silence_is_zero() {
+        assert_eq!(PULSE_TABLE[0], 0.0);
+        assert_eq!(TND_TABLE[0], 0.0);
+        assert_eq!(pulse_out(0, 0), 0.0);
+        assert_eq!(tnd_out(0, 0, 0), 0.0);
+    }
+
+    #[test]
+    fn tables_follow_the_documented_formulas() {
+        let pulse = 95.52 / (8128.0 / 30.0 + 100.0);
+        assert!((PULSE_TABLE[30] - pulse).abs() < 1e-6);
+        assert_eq!(pulse_out(15, 15), PULSE_TABLE[30]);
+        let tnd = 163.67 / (24329.0 / 202.0 + 100.0);
+        assert!((TND_TABLE[202] - tnd).abs() < 1e-6);
+        assert_eq!(tnd_out(15, 15, 127), TND_TABLE[202]);
+        // Both groups at full volume come to just under 1.0
+        assert!((PULSE_TABLE[30] + TND_TABLE[202] - 1.0).abs() < 0.02);
+    }
+}
@@ -1,3 +1,19 @@
+pub mod blip;
+pub mod filter;
+pub mod mixer;
+pub mod vgm;
+pub mod wav;
+
+use blip::BlipBuffer;
+use filter::NesFilterChain;
+use mixer::{ApuChannel, ChannelState};
+
+/// Clock rate of the NTSC 2A03, which drives the APU
+pub const NTSC_CPU_CLOCK_RATE: f64 = 1789773.0;
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+/// Number of cpu cycles synthesized before output samples are made available for reading
+const AUDIO_FRAME_CYCLES: u32 = 1024;
+
 struct Sequencer {
     timer: u16,
     reload: u16,
@@ -93,6 +109,45 @@ impl LengthCounter {
     }
 }
 
+/// Decays the channel volume from 15 to 0, one step every `period + 1` quarter frames
+struct Envelope {
+    start: bool,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self {
+            start: false,
+            divider: 0,
+            decay: 0,
+        }
+    }
+    pub fn clock(&mut self, period: u8, looping: bool) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = period;
+        } else if self.divider == 0 {
+            self.divider = period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+    pub fn value(&self) -> u8 {
+        self.decay
+    }
+}
+
 struct Sweeper {}
 
 struct PulseChannel {
@@ -100,6 +155,7 @@ struct PulseChannel {
     length_counter_toggle: bool,
     volume_envelope_toggle: bool,
     volume_envelope_period: u8,
+    envelope: Envelope,
     sweeper: Sweeper,
     sequencer: Sequencer,
     length_counter: LengthCounter,
@@ -112,6 +168,7 @@ impl PulseChannel {
             length_counter_toggle: false,
             volume_envelope_toggle: false,
             volume_envelope_period: 0,
+            envelope: Envelope::new(),
             sweeper: Sweeper {},
             sequencer: Sequencer::new(),
             length_counter: LengthCounter::new(),
@@ -139,10 +196,28 @@ impl PulseChannel {
         if muted {
             0
         } else {
-            sample
+            sample * self.volume()
         }
     }
 
+    /// 4-bit volume applied to the high portion of the duty cycle
+    pub fn volume(&self) -> u8 {
+        if self.volume_envelope_toggle {
+            self.volume_envelope_period
+        } else {
+            self.envelope.value()
+        }
+    }
+
+    /// Clocked every quarter frame. The loop flag shares its bit with the length counter halt.
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock(self.volume_envelope_period, self.length_counter_toggle);
+    }
+
+    pub fn period(&self) -> u16 {
+        self.sequencer.reload
+    }
+
     pub fn write_0(
         &mut self,
         duty: u8,
@@ -168,6 +243,7 @@ impl PulseChannel {
         self.sequencer.output = 0;
 
         self.length_counter.reload(length_counter_load);
+        self.envelope.restart();
 
         // Immediately restart sequencer, restart envelope. Period Divider is NOT reset
     }
@@ -270,6 +346,17 @@ pub struct Apu {
     clock_counter: u64,
     frame_counter: u32,
     frame_mode: bool,
+
+    // Output synthesis
+    blip: BlipBuffer,
+    filters: NesFilterChain,
+    blip_clock: u32,
+    pulse_level: f32,
+    tnd_level: f32,
+
+    // Per-channel taps, indexed by ApuChannel
+    channel_outputs: [u8; 5],
+    channel_muted: [bool; 5],
 }
 
 impl Apu {
@@ -289,8 +376,146 @@ impl Apu {
             clock_counter: 0,
             frame_counter: 0,
             frame_mode: false,
+
+            blip: BlipBuffer::new(NTSC_CPU_CLOCK_RATE, DEFAULT_SAMPLE_RATE),
+            filters: NesFilterChain::new(DEFAULT_SAMPLE_RATE),
+            blip_clock: 0,
+            pulse_level: 0.0,
+            tnd_level: 0.0,
+
+            channel_outputs: [0; 5],
+            channel_muted: [false; 5],
         }
     }
+
+    /// Current DAC inputs of every channel, indexed by `ApuChannel::index`. Reading this after
+    /// each `clock` gives the raw per-channel waveforms for oscilloscopes or stem export.
+    pub fn channel_outputs(&self) -> [u8; 5] {
+        self.channel_outputs
+    }
+
+    pub fn channel_state(&self, channel: ApuChannel) -> ChannelState {
+        let index = channel.index();
+        let (volume, period, enabled, length_counter) = match channel {
+            ApuChannel::Pulse1 => (
+                self.square1.volume(),
+                self.square1.period(),
+                self.square1_enable,
+                self.square1.length_counter.value(),
+            ),
+            ApuChannel::Pulse2 => (
+                self.square2.volume(),
+                self.square2.period(),
+                self.square2_enable,
+                self.square2.length_counter.value(),
+            ),
+            // TODO: Report real state once the triangle, noise and dmc channels are emulated
+            ApuChannel::Triangle => (0, self.triangle.reload, self.triangle_enable, 0),
+            ApuChannel::Noise => (0, self.noise.reload, self.noise_enable, 0),
+            ApuChannel::Dmc => (0, 0, false, 0),
+        };
+        ChannelState {
+            output: self.channel_outputs[index],
+            volume,
+            period,
+            enabled,
+            length_counter,
+            muted: self.channel_muted[index],
+        }
+    }
+
+    /// Removes a channel from the mix without affecting its emulation
+    pub fn set_channel_muted(&mut self, channel: ApuChannel, muted: bool) {
+        self.channel_muted[channel.index()] = muted;
+    }
+
+    pub fn is_channel_muted(&self, channel: ApuChannel) -> bool {
+        self.channel_muted[channel.index()]
+    }
+
+    /// Mutes every channel except `channel`, or unmutes all of them when given None
+    pub fn solo_channel(&mut self, channel: Option<ApuChannel>) {
+        for other in ApuChannel::ALL {
+            self.channel_muted[other.index()] = channel.is_some_and(|solo| solo != other);
+        }
+    }
+
+    /// Sets the rate of the resampled output, e.g. 44100, 48000 or 96000
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.blip.set_rates(self.blip.clock_rate(), sample_rate);
+        self.filters = NesFilterChain::new(sample_rate);
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.blip.sample_rate()
+    }
+
+    /// Sets the rate at which `clock` is called, which is the cpu clock rate of the console
+    pub fn set_clock_rate(&mut self, clock_rate: f64) {
+        self.blip.set_rates(clock_rate, self.blip.sample_rate());
+    }
+
+    /// Number of resampled output frames that can be read
+    pub fn samples_available(&self) -> usize {
+        self.blip.samples_available()
+    }
+
+    /// Reads filtered, resampled output in the range [-1.0, 1.0], returning the number of
+    /// samples written
+    pub fn read_samples_f32(&mut self, out: &mut [f32]) -> usize {
+        let count = self.blip.read_samples(out);
+        for sample in &mut out[..count] {
+            *sample = self.filters.process(*sample).clamp(-1.0, 1.0);
+        }
+        count
+    }
+
+    /// Reads filtered, resampled output as signed 16-bit PCM, returning the number of samples
+    /// written
+    pub fn read_samples_i16(&mut self, out: &mut [i16]) -> usize {
+        let mut buffer = [0.0f32; 256];
+        let mut written = 0;
+        while written < out.len() {
+            let chunk = (out.len() - written).min(buffer.len());
+            let count = self.read_samples_f32(&mut buffer[..chunk]);
+            for (pcm, sample) in out[written..written + count].iter_mut().zip(&buffer) {
+                *pcm = (sample * f32::from(i16::MAX)) as i16;
+            }
+            written += count;
+            if count < chunk {
+                break;
+            }
+        }
+        written
+    }
+
+    /// Drops any synthesized output that has not been read yet
+    pub fn clear_samples(&mut self) {
+        self.blip.clear();
+        self.filters.reset();
+        self.blip_clock = 0;
+        self.pulse_level = 0.0;
+        self.tnd_level = 0.0;
+    }
+
+    /// Places the amplitude changes of this cycle into the band-limited buffer
+    fn synthesize(&mut self, pulse_out: f32, tnd_out: f32) {
+        if pulse_out != self.pulse_level {
+            self.blip.add_delta(self.blip_clock, pulse_out - self.pulse_level);
+            self.pulse_level = pulse_out;
+        }
+        if tnd_out != self.tnd_level {
+            self.blip.add_delta(self.blip_clock, tnd_out - self.tnd_level);
+            self.tnd_level = tnd_out;
+        }
+        self.blip_clock += 1;
+        if self.blip_clock == AUDIO_FRAME_CYCLES {
+            self.blip.end_frame(self.blip_clock);
+            self.blip_clock = 0;
+        }
+    }
+    /// Emulates one cpu cycle of the APU. Returns the raw, unfiltered mixer level of this cycle;
+    /// the band-limited output meant for playback is read with `read_samples_f32`/`read_samples_i16`.
     pub fn clock(&mut self, pins: &mut ApuPinout) -> f64 {
         if !pins.cpu_rw {
             let data = pins.cpu_data;
@@ -428,6 +653,8 @@ impl Apu {
 
             if clock_envelopes {
                 // Clock envelopes and triangle linear counter
+                self.square1.clock_envelope();
+                self.square2.clock_envelope();
             }
 
             if clock_length_counters {
@@ -451,31 +678,32 @@ impl Apu {
         }
         self.clock_counter += 1;
 
-        let p1_sample = self.square1.sample() as f64;
-        let p2_sample = self.square2.sample() as f64;
+        let p1_sample = self.square1.sample();
+        let p2_sample = self.square2.sample();
 
         // self.triangle.clock(self.triangle_enable, |_s| {});
-        let t_sample = 0.0;
+        let t_sample = 0;
 
         // self.noise.clock(self.noise_enable, |_s| {});
-        let n_sample = 0.0;
+        let n_sample = 0;
 
-        let dmc_sample = 0.0;
+        let dmc_sample = 0;
 
-        let pulse_under = p1_sample + p2_sample;
-        let pulse_out = if pulse_under == 0.0 {
-            0.0
-        } else {
-            95.88 / ((8128.0 / pulse_under) + 100.0)
-        };
-        let tnd_under = (t_sample / 8227.0) + (n_sample / 12241.0) + dmc_sample / 22638.0;
-        let tnd_out = if tnd_under == 0.0 {
-            0.0
-        } else {
-            159.79 / (1.0 / tnd_under + 100.0)
-        };
+        self.channel_outputs = [p1_sample, p2_sample, t_sample, n_sample, dmc_sample];
+        let [p1, p2, t, n, dmc] = std::array::from_fn(|i| {
+            if self.channel_muted[i] {
+                0
+            } else {
+                self.channel_outputs[i]
+            }
+        });
 
-        pulse_out + tnd_out
+        let pulse_out = mixer::pulse_out(p1, p2);
+        let tnd_out = mixer::tnd_out(t, n, dmc);
+
+        self.synthesize(pulse_out, tnd_out);
+
+        f64::from(pulse_out + tnd_out)
     }
 }
 
@@ -484,3 +712,79 @@ impl Default for Apu {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{mixer::pulse_out, Apu, ApuChannel, ApuPinout};
+
+    fn write(apu: &mut Apu, register: u8, data: u8) {
+        let mut pins = ApuPinout {
+            cpu_addr: register,
+            cpu_data: data,
+            cpu_rw: false,
+        };
+        apu.clock(&mut pins);
+    }
+
+    /// Plays both pulse channels at constant volumes, checking every cycle's mix against the
+    /// channels that should be in it
+    fn check_mix(apu: &mut Apu, pulse1: bool, pulse2: bool) {
+        let mut pins = ApuPinout::new();
+        let mut heard = [false; 2];
+        for _ in 0..2000 {
+            let mixed = apu.clock(&mut pins);
+            let [p1, p2, ..] = apu.channel_outputs();
+            heard[0] |= p1 != 0;
+            heard[1] |= p2 != 0;
+            let expected = pulse_out(if pulse1 { p1 } else { 0 }, if pulse2 { p2 } else { 0 });
+            assert_eq!(mixed, f64::from(expected));
+        }
+        assert_eq!(heard, [true, true]);
+    }
+
+    #[test]
+    fn muted_channels_leave_the_mix() {
+        let mut apu = Apu::new();
+        write(&mut apu, 0x15, 0x03);
+        // 50% duty, constant volumes 15 and 6
+        write(&mut apu, 0x00, 0xBF);
+        write(&mut apu, 0x02, 0x40);
+        write(&mut apu, 0x03, 0x08);
+        write(&mut apu, 0x04, 0xB6);
+        write(&mut apu, 0x06, 0x61);
+        write(&mut apu, 0x07, 0x08);
+
+        check_mix(&mut apu, true, true);
+        apu.set_channel_muted(ApuChannel::Pulse1, true);
+        check_mix(&mut apu, false, true);
+        apu.solo_channel(Some(ApuChannel::Pulse1));
+        assert!(!apu.is_channel_muted(ApuChannel::Pulse1));
+        check_mix(&mut apu, true, false);
+        apu.solo_channel(None);
+        check_mix(&mut apu, true, true);
+    }
+
+    #[test]
+    fn i16_output_clamps() {
+        for level in [3.0, -3.0] {
+            let mut apu = Apu::new();
+            // Far past full scale; the filters pass the start of a step almost unchanged
+            apu.blip.add_delta(0, level);
+            apu.blip.end_frame(2000);
+            let mut out = [0; 64];
+            let count = apu.read_samples_i16(&mut out);
+            let peak = if level > 0.0 { i16::MAX } else { -i16::MAX };
+            let first = out[..count]
+                .iter()
+                .position(|&sample| sample == peak)
+                .unwrap();
+            let last = out[..count]
+                .iter()
+                .rposition(|&sample| sample == peak)
+                .unwrap();
+            // Wrapping would scatter the top of the step across the range
+            assert!(last - first > 8);
+            assert!(out[first..=last].iter().all(|&sample| sample == peak));
+        }
+    }
+}
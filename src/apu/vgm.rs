@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+
+/// Sample rate every VGM wait command is measured in
+const VGM_SAMPLE_RATE: u64 = 44100;
+const VGM_VERSION: u32 = 0x0000_0171;
+const HEADER_SIZE: usize = 0x100;
+
+const CMD_NES_APU_WRITE: u8 = 0xB4;
+const CMD_WAIT: u8 = 0x61;
+const CMD_WAIT_NTSC_FRAME: u8 = 0x62;
+const CMD_WAIT_PAL_FRAME: u8 = 0x63;
+const CMD_WAIT_SHORT: u8 = 0x70;
+const CMD_END_OF_DATA: u8 = 0x66;
+const CMD_DATA_BLOCK: u8 = 0x67;
+/// Data block type for writes into the NES APU's view of cpu memory ($C000-$FFFF samples)
+const DATA_BLOCK_NES_APU_RAM: u8 = 0xC2;
+
+/// First address the DMC can fetch sample bytes from
+const DMC_SAMPLE_BASE: u16 = 0xC000;
+
+/// Captures writes to the APU (and FDS expansion audio) together with the cpu cycle they
+/// happened on, then serializes them as a VGM 1.71 file using the NES APU chip commands.
+///
+/// Sample data for the DMC lives in cartridge memory, so whenever the game starts a sample the
+/// recorder copies the bytes it points at into a data block; VGM players keep those blocks in
+/// their own copy of $C000-$FFFF. Expansion chips other than the FDS have no VGM 1.71 command and
+/// are ignored.
+pub struct VgmRecorder {
+    cpu_clock_rate: f64,
+    commands: Vec<u8>,
+    /// Cpu cycle of the first recorded write; waits are measured from here
+    start_cycle: Option<u64>,
+    /// Number of 44.1kHz samples already covered by wait commands
+    samples_written: u64,
+    uses_fds: bool,
+
+    dmc_address: u8,
+    dmc_length: u8,
+    /// What the player's copy of $C000-$FFFF holds, so unchanged samples are not sent twice
+    dmc_memory: BTreeMap<u16, u8>,
+}
+
+impl VgmRecorder {
+    pub fn new(cpu_clock_rate: f64) -> Self {
+        Self {
+            cpu_clock_rate,
+            commands: Vec::new(),
+            start_cycle: None,
+            samples_written: 0,
+            uses_fds: false,
+            dmc_address: 0,
+            dmc_length: 0,
+            dmc_memory: BTreeMap::new(),
+        }
+    }
+
+    /// Whether `address` is a register the recorder captures
+    pub fn is_audio_register(address: u16) -> bool {
+        Self::register_byte(address).is_some()
+    }
+
+    /// Maps a cpu address onto the register byte of the 0xB4 command
+    fn register_byte(address: u16) -> Option<u8> {
+        match address {
+            // $4014 is OAM DMA and $4016 the controller strobe; neither are audio
+            0x4014 | 0x4016 => None,
+            0x4000..=0x401F => Some((address - 0x4000) as u8),
+            // FDS sound registers
+            0x4080..=0x409E => Some((address - 0x4080) as u8 + 0x20),
+            0x4023 => Some(0x3F),
+            // FDS wave table
+            0x4040..=0x407F => Some((address - 0x4040) as u8 + 0x40),
+            _ => None,
+        }
+    }
+
+    /// Records a cpu write. `read_memory` is used to copy DMC sample bytes out of cartridge
+    /// memory when a sample is started.
+    pub fn record_write(
+        &mut self,
+        cpu_cycle: u64,
+        address: u16,
+        data: u8,
+        read_memory: impl FnMut(u16) -> u8,
+    ) {
+        let Some(register) = Self::register_byte(address) else {
+            return;
+        };
+        self.wait_until(cpu_cycle);
+
+        match address {
+            0x4012 => self.dmc_address = data,
+            0x4013 => self.dmc_length = data,
+            // Enabling the DMC (re)starts the sample at the current address and length
+            0x4015 if data & 0x10 > 0 => self.record_dmc_sample(read_memory),
+            0x4023 | 0x4040..=0x409E => self.uses_fds = true,
+            _ => {}
+        }
+
+        self.commands
+            .extend_from_slice(&[CMD_NES_APU_WRITE, register, data]);
+    }
+
+    fn record_dmc_sample(&mut self, mut read_memory: impl FnMut(u16) -> u8) {
+        let start = DMC_SAMPLE_BASE.wrapping_add(u16::from(self.dmc_address) * 64);
+        let length = usize::from(self.dmc_length) * 16 + 1;
+        let mut bytes = Vec::with_capacity(length);
+        let mut changed = false;
+        for offset in 0..length {
+            // Sample fetches wrap from $FFFF around to $8000
+            let address = match start as usize + offset {
+                a if a > 0xFFFF => (a - 0x8000) as u16,
+                a => a as u16,
+            };
+            let byte = read_memory(address);
+            changed |= self.dmc_memory.insert(address, byte) != Some(byte);
+            bytes.push(byte);
+        }
+        if !changed {
+            return;
+        }
+
+        // A block can't wrap, so split it when the sample runs past $FFFF
+        let first_length = (0x1_0000 - start as usize).min(length);
+        self.push_data_block(start, &bytes[..first_length]);
+        if first_length < length {
+            self.push_data_block(0x8000, &bytes[first_length..]);
+        }
+    }
+
+    fn push_data_block(&mut self, address: u16, bytes: &[u8]) {
+        let size = (bytes.len() + 2) as u32;
+        self.commands
+            .extend_from_slice(&[CMD_DATA_BLOCK, CMD_END_OF_DATA, DATA_BLOCK_NES_APU_RAM]);
+        self.commands.extend_from_slice(&size.to_le_bytes());
+        self.commands.extend_from_slice(&address.to_le_bytes());
+        self.commands.extend_from_slice(bytes);
+    }
+
+    /// Emits wait commands covering the time between the last event and `cpu_cycle`
+    fn wait_until(&mut self, cpu_cycle: u64) {
+        let start = *self.start_cycle.get_or_insert(cpu_cycle);
+        let elapsed = cpu_cycle.saturating_sub(start) as f64;
+        let target = (elapsed * VGM_SAMPLE_RATE as f64 / self.cpu_clock_rate) as u64;
+        let mut remaining = target.saturating_sub(self.samples_written);
+        self.samples_written += remaining;
+        while remaining > 0 {
+            let step = match remaining {
+                735 => {
+                    self.commands.push(CMD_WAIT_NTSC_FRAME);
+                    735
+                }
+                882 => {
+                    self.commands.push(CMD_WAIT_PAL_FRAME);
+                    882
+                }
+                1..=16 => {
+                    self.commands.push(CMD_WAIT_SHORT + (remaining - 1) as u8);
+                    remaining
+                }
+                _ => {
+                    let step = remaining.min(u64::from(u16::MAX));
+                    self.commands.push(CMD_WAIT);
+                    self.commands
+                        .extend_from_slice(&(step as u16).to_le_bytes());
+                    step
+                }
+            };
+            remaining -= step;
+        }
+    }
+
+    /// Total length of the recording in 44.1kHz samples
+    pub fn total_samples(&self) -> u64 {
+        self.samples_written
+    }
+
+    /// Pads the recording up to `cpu_cycle` and serializes it into a VGM file
+    pub fn finish(mut self, cpu_cycle: u64) -> Vec<u8> {
+        self.wait_until(cpu_cycle);
+
+        let mut file = vec![0u8; HEADER_SIZE];
+        let mut put = |offset: usize, value: u32| {
+            file[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        };
+        put(0x08, VGM_VERSION);
+        put(0x18, self.samples_written as u32);
+        // Data offsets are relative to the field holding them
+        put(0x34, (HEADER_SIZE - 0x34) as u32);
+        let fds_flag = if self.uses_fds { 0x8000_0000 } else { 0 };
+        put(0x84, self.cpu_clock_rate.round() as u32 | fds_flag);
+        file[0x00..0x04].copy_from_slice(b"Vgm ");
+
+        file.extend_from_slice(&self.commands);
+        file.push(CMD_END_OF_DATA);
+        let eof_offset = (file.len() - 0x04) as u32;
+        file[0x04..0x08].copy_from_slice(&eof_offset.to_le_bytes());
+        file
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::VgmRecorder;
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    /// The commands of a recording, between the header and the end of data command
+    fn commands(file: &[u8]) -> &[u8] {
+        assert_eq!(file.last(), Some(&0x66));
+        &file[0x100..file.len() - 1]
+    }
+
+    #[test]
+    fn header_fields() {
+        let mut recorder = VgmRecorder::new(1789773.0);
+        recorder.record_write(100, 0x4000, 0x30, |_| 0);
+        // One second after the first write
+        let file = recorder.finish(100 + 1789773);
+
+        assert_eq!(&file[0x00..0x04], b"Vgm ");
+        assert_eq!(u32_at(&file, 0x04) as usize, file.len() - 0x04);
+        assert_eq!(u32_at(&file, 0x08), 0x171);
+        assert_eq!(u32_at(&file, 0x18), 44100);
+        assert_eq!(u32_at(&file, 0x34) as usize, 0x100 - 0x34);
+        assert_eq!(u32_at(&file, 0x84), 1789773);
+        assert_eq!(&commands(&file)[..3], &[0xB4, 0x00, 0x30]);
+    }
+
+    #[test]
+    fn waits_use_the_shortest_command() {
+        // A clock of 44.1kHz makes every cpu cycle one sample
+        let mut recorder = VgmRecorder::new(44100.0);
+        let mut cycle = 0;
+        for wait in [0, 735, 882, 1, 16, 17, 70000] {
+            cycle += wait;
+            recorder.record_write(cycle, 0x4011, 0x00, |_| 0);
+        }
+        assert_eq!(recorder.total_samples(), cycle);
+        let file = recorder.finish(cycle);
+        #[rustfmt::skip]
+        let expected = [
+            0xB4, 0x11, 0x00,
+            0x62, 0xB4, 0x11, 0x00,
+            0x63, 0xB4, 0x11, 0x00,
+            0x70, 0xB4, 0x11, 0x00,
+            0x7F, 0xB4, 0x11, 0x00,
+            0x61, 17, 0, 0xB4, 0x11, 0x00,
+            // Waits past 65535 samples take more than one command
+            0x61, 0xFF, 0xFF, 0x61, 0x71, 0x11, 0xB4, 0x11, 0x00,
+        ];
+        assert_eq!(commands(&file), &expected);
+    }
+
+    #[test]
+    fn register_offsets() {
+        let mut recorder = VgmRecorder::new(44100.0);
+        for (address, register) in [
+            (0x4000, Some(0x00)),
+            (0x4013, Some(0x13)),
+            (0x4014, None),
+            (0x4016, None),
+            (0x4017, Some(0x17)),
+            (0x4023, Some(0x3F)),
+            (0x4040, Some(0x40)),
+            (0x407F, Some(0x7F)),
+            (0x4080, Some(0x20)),
+            (0x409E, Some(0x3E)),
+            (0x409F, None),
+        ] {
+            recorder.record_write(0, address, 0x5A, |_| 0);
+            let recorded = recorder
+                .commands
+                .ends_with(&[0xB4, register.unwrap_or(0), 0x5A]);
+            assert_eq!(recorded, register.is_some(), "{address:04X}");
+            recorder.commands.clear();
+        }
+        // The FDS writes set the expansion flag on the clock
+        assert_eq!(u32_at(&recorder.finish(0), 0x84), 44100 | 0x8000_0000);
+    }
+
+    #[test]
+    fn dmc_samples_become_data_blocks_split_at_ffff() {
+        let mut recorder = VgmRecorder::new(44100.0);
+        let memory = |address: u16| (address as u8) ^ 0xA5;
+        // $FFC0, 65 bytes: 64 before the end of memory and one wrapped around to $8000
+        recorder.record_write(0, 0x4012, 0xFF, memory);
+        recorder.record_write(0, 0x4013, 0x04, memory);
+        recorder.record_write(0, 0x4015, 0x10, memory);
+        // Starting the same sample again sends nothing new
+        recorder.record_write(0, 0x4015, 0x10, memory);
+        let file = recorder.finish(0);
+        let commands = commands(&file);
+
+        assert_eq!(&commands[..6], &[0xB4, 0x12, 0xFF, 0xB4, 0x13, 0x04]);
+        let first = &commands[6..];
+        assert_eq!(&first[..3], &[0x67, 0x66, 0xC2]);
+        assert_eq!(u32_at(first, 3), 64 + 2);
+        assert_eq!(&first[7..9], &[0xC0, 0xFF]);
+        let bytes: Vec<u8> = (0xFFC0..=0xFFFF).map(memory).collect();
+        assert_eq!(&first[9..9 + 64], &bytes[..]);
+
+        let second = &first[9 + 64..];
+        assert_eq!(&second[..3], &[0x67, 0x66, 0xC2]);
+        assert_eq!(u32_at(second, 3), 1 + 2);
+        assert_eq!(&second[7..10], &[0x00, 0x80, memory(0x8000)]);
+        assert_eq!(&second[10..], &[0xB4, 0x15, 0x10, 0xB4, 0x15, 0x10]);
+    }
+}
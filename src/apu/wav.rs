@@ -0,0 +1,257 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use super::Apu;
+
+const WAVE_FORMAT_PCM: u16 = 1;
+const WAVE_FORMAT_IEEE_FLOAT: u16 = 3;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WavChannels {
+    Mono,
+    /// The mono APU output duplicated into both channels
+    Stereo,
+}
+
+impl WavChannels {
+    fn count(self) -> u16 {
+        match self {
+            WavChannels::Mono => 1,
+            WavChannels::Stereo => 2,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WavSampleFormat {
+    Pcm16,
+    Float32,
+}
+
+impl WavSampleFormat {
+    fn bytes_per_sample(self) -> u16 {
+        match self {
+            WavSampleFormat::Pcm16 => 2,
+            WavSampleFormat::Float32 => 4,
+        }
+    }
+}
+
+/// Streams audio into a RIFF/WAVE file. Sizes in the header are written as placeholders and
+/// patched by `finish`, so the writer needs to be seekable. The output only depends on the
+/// samples written, which makes it suitable for hashing in tests.
+pub struct WavWriter<W: Write + Seek> {
+    inner: W,
+    channels: WavChannels,
+    format: WavSampleFormat,
+    /// Stream position of the RIFF chunk size
+    riff_size_position: u64,
+    /// Stream position of the fact chunk's frame count, only present for float data
+    fact_position: Option<u64>,
+    data_size_position: u64,
+    frames: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        sample_rate: u32,
+        channels: WavChannels,
+        format: WavSampleFormat,
+    ) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        Self::new(file, sample_rate, channels, format)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(
+        mut inner: W,
+        sample_rate: u32,
+        channels: WavChannels,
+        format: WavSampleFormat,
+    ) -> io::Result<Self> {
+        let start = inner.stream_position()?;
+        let channel_count = channels.count();
+        let block_align = channel_count * format.bytes_per_sample();
+        let (format_tag, fmt_size) = match format {
+            WavSampleFormat::Pcm16 => (WAVE_FORMAT_PCM, 16u32),
+            // Non-PCM formats carry an (empty) extension size field
+            WavSampleFormat::Float32 => (WAVE_FORMAT_IEEE_FLOAT, 18u32),
+        };
+
+        inner.write_all(b"RIFF")?;
+        let riff_size_position = start + 4;
+        inner.write_all(&0u32.to_le_bytes())?;
+        inner.write_all(b"WAVE")?;
+
+        inner.write_all(b"fmt ")?;
+        inner.write_all(&fmt_size.to_le_bytes())?;
+        inner.write_all(&format_tag.to_le_bytes())?;
+        inner.write_all(&channel_count.to_le_bytes())?;
+        inner.write_all(&sample_rate.to_le_bytes())?;
+        inner.write_all(&(sample_rate * u32::from(block_align)).to_le_bytes())?;
+        inner.write_all(&block_align.to_le_bytes())?;
+        inner.write_all(&(format.bytes_per_sample() * 8).to_le_bytes())?;
+
+        let mut fact_position = None;
+        if format == WavSampleFormat::Float32 {
+            inner.write_all(&0u16.to_le_bytes())?;
+            inner.write_all(b"fact")?;
+            inner.write_all(&4u32.to_le_bytes())?;
+            fact_position = Some(inner.stream_position()?);
+            inner.write_all(&0u32.to_le_bytes())?;
+        }
+
+        inner.write_all(b"data")?;
+        let data_size_position = inner.stream_position()?;
+        inner.write_all(&0u32.to_le_bytes())?;
+
+        Ok(Self {
+            inner,
+            channels,
+            format,
+            riff_size_position,
+            fact_position,
+            data_size_position,
+            frames: 0,
+        })
+    }
+
+    /// Appends mono samples in the range [-1.0, 1.0]; values outside the range are clipped
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(
+            samples.len() * usize::from(self.channels.count() * self.format.bytes_per_sample()),
+        );
+        for sample in samples {
+            let sample = sample.clamp(-1.0, 1.0);
+            for _ in 0..self.channels.count() {
+                match self.format {
+                    WavSampleFormat::Pcm16 => {
+                        let pcm = (sample * f32::from(i16::MAX)) as i16;
+                        bytes.extend_from_slice(&pcm.to_le_bytes());
+                    }
+                    WavSampleFormat::Float32 => bytes.extend_from_slice(&sample.to_le_bytes()),
+                }
+            }
+        }
+        self.inner.write_all(&bytes)?;
+        self.frames += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Moves every sample the APU has finished resampling into the file, returning the count
+    pub fn write_from_apu(&mut self, apu: &mut Apu) -> io::Result<usize> {
+        let mut buffer = [0.0f32; 512];
+        let mut total = 0;
+        loop {
+            let count = apu.read_samples_f32(&mut buffer);
+            if count == 0 {
+                return Ok(total);
+            }
+            self.write_samples(&buffer[..count])?;
+            total += count;
+        }
+    }
+
+    /// Number of sample frames written so far
+    pub fn frames(&self) -> u32 {
+        self.frames
+    }
+
+    /// Patches the header sizes and hands back the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        let end = self.inner.stream_position()?;
+        // Every supported sample format is an even number of bytes, so no pad byte is needed
+        let data_size = (end - self.data_size_position - 4) as u32;
+        let riff_start = self.riff_size_position - 4;
+        let riff_size = (end - riff_start - 8) as u32;
+
+        self.inner.seek(SeekFrom::Start(self.riff_size_position))?;
+        self.inner.write_all(&riff_size.to_le_bytes())?;
+        if let Some(position) = self.fact_position {
+            self.inner.seek(SeekFrom::Start(position))?;
+            self.inner.write_all(&self.frames.to_le_bytes())?;
+        }
+        self.inner.seek(SeekFrom::Start(self.data_size_position))?;
+        self.inner.write_all(&data_size.to_le_bytes())?;
+        self.inner.seek(SeekFrom::Start(end))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{WavChannels, WavSampleFormat, WavWriter};
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn finish_patches_pcm_header_sizes() {
+        let mut writer = WavWriter::new(
+            Cursor::new(Vec::new()),
+            44100,
+            WavChannels::Stereo,
+            WavSampleFormat::Pcm16,
+        )
+        .unwrap();
+        writer.write_samples(&[0.0, 1.0, -1.0, 2.0, 0.5]).unwrap();
+        assert_eq!(writer.frames(), 5);
+        let bytes = writer.finish().unwrap().into_inner();
+
+        // 5 frames of 2 channels at 2 bytes each after a 44 byte header
+        assert_eq!(bytes.len(), 44 + 20);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 20);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(
+            &bytes[16..36],
+            &[
+                16, 0, 0, 0, // fmt size
+                1, 0, // PCM
+                2, 0, // channels
+                0x44, 0xAC, 0, 0, // 44100 Hz
+                0x10, 0xB1, 2, 0, // 176400 bytes per second
+                4, 0, // block align
+                16, 0, // bits per sample
+            ]
+        );
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 20);
+        // Samples are duplicated into both channels and clipped to the range
+        assert_eq!(&bytes[48..52], &[0xFF, 0x7F, 0xFF, 0x7F]);
+        assert_eq!(&bytes[56..60], &[0xFF, 0x7F, 0xFF, 0x7F]);
+    }
+
+    #[test]
+    fn finish_patches_float_frame_count() {
+        let mut writer = WavWriter::new(
+            Cursor::new(Vec::new()),
+            48000,
+            WavChannels::Mono,
+            WavSampleFormat::Float32,
+        )
+        .unwrap();
+        writer.write_samples(&[0.25; 3]).unwrap();
+        let bytes = writer.finish().unwrap().into_inner();
+
+        // 18 byte fmt chunk and a 12 byte fact chunk make the header 58 bytes
+        assert_eq!(bytes.len(), 58 + 12);
+        assert_eq!(u32_at(&bytes, 4), 50 + 12);
+        assert_eq!(u32_at(&bytes, 16), 18);
+        assert_eq!(&bytes[20..22], &[3, 0]);
+        assert_eq!(&bytes[38..42], b"fact");
+        assert_eq!(u32_at(&bytes, 46), 3);
+        assert_eq!(&bytes[50..54], b"data");
+        assert_eq!(u32_at(&bytes, 54), 12);
+        assert_eq!(&bytes[58..62], &0.25f32.to_le_bytes());
+    }
+}
@@ -1,9 +1,162 @@
 #![allow(dead_code)]
 #![allow(unused_variables)]
 
-trait Mapper {
+use crate::cartidge::NameTableArrangement;
+use crate::ppu::PpuBusEvent;
+
+pub trait Mapper {
     fn access_prg(&mut self, address: u16, data: &mut u8, rw: bool);
     fn access_chr(&mut self, address: u16, data: &mut u8, rw: bool);
+    /// Called for every dot the ppu uses its bus, for mappers that react to what the ppu fetches
+    /// rather than just serving it
+    fn observe_ppu_bus(&mut self, event: &PpuBusEvent) {}
+    /// Whether the mapper is holding the cpu's IRQ line low
+    fn irq(&self) -> bool {
+        false
+    }
+    /// The arrangement the mapper currently selects, for mappers that control mirroring. The board
+    /// checks it on every nametable access, see `NameTableArrangement::with_mapper`.
+    fn nametable_arrangement(&self) -> Option<NameTableArrangement> {
+        None
+    }
+}
+
+/// Rises of PPU A12 shorter than this after it went low are ignored by the MMC3, about three cpu
+/// cycles
+pub const MMC3_A12_LOW_DOTS: u64 = 9;
+
+/// Detects rises of PPU A12 that follow it being low for a minimum time, the way the MMC3 clocks
+/// its scanline counter. Without the filter the 8 sprite fetches of an 8x16 line or $2006 writes
+/// would each count.
+pub struct A12Filter {
+    min_low_dots: u64,
+    high: bool,
+    /// Dot A12 last went low at
+    fell_at: u64,
+}
+
+impl A12Filter {
+    pub fn new(min_low_dots: u64) -> Self {
+        Self {
+            min_low_dots,
+            high: false,
+            fell_at: 0,
+        }
+    }
+
+    /// Follows A12 through `event` and returns whether it rose after being low long enough
+    pub fn observe(&mut self, event: &PpuBusEvent) -> bool {
+        let a12 = event.a12();
+        let rose = a12 && !self.high && event.dots - self.fell_at >= self.min_low_dots;
+        if !a12 && self.high {
+            self.fell_at = event.dots;
+        }
+        self.high = a12;
+        rose
+    }
+}
+
+impl Default for A12Filter {
+    fn default() -> Self {
+        Self::new(MMC3_A12_LOW_DOTS)
+    }
+}
+
+/// The mapper for an iNES mapper number, `None` for NROM, which the board serves itself, and for
+/// mappers that aren't emulated
+pub fn from_number(number: usize) -> Option<Box<dyn Mapper + Send + Sync>> {
+    match number {
+        4 => Some(Box::new(MapperMMC3::new())),
+        _ => None,
+    }
+}
+
+/// The MMC3's scanline IRQ and mirroring control. Bank switching isn't emulated, the board keeps
+/// serving the first 32KiB of PRG and 8KiB of CHR.
+pub struct MapperMMC3 {
+    a12: A12Filter,
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq: bool,
+    nametable_arrangement: NameTableArrangement,
+}
+
+impl MapperMMC3 {
+    pub fn new() -> Self {
+        Self {
+            a12: A12Filter::default(),
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq: false,
+            nametable_arrangement: NameTableArrangement::VERTICAL,
+        }
+    }
+
+    /// Clocks the scanline counter the way the later MMC3 revisions do: the IRQ is raised every
+    /// time the counter is 0 after a clock, including when the latch is 0
+    fn clock_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq = true;
+        }
+    }
+}
+
+impl Default for MapperMMC3 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mapper for MapperMMC3 {
+    fn access_prg(&mut self, address: u16, data: &mut u8, rw: bool) {
+        if rw || address < 0x8000 {
+            return;
+        }
+        // Registers are selected by A13-A14 and A0
+        match (address & 0xE001, *data) {
+            (0xA000, data) => {
+                self.nametable_arrangement = if data & 0x01 == 0 {
+                    NameTableArrangement::VERTICAL
+                } else {
+                    NameTableArrangement::HORIZONTAL
+                };
+            }
+            (0xC000, data) => self.irq_latch = data,
+            (0xC001, _) => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            (0xE000, _) => {
+                self.irq_enabled = false;
+                self.irq = false;
+            }
+            (0xE001, _) => self.irq_enabled = true,
+            // Bank select and data, PRG RAM protect
+            _ => {}
+        }
+    }
+    fn access_chr(&mut self, address: u16, data: &mut u8, rw: bool) {}
+    fn observe_ppu_bus(&mut self, event: &PpuBusEvent) {
+        if self.a12.observe(event) {
+            self.clock_counter();
+        }
+    }
+    fn irq(&self) -> bool {
+        self.irq
+    }
+    fn nametable_arrangement(&self) -> Option<NameTableArrangement> {
+        Some(self.nametable_arrangement)
+    }
 }
 
 pub struct MapperNROM {
@@ -19,3 +172,123 @@ impl Mapper for MapperNROM {
         
     }
 } 
+
+#[cfg(test)]
+mod tests {
+    use super::{A12Filter, Mapper, MapperMMC3};
+    use crate::cartidge::NameTableArrangement;
+    use crate::ppu::{Ppu, PpuBusAccess, PpuBusEvent, PpuPinout};
+
+    fn write_register(ppu: &mut Ppu, pins: &mut PpuPinout, register: u8, data: u8) {
+        pins.cpu_control = true;
+        pins.cpu_rw = false;
+        pins.cpu_addr = register;
+        pins.cpu_data = data;
+        ppu.clock(pins);
+        pins.cpu_control = false;
+    }
+
+    #[test]
+    fn a12_filter_counts_one_rise_per_rendered_line() {
+        let mut ppu = Ppu::new();
+        let mut pins = PpuPinout {
+            nmi: true,
+            cpu_control: false,
+            cpu_rw: true,
+            cpu_addr: 0,
+            cpu_data: 0,
+            ppu_address_data_low: 0,
+            ppu_address_high: 0,
+            ppu_r: false,
+            ppu_w: false,
+            ppu_sync: false,
+            ppu_ale: false,
+            finished_frame: false,
+            extra_sprite_address: None,
+            extra_sprite_data: [0; 2],
+        };
+        // Background from $0000 and 8x8 sprites from $1000, the usual MMC3 arrangement
+        write_register(&mut ppu, &mut pins, 0, 0x08);
+        write_register(&mut ppu, &mut pins, 1, 0x18);
+        while ppu.scanline() != 0 || ppu.dot() != 0 {
+            ppu.clock(&mut pins);
+        }
+
+        let mut filter = A12Filter::default();
+        let mut rises = Vec::new();
+        loop {
+            ppu.clock(&mut pins);
+            if let Some(event) = ppu.bus_event(&pins) {
+                if filter.observe(&event) {
+                    rises.push(event.scanline);
+                }
+            }
+            if ppu.scanline() == 0 && ppu.dot() == 0 {
+                break;
+            }
+        }
+        // The pre-render line fetches sprites like the visible lines do
+        let expected: Vec<usize> = (0..240).chain([261]).collect();
+        assert_eq!(rises, expected);
+    }
+
+    fn write_mmc3(mapper: &mut MapperMMC3, address: u16, data: u8) {
+        let mut data = data;
+        mapper.access_prg(address, &mut data, false);
+    }
+
+    /// Fetches from $0000 then $1000 at `dots`, a rise of A12 the filter lets through
+    fn rise_a12(mapper: &mut MapperMMC3, dots: u64) {
+        for (address, dots) in [(0x0000, dots), (0x1000, dots + 16)] {
+            mapper.observe_ppu_bus(&PpuBusEvent {
+                address,
+                access: PpuBusAccess::Read,
+                scanline: 0,
+                dot: 0,
+                dots,
+                rendering: true,
+            });
+        }
+    }
+
+    #[test]
+    fn mmc3_irq_follows_the_latch() {
+        let mut mapper = MapperMMC3::new();
+        write_mmc3(&mut mapper, 0xC000, 3);
+        write_mmc3(&mut mapper, 0xC001, 0);
+        write_mmc3(&mut mapper, 0xE001, 0);
+        let mut irqs = Vec::new();
+        for rise in 0..9 {
+            rise_a12(&mut mapper, 100 * (rise + 1));
+            if mapper.irq() {
+                irqs.push(rise);
+                // Acknowledge and enable again
+                write_mmc3(&mut mapper, 0xE000, 0);
+                write_mmc3(&mut mapper, 0xE001, 0);
+            }
+        }
+        // The first rise reloads the counter, the IRQ fires when it's counted down to 0
+        assert_eq!(irqs, [3, 7]);
+
+        write_mmc3(&mut mapper, 0xE000, 0);
+        for rise in 9..20 {
+            rise_a12(&mut mapper, 100 * (rise + 1));
+        }
+        assert!(!mapper.irq());
+    }
+
+    #[test]
+    fn mmc3_switches_mirroring() {
+        let mut mapper = MapperMMC3::new();
+        let vertical = Some(NameTableArrangement::VERTICAL);
+        let horizontal = Some(NameTableArrangement::HORIZONTAL);
+        assert_eq!(mapper.nametable_arrangement(), vertical);
+        write_mmc3(&mut mapper, 0xA000, 1);
+        assert_eq!(mapper.nametable_arrangement(), horizontal);
+        // $A001 is PRG RAM protect
+        write_mmc3(&mut mapper, 0xA001, 0);
+        assert_eq!(mapper.nametable_arrangement(), horizontal);
+        write_mmc3(&mut mapper, 0xBFFE, 0);
+        assert_eq!(mapper.nametable_arrangement(), vertical);
+    }
+}
@@ -2,6 +2,8 @@
 #![allow(unused_variables)]
 
 use std::ops::Range;
+
+use crate::{ppu::PpuVariant, region::Region};
 pub mod mapper;
 
 pub struct CartridgeData {
@@ -16,6 +18,10 @@ pub struct CartridgeData {
     pub battery: bool,
     pub title: Option<String>,
     pub mapper: usize,
+    /// Only NES 2.0 headers reliably declare a region, every other format reports RP2C02
+    pub timing_mode: TimingMode,
+    /// The RGB ppus of PlayChoice-10 and Vs. System dumps, RP2C02 for everything else
+    pub ppu_variant: PpuVariant,
 }
 
 pub enum CartidgeFileFormat {
@@ -26,6 +32,16 @@ pub enum CartidgeFileFormat {
 }
 
 impl CartridgeData {
+    /// The arrangement the board should start with. Outside of a few mappers that reuse the bit,
+    /// the alternative layout flag means the cartridge has four-screen vram.
+    pub fn board_nametable_arrangement(&self) -> NameTableArrangement {
+        if self.nametable_alternate {
+            NameTableArrangement::FourScreen
+        } else {
+            self.nametable_arrangement
+        }
+    }
+
     pub fn decode(program: &[u8]) -> Self {
         let data_size = program.len();
         let byte0 = program[0];
@@ -100,6 +116,8 @@ impl CartridgeData {
                 let prg_ram_size = if battery { 8192 } else { 0 };
                 let title = None;
                 let mapper = ines_archaic_data.mapper;
+                let timing_mode = TimingMode::RP2C02;
+                let ppu_variant = PpuVariant::Rp2c02;
                 Self {
                     trainer_range,
                     prg_rom_range,
@@ -112,6 +130,8 @@ impl CartridgeData {
                     battery,
                     title,
                     mapper,
+                    timing_mode,
+                    ppu_variant,
                 }
             }
             CartidgeFileFormat::INES => {
@@ -157,6 +177,14 @@ impl CartridgeData {
                 let battery = ines_data.base.non_volatile_data;
                 let title = None;
                 let mapper = ines_data.base.mapper;
+                let timing_mode = TimingMode::RP2C02;
+                // iNES only says a game is for the PlayChoice-10, whose ppu is always a 2C03. Vs.
+                // System boards used several ppus and iNES doesn't say which.
+                let ppu_variant = if ines_data.playchoice {
+                    PpuVariant::Rp2c03
+                } else {
+                    PpuVariant::Rp2c02
+                };
 
                 // If using INES prg-ram specification, insert max(1, N) 8KiB banks of prg-ram
                 // If battery-backed, insert 8KiB ram; otherwise open-bus
@@ -173,6 +201,8 @@ impl CartridgeData {
                     battery,
                     title,
                     mapper,
+                    timing_mode,
+                    ppu_variant,
                 }
             }
             CartidgeFileFormat::NES2 => {
@@ -222,6 +252,14 @@ impl CartridgeData {
                 let title = None;
 
                 let mapper = ines2_data.base.mapper;
+                let timing_mode = ines2_data.timing_mode;
+                let ppu_variant = match (&ines2_data.console_type, &ines2_data.extra_hardware_info) {
+                    (_, Some(ExtraHardwareInfo::VSSystemType { ppu_type, .. })) => {
+                        PpuVariant::from_vs_ppu_type(*ppu_type).unwrap_or(PpuVariant::Rp2c03)
+                    }
+                    (ConsoleType::NPlaychoice10, _) => PpuVariant::Rp2c03,
+                    _ => PpuVariant::Rp2c02,
+                };
                 Self {
                     trainer_range,
                     prg_rom_range,
@@ -234,6 +272,8 @@ impl CartridgeData {
                     battery,
                     title,
                     mapper,
+                    timing_mode,
+                    ppu_variant,
                 }
             }
             _ => {
@@ -285,6 +325,8 @@ impl CartridgeData {
                     100 => unimplemented!("FDS is not yet implemented"),
                     _ => unimplemented!("Not a known TNES mapper"),
                 };
+                let timing_mode = TimingMode::RP2C02;
+                let ppu_variant = PpuVariant::Rp2c02;
                 Self {
                     trainer_range,
                     prg_rom_range,
@@ -297,18 +339,71 @@ impl CartridgeData {
                     battery,
                     title,
                     mapper,
+                    timing_mode,
+                    ppu_variant,
                 }
             }
         }
     }
 }
 
+/// How the four nametables at $2000-$2FFF map onto the 2KiB of CIRAM, named after the mirroring
+/// they produce
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum NameTableArrangement {
+    /// $2000 = $2400 and $2800 = $2C00, CIRAM A10 follows PPU A11
     HORIZONTAL,
+    /// $2000 = $2800 and $2400 = $2C00, CIRAM A10 follows PPU A10
     VERTICAL,
+    /// Every nametable is the first KiB of CIRAM
+    SingleScreenA,
+    /// Every nametable is the second KiB of CIRAM
+    SingleScreenB,
+    /// 2KiB of extra ram on the cartridge gives each nametable its own memory
+    FourScreen,
+    /// The mapper switches between the other arrangements, see `Mapper::nametable_arrangement`.
+    /// Until it picks one, this folds like `SingleScreenA`.
     MapperControlled,
 }
 
+impl NameTableArrangement {
+    /// Folds a ppu address in $2000-$3EFF to an offset into nametable memory: CIRAM, followed
+    /// by the cartridge's extra 2KiB for `FourScreen`. $3000-$3EFF mirrors $2000-$2EFF.
+    pub fn fold(&self, address: u16) -> usize {
+        let address = usize::from(address) & 0x0FFF;
+        let offset = address & 0x03FF;
+        match self {
+            NameTableArrangement::HORIZONTAL => ((address >> 1) & 0x0400) | offset,
+            NameTableArrangement::VERTICAL => address & 0x07FF,
+            NameTableArrangement::SingleScreenA | NameTableArrangement::MapperControlled => offset,
+            NameTableArrangement::SingleScreenB => 0x0400 | offset,
+            NameTableArrangement::FourScreen => address,
+        }
+    }
+
+    /// The arrangement in effect when the mapper selects `mapper`. Four-screen vram is wired by
+    /// the board, so a four-screen board ignores the mapper and a mapper can't pick four-screen.
+    pub fn with_mapper(self, mapper: Option<NameTableArrangement>) -> Self {
+        match mapper {
+            Some(arrangement)
+                if self != NameTableArrangement::FourScreen
+                    && arrangement != NameTableArrangement::FourScreen =>
+            {
+                arrangement
+            }
+            _ => self,
+        }
+    }
+
+    /// Bytes of nametable memory the arrangement needs
+    pub fn memory_size(&self) -> usize {
+        match self {
+            NameTableArrangement::FourScreen => 0x1000,
+            _ => 0x0800,
+        }
+    }
+}
+
 enum TVSystem {
     Ntsc,
     Pal,
@@ -322,13 +417,25 @@ enum ConsoleType {
     Extended,
 }
 
-enum TimingMode {
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TimingMode {
     RP2C02, // NTSC NES
     RP2C07, // Liscenced PAL NES
     MulReg,
     UA6538, // Dendy
 }
 
+impl TimingMode {
+    /// The region to run in, multi-region games run as NTSC
+    pub fn region(&self) -> Region {
+        match self {
+            TimingMode::RP2C02 | TimingMode::MulReg => Region::Ntsc,
+            TimingMode::RP2C07 => Region::Pal,
+            TimingMode::UA6538 => Region::Dendy,
+        }
+    }
+}
+
 enum ExtraHardwareInfo {
     VSSystemType { ppu_type: u8, hardware_type: u8 },
     ExtendedConsole { extended_console_type: u8 },
@@ -641,3 +748,84 @@ impl TNESFormat {
         8192 * self.chr_rom_size
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::NameTableArrangement;
+
+    /// Offsets the first byte of $2000, $2400, $2800 and $2C00 fold to
+    fn fold_nametables(arrangement: NameTableArrangement) -> [usize; 4] {
+        [0x2000, 0x2400, 0x2800, 0x2C00].map(|address| arrangement.fold(address))
+    }
+
+    #[test]
+    fn horizontal_mirrors_left_and_right() {
+        let arrangement = NameTableArrangement::HORIZONTAL;
+        assert_eq!(fold_nametables(arrangement), [0x000, 0x000, 0x400, 0x400]);
+        assert_eq!(arrangement.fold(0x27FF), 0x3FF);
+        assert_eq!(arrangement.fold(0x2BC0), 0x7C0);
+    }
+
+    #[test]
+    fn vertical_mirrors_top_and_bottom() {
+        let arrangement = NameTableArrangement::VERTICAL;
+        assert_eq!(fold_nametables(arrangement), [0x000, 0x400, 0x000, 0x400]);
+        assert_eq!(arrangement.fold(0x2FFF), 0x7FF);
+    }
+
+    #[test]
+    fn single_screen_uses_one_nametable() {
+        assert_eq!(fold_nametables(NameTableArrangement::SingleScreenA), [0x000; 4]);
+        assert_eq!(fold_nametables(NameTableArrangement::SingleScreenB), [0x400; 4]);
+        assert_eq!(NameTableArrangement::SingleScreenB.fold(0x2FC5), 0x7C5);
+    }
+
+    #[test]
+    fn four_screen_keeps_every_nametable() {
+        let arrangement = NameTableArrangement::FourScreen;
+        assert_eq!(fold_nametables(arrangement), [0x000, 0x400, 0x800, 0xC00]);
+        assert_eq!(arrangement.fold(0x2FFF), 0xFFF);
+        assert_eq!(arrangement.memory_size(), 0x1000);
+    }
+
+    #[test]
+    fn mapper_controlled_defaults_to_single_screen() {
+        assert_eq!(fold_nametables(NameTableArrangement::MapperControlled), [0x000; 4]);
+    }
+
+    #[test]
+    fn mapper_switches_the_folding() {
+        let board = NameTableArrangement::MapperControlled;
+        assert_eq!(fold_nametables(board.with_mapper(None)), [0x000; 4]);
+        let vertical = board.with_mapper(Some(NameTableArrangement::VERTICAL));
+        assert_eq!(fold_nametables(vertical), [0x000, 0x400, 0x000, 0x400]);
+        let horizontal = board.with_mapper(Some(NameTableArrangement::HORIZONTAL));
+        assert_eq!(fold_nametables(horizontal), [0x000, 0x000, 0x400, 0x400]);
+        let single = board.with_mapper(Some(NameTableArrangement::SingleScreenB));
+        assert_eq!(fold_nametables(single), [0x400; 4]);
+
+        // A header's arrangement is only the starting point for mappers that switch
+        let header = NameTableArrangement::HORIZONTAL;
+        let vertical = NameTableArrangement::VERTICAL;
+        assert_eq!(header.with_mapper(Some(vertical)), vertical);
+        // Four-screen vram is on the board
+        let four_screen = NameTableArrangement::FourScreen;
+        assert_eq!(four_screen.with_mapper(Some(vertical)), four_screen);
+        assert_eq!(header.with_mapper(Some(four_screen)), header);
+    }
+
+    #[test]
+    fn upper_mirror_matches_nametables() {
+        for arrangement in [
+            NameTableArrangement::HORIZONTAL,
+            NameTableArrangement::VERTICAL,
+            NameTableArrangement::SingleScreenA,
+            NameTableArrangement::SingleScreenB,
+            NameTableArrangement::FourScreen,
+        ] {
+            for address in 0x3000..0x3F00 {
+                assert_eq!(arrangement.fold(address), arrangement.fold(address - 0x1000));
+            }
+        }
+    }
+}
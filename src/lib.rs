@@ -2,3 +2,6 @@ pub mod apu;
 pub mod cpu;
 pub mod ppu;
 pub mod cartidge;
+pub mod nsf;
+pub mod video;
+pub mod region;
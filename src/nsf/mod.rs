@@ -0,0 +1,479 @@
+use std::{fmt, time::Duration};
+
+use bitflags::bitflags;
+
+use crate::{apu::NTSC_CPU_CLOCK_RATE, region::Region};
+
+pub mod player;
+
+pub use player::NsfPlayer;
+
+const NSF_MAGIC: &[u8; 5] = b"NESM\x1A";
+const NSFE_MAGIC: &[u8; 4] = b"NSFE";
+const NSF_HEADER_SIZE: usize = 0x80;
+
+/// Play rates used when a file leaves them as zero, in microseconds
+const DEFAULT_NTSC_SPEED: u16 = 16639;
+const DEFAULT_PAL_SPEED: u16 = 19997;
+
+bitflags! {
+    /// Expansion audio declared by the file. The flags are exposed as metadata; only the
+    /// 2A03's own channels are synthesized.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct NsfExpansion: u8 {
+        const VRC6 = 0b00000001;
+        const VRC7 = 0b00000010;
+        const FDS = 0b00000100;
+        const MMC5 = 0b00001000;
+        const N163 = 0b00010000;
+        const SUNSOFT_5B = 0b00100000;
+        const VT02 = 0b01000000;
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NsfRegion {
+    Ntsc,
+    Pal,
+}
+
+impl NsfRegion {
+    pub fn cpu_clock_rate(self) -> f64 {
+        match self {
+            NsfRegion::Ntsc => NTSC_CPU_CLOCK_RATE,
+            NsfRegion::Pal => Region::Pal.cpu_clock_rate(),
+        }
+    }
+}
+
+/// Which regions the tune was written for
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NsfRegionSupport {
+    Ntsc,
+    Pal,
+    Dual,
+}
+
+#[derive(Debug)]
+pub enum NsfError {
+    /// Neither the NSF nor the NSFe signature was found
+    UnknownFormat,
+    /// The file ended inside the header or a chunk
+    Truncated,
+    /// A chunk every NSFe must contain is missing
+    MissingChunk(&'static str),
+    /// An NSFe chunk marked as required (upper-case first letter) that this player doesn't know
+    UnsupportedChunk(String),
+}
+
+impl fmt::Display for NsfError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NsfError::UnknownFormat => write!(f, "not an NSF or NSFe file"),
+            NsfError::Truncated => write!(f, "file is truncated"),
+            NsfError::MissingChunk(id) => write!(f, "missing required NSFe chunk {id}"),
+            NsfError::UnsupportedChunk(id) => write!(f, "unsupported required NSFe chunk {id}"),
+        }
+    }
+}
+
+impl std::error::Error for NsfError {}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct NsfTrack {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub duration: Option<Duration>,
+    pub fade: Option<Duration>,
+}
+
+/// A decoded NSF or NSFe file
+#[derive(Clone, Debug)]
+pub struct Nsf {
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    /// Initial values of $5FF8-$5FFF; all zero when the tune isn't bankswitched
+    pub bank_init: [u8; 8],
+    /// Microseconds between calls to PLAY
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    pub region_support: NsfRegionSupport,
+    pub expansion: NsfExpansion,
+    /// 0-based song number INIT starts with
+    pub starting_track: u8,
+
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    pub ripper: Option<String>,
+    pub tracks: Vec<NsfTrack>,
+    /// Order of songs to play, from an NSFe playlist chunk
+    pub playlist: Option<Vec<u8>>,
+
+    pub data: Vec<u8>,
+}
+
+impl Nsf {
+    pub fn decode(file: &[u8]) -> Result<Self, NsfError> {
+        if file.starts_with(NSF_MAGIC) {
+            Self::decode_nsf(file)
+        } else if file.starts_with(NSFE_MAGIC) {
+            Self::decode_nsfe(file)
+        } else {
+            Err(NsfError::UnknownFormat)
+        }
+    }
+
+    fn decode_nsf(file: &[u8]) -> Result<Self, NsfError> {
+        if file.len() < NSF_HEADER_SIZE {
+            return Err(NsfError::Truncated);
+        }
+        let word = |offset: usize| u16::from_le_bytes([file[offset], file[offset + 1]]);
+
+        let total_songs = file[0x06];
+        let starting_track = file[0x07].saturating_sub(1);
+        let mut bank_init = [0; 8];
+        bank_init.copy_from_slice(&file[0x70..0x78]);
+        let region_support = match file[0x7A] & 0b11 {
+            0 => NsfRegionSupport::Ntsc,
+            1 => NsfRegionSupport::Pal,
+            _ => NsfRegionSupport::Dual,
+        };
+
+        // NSF2 can declare the program length so metadata may follow it; otherwise the program
+        // runs to the end of the file
+        let data_length =
+            usize::from(file[0x7D]) | usize::from(file[0x7E]) << 8 | usize::from(file[0x7F]) << 16;
+        let data_end = if data_length > 0 {
+            (NSF_HEADER_SIZE + data_length).min(file.len())
+        } else {
+            file.len()
+        };
+
+        Ok(Self {
+            load_address: word(0x08),
+            init_address: word(0x0A),
+            play_address: word(0x0C),
+            bank_init,
+            ntsc_speed: word(0x6E),
+            pal_speed: word(0x78),
+            region_support,
+            expansion: NsfExpansion::from_bits_truncate(file[0x7B]),
+            starting_track,
+            title: fixed_string(&file[0x0E..0x2E]),
+            artist: fixed_string(&file[0x2E..0x4E]),
+            copyright: fixed_string(&file[0x4E..0x6E]),
+            ripper: None,
+            tracks: vec![NsfTrack::default(); usize::from(total_songs)],
+            playlist: None,
+            data: file[NSF_HEADER_SIZE..data_end].to_vec(),
+        })
+    }
+
+    fn decode_nsfe(file: &[u8]) -> Result<Self, NsfError> {
+        let mut info = None;
+        let mut data = None;
+        let mut bank_init = [0; 8];
+        let mut rate = None;
+        let mut strings: [Option<String>; 4] = Default::default();
+        let mut labels = Vec::new();
+        let mut authors = Vec::new();
+        let mut times = Vec::new();
+        let mut fades = Vec::new();
+        let mut playlist = None;
+
+        let mut offset = NSFE_MAGIC.len();
+        loop {
+            let header = file.get(offset..offset + 8).ok_or(NsfError::Truncated)?;
+            let length = u32::from_le_bytes([header[0], header[1], header[2], header[3]]) as usize;
+            let id = &header[4..8];
+            offset += 8;
+            let chunk = file
+                .get(offset..offset + length)
+                .ok_or(NsfError::Truncated)?;
+            offset += length;
+            match id {
+                b"INFO" => info = Some(chunk),
+                b"DATA" => data = Some(chunk),
+                b"NEND" => break,
+                b"BANK" => {
+                    let count = chunk.len().min(8);
+                    bank_init[..count].copy_from_slice(&chunk[..count]);
+                }
+                b"RATE" => rate = Some(chunk),
+                b"auth" => {
+                    for (slot, text) in strings.iter_mut().zip(chunk.split(|&b| b == 0)) {
+                        *slot = Some(String::from_utf8_lossy(text).into_owned());
+                    }
+                }
+                b"tlbl" => labels = string_list(chunk),
+                b"taut" => authors = string_list(chunk),
+                b"time" => times = millisecond_list(chunk),
+                b"fade" => fades = millisecond_list(chunk),
+                b"plst" => playlist = Some(chunk.to_vec()),
+                // Lower-case chunks are optional and may be skipped
+                _ if id[0].is_ascii_lowercase() => {}
+                _ => {
+                    return Err(NsfError::UnsupportedChunk(
+                        String::from_utf8_lossy(id).into_owned(),
+                    ))
+                }
+            }
+        }
+
+        let info = info.ok_or(NsfError::MissingChunk("INFO"))?;
+        let data = data.ok_or(NsfError::MissingChunk("DATA"))?;
+        if info.len() < 8 {
+            return Err(NsfError::Truncated);
+        }
+        let word =
+            |bytes: &[u8], offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let total_songs = info.get(8).copied().unwrap_or(1);
+        let starting_track = info.get(9).copied().unwrap_or(0);
+        let region_support = match info[6] & 0b11 {
+            0 => NsfRegionSupport::Ntsc,
+            1 => NsfRegionSupport::Pal,
+            _ => NsfRegionSupport::Dual,
+        };
+        let (ntsc_speed, pal_speed) = match rate {
+            Some(rate) if rate.len() >= 4 => (word(rate, 0), word(rate, 2)),
+            Some(rate) if rate.len() >= 2 => (word(rate, 0), 0),
+            _ => (0, 0),
+        };
+
+        let tracks = (0..usize::from(total_songs))
+            .map(|track| NsfTrack {
+                name: labels.get(track).cloned(),
+                author: authors.get(track).cloned(),
+                duration: times.get(track).copied().flatten(),
+                fade: fades.get(track).copied().flatten(),
+            })
+            .collect();
+        let [title, artist, copyright, ripper] = strings;
+
+        Ok(Self {
+            load_address: word(info, 0),
+            init_address: word(info, 2),
+            play_address: word(info, 4),
+            bank_init,
+            ntsc_speed,
+            pal_speed,
+            region_support,
+            expansion: NsfExpansion::from_bits_truncate(info[7]),
+            starting_track,
+            title: title.unwrap_or_default(),
+            artist: artist.unwrap_or_default(),
+            copyright: copyright.unwrap_or_default(),
+            ripper,
+            tracks,
+            playlist,
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn is_bankswitched(&self) -> bool {
+        self.bank_init.iter().any(|&bank| bank != 0)
+    }
+
+    /// Microseconds between PLAY calls for `region`, falling back to the standard frame rate
+    pub fn play_speed(&self, region: NsfRegion) -> u16 {
+        match region {
+            NsfRegion::Ntsc if self.ntsc_speed != 0 => self.ntsc_speed,
+            NsfRegion::Ntsc => DEFAULT_NTSC_SPEED,
+            NsfRegion::Pal if self.pal_speed != 0 => self.pal_speed,
+            NsfRegion::Pal => DEFAULT_PAL_SPEED,
+        }
+    }
+
+    /// The region to play in when the user hasn't chosen one
+    pub fn default_region(&self) -> NsfRegion {
+        match self.region_support {
+            NsfRegionSupport::Pal => NsfRegion::Pal,
+            _ => NsfRegion::Ntsc,
+        }
+    }
+}
+
+fn fixed_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+fn string_list(chunk: &[u8]) -> Vec<String> {
+    chunk
+        .split(|&b| b == 0)
+        .map(|text| String::from_utf8_lossy(text).into_owned())
+        .collect()
+}
+
+/// Signed millisecond values where a negative entry means "unknown"
+fn millisecond_list(chunk: &[u8]) -> Vec<Option<Duration>> {
+    chunk
+        .chunks_exact(4)
+        .map(|bytes| {
+            let ms = i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            u64::try_from(ms).ok().map(Duration::from_millis)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Nsf, NsfError, NsfExpansion, NsfRegion, NsfRegionSupport};
+
+    fn nsf_header() -> Vec<u8> {
+        let mut file = vec![0; 0x80];
+        file[..5].copy_from_slice(b"NESM\x1A");
+        file[0x05] = 2;
+        file[0x06] = 3;
+        file[0x07] = 2;
+        file[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x03, 0x80, 0x06, 0x80]);
+        file[0x0E..0x13].copy_from_slice(b"Title");
+        file[0x2E..0x34].copy_from_slice(b"Artist");
+        file[0x4E..0x52].copy_from_slice(b"2024");
+        // 16666us NTSC and 20000us PAL
+        file[0x6E..0x70].copy_from_slice(&[0x1A, 0x41]);
+        file[0x70..0x78].copy_from_slice(&[0, 1, 2, 3, 4, 5, 6, 7]);
+        file[0x78..0x7A].copy_from_slice(&[0x20, 0x4E]);
+        file[0x7A] = 0b10;
+        file[0x7B] = 0b101;
+        file
+    }
+
+    #[test]
+    fn decodes_nsf_header() {
+        let mut file = nsf_header();
+        file.extend_from_slice(&[0xEA; 16]);
+        let nsf = Nsf::decode(&file).unwrap();
+        assert_eq!(nsf.load_address, 0x8000);
+        assert_eq!(nsf.init_address, 0x8003);
+        assert_eq!(nsf.play_address, 0x8006);
+        assert_eq!(nsf.bank_init, [0, 1, 2, 3, 4, 5, 6, 7]);
+        assert!(nsf.is_bankswitched());
+        assert_eq!(nsf.play_speed(NsfRegion::Ntsc), 16666);
+        assert_eq!(nsf.play_speed(NsfRegion::Pal), 20000);
+        assert_eq!(nsf.region_support, NsfRegionSupport::Dual);
+        assert_eq!(nsf.expansion, NsfExpansion::VRC6 | NsfExpansion::FDS);
+        assert_eq!(nsf.track_count(), 3);
+        assert_eq!(nsf.starting_track, 1);
+        assert_eq!(
+            [&nsf.title[..], &nsf.artist[..], &nsf.copyright[..]],
+            ["Title", "Artist", "2024"]
+        );
+        assert_eq!(nsf.data, [0xEA; 16]);
+
+        // Speeds of zero fall back to the standard frame rates
+        file[0x6E..0x70].fill(0);
+        file[0x78..0x7A].fill(0);
+        let nsf = Nsf::decode(&file).unwrap();
+        assert_eq!(nsf.play_speed(NsfRegion::Ntsc), 16639);
+        assert_eq!(nsf.play_speed(NsfRegion::Pal), 19997);
+    }
+
+    #[test]
+    fn nsf2_data_length_leaves_out_metadata() {
+        let mut file = nsf_header();
+        file[0x7D..0x80].copy_from_slice(&[4, 0, 0]);
+        file.extend_from_slice(&[1, 2, 3, 4]);
+        file.extend_from_slice(b"\x00\x00\x00\x00NEND");
+        assert_eq!(Nsf::decode(&file).unwrap().data, [1, 2, 3, 4]);
+        assert!(matches!(
+            Nsf::decode(&file[..0x7F]),
+            Err(NsfError::Truncated)
+        ));
+    }
+
+    fn chunk(id: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut chunk = (data.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    fn nsfe(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut file = b"NSFE".to_vec();
+        chunks
+            .iter()
+            .for_each(|chunk| file.extend_from_slice(chunk));
+        file
+    }
+
+    /// Load $8000, INIT $8003, PLAY $8006, PAL, FDS, 2 songs starting with the second
+    fn info() -> Vec<u8> {
+        chunk(
+            b"INFO",
+            &[0x00, 0x80, 0x03, 0x80, 0x06, 0x80, 1, 0b100, 2, 1],
+        )
+    }
+
+    #[test]
+    fn decodes_nsfe_chunks() {
+        let mut time = 90_000i32.to_le_bytes().to_vec();
+        time.extend_from_slice(&(-1i32).to_le_bytes());
+        let file = nsfe(&[
+            info(),
+            chunk(b"DATA", &[0x60; 8]),
+            chunk(b"BANK", &[0, 1]),
+            chunk(b"RATE", &[0x1A, 0x41]),
+            chunk(b"auth", b"Title\0Artist\0Copyright\0Ripper"),
+            chunk(b"tlbl", b"First\0Second"),
+            chunk(b"time", &time),
+            chunk(b"plst", &[1, 0, 1]),
+            // Unknown optional chunks are skipped
+            chunk(b"xtra", &[1, 2, 3]),
+            chunk(b"NEND", &[]),
+        ]);
+        let nsf = Nsf::decode(&file).unwrap();
+        assert_eq!(nsf.init_address, 0x8003);
+        assert_eq!(nsf.play_address, 0x8006);
+        assert_eq!(nsf.region_support, NsfRegionSupport::Pal);
+        assert_eq!(nsf.expansion, NsfExpansion::FDS);
+        assert_eq!(nsf.starting_track, 1);
+        assert_eq!(nsf.data, [0x60; 8]);
+        assert_eq!(nsf.bank_init, [0, 1, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(nsf.play_speed(NsfRegion::Ntsc), 16666);
+        assert_eq!(nsf.title, "Title");
+        assert_eq!(nsf.artist, "Artist");
+        assert_eq!(nsf.copyright, "Copyright");
+        assert_eq!(nsf.ripper.as_deref(), Some("Ripper"));
+        assert_eq!(nsf.track_count(), 2);
+        assert_eq!(nsf.tracks[0].name.as_deref(), Some("First"));
+        assert_eq!(nsf.tracks[1].name.as_deref(), Some("Second"));
+        assert_eq!(nsf.tracks[0].duration, Some(Duration::from_secs(90)));
+        assert_eq!(nsf.tracks[1].duration, None);
+        assert_eq!(nsf.playlist, Some(vec![1, 0, 1]));
+    }
+
+    #[test]
+    fn rejects_broken_nsfe() {
+        let data = chunk(b"DATA", &[0x60]);
+        let end = chunk(b"NEND", &[]);
+        let decode = |chunks: &[Vec<u8>]| Nsf::decode(&nsfe(chunks));
+
+        assert!(matches!(
+            decode(&[data.clone(), end.clone()]),
+            Err(NsfError::MissingChunk("INFO"))
+        ));
+        assert!(matches!(
+            decode(&[info(), end.clone()]),
+            Err(NsfError::MissingChunk("DATA"))
+        ));
+        match decode(&[info(), chunk(b"VRC7", &[0]), data.clone(), end.clone()]) {
+            Err(NsfError::UnsupportedChunk(id)) => assert_eq!(id, "VRC7"),
+            other => panic!("{other:?}"),
+        }
+        // A chunk running past the end of the file, and a file without NEND
+        let mut file = nsfe(&[info(), data.clone(), end]);
+        file[4 + info().len()] = 0xFF;
+        assert!(matches!(Nsf::decode(&file), Err(NsfError::Truncated)));
+        assert!(matches!(decode(&[info(), data]), Err(NsfError::Truncated)));
+        assert!(matches!(Nsf::decode(b"NESN"), Err(NsfError::UnknownFormat)));
+    }
+}
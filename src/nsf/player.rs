@@ -0,0 +1,407 @@
+use std::time::Duration;
+
+use super::{Nsf, NsfRegion, NsfTrack};
+use crate::{
+    apu::{Apu, ApuPinout},
+    cpu::{instructions::Instructions as Op, Cpu, CpuPinout},
+};
+
+/// The driver routine lives in the otherwise unused PPU register mirrors; NSF code never touches
+/// the PPU so nothing else is mapped there
+const DRIVER_ADDRESS: u16 = 0x3F00;
+/// Offsets of the entry points within the driver, see `build_driver`
+const DRIVER_IDLE: u16 = DRIVER_ADDRESS + 32;
+const DRIVER_NMI: u16 = DRIVER_ADDRESS + 35;
+const DRIVER_PLAY_RETURN: u16 = DRIVER_ADDRESS + 38;
+
+const BANK_SIZE: usize = 0x1000;
+
+/// Plays the songs of an NSF by running its code on the regular `Cpu` and `Apu`.
+///
+/// The player builds the memory map an NSF expects: 2KB of ram, 8KB of work ram at $6000, the
+/// program at its load address and, for bankswitched tunes, eight 4KB windows over $8000-$FFFF
+/// selected through $5FF8-$5FFF. A small driver is placed at $3F00 which silences the APU, calls
+/// INIT with the song number in A and the region in X, then spins. The interrupt vectors are
+/// overridden to point at the driver and an NMI is raised at the file's play rate to call PLAY.
+///
+/// Expansion audio writes are ignored, as is the FDS's writable program ram.
+pub struct NsfPlayer {
+    nsf: Nsf,
+
+    cpu: Cpu,
+    cpu_pins: CpuPinout,
+
+    apu: Apu,
+    apu_pins: ApuPinout,
+
+    ram: Vec<u8>,
+    wram: Vec<u8>,
+    /// Program data padded so that bank 0 starts on a 4KB boundary
+    rom: Vec<u8>,
+    banks: [u8; 8],
+    driver: Vec<u8>,
+
+    region: NsfRegion,
+    /// Position within `playlist()`
+    playlist_position: usize,
+    track: u8,
+    track_cycles: u64,
+
+    /// Cpu cycles between PLAY calls
+    play_period: f64,
+    play_timer: f64,
+    /// PLAY is due but INIT or the previous PLAY hasn't returned yet
+    play_pending: bool,
+    /// INIT has returned to the driver's idle loop
+    initialized: bool,
+    playing: bool,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf) -> Self {
+        let region = nsf.default_region();
+        let track = nsf.starting_track;
+        let mut player = Self {
+            nsf,
+            cpu: Cpu::new(),
+            cpu_pins: Self::startup_pins(),
+            apu: Apu::new(),
+            apu_pins: ApuPinout::new(),
+            ram: vec![0; 0x0800],
+            wram: vec![0; 0x2000],
+            rom: Vec::new(),
+            banks: [0; 8],
+            driver: Vec::new(),
+            region,
+            playlist_position: 0,
+            track,
+            track_cycles: 0,
+            play_period: 0.0,
+            play_timer: 0.0,
+            play_pending: false,
+            initialized: false,
+            playing: false,
+        };
+        player.rom = player.padded_rom();
+        player.set_track(track);
+        player
+    }
+
+    // Like the board, the cpu starts by detecting a reset
+    fn startup_pins() -> CpuPinout {
+        CpuPinout {
+            irq: true,
+            nmi: true,
+            reset: false,
+            phi: false,
+            ready: false,
+            data_bus: 0,
+            address_bus: 0,
+            address_rw: true,
+            sync: false,
+        }
+    }
+
+    fn padded_rom(&self) -> Vec<u8> {
+        let padding = if self.nsf.is_bankswitched() {
+            usize::from(self.nsf.load_address) % BANK_SIZE
+        } else {
+            usize::from(self.nsf.load_address.saturating_sub(0x8000))
+        };
+        let mut rom = vec![0; padding];
+        rom.extend_from_slice(&self.nsf.data);
+        rom
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    pub fn region(&self) -> NsfRegion {
+        self.region
+    }
+
+    /// Switches region and restarts the current track, since INIT is told the region
+    pub fn set_region(&mut self, region: NsfRegion) {
+        self.region = region;
+        self.set_track(self.track);
+    }
+
+    /// The 0-based song number currently playing
+    pub fn track(&self) -> u8 {
+        self.track
+    }
+
+    pub fn track_count(&self) -> usize {
+        self.nsf.track_count()
+    }
+
+    pub fn track_info(&self) -> Option<&NsfTrack> {
+        self.nsf.tracks.get(usize::from(self.track))
+    }
+
+    /// Order tracks are stepped through: the NSFe playlist if the file has one, otherwise every
+    /// song in order
+    pub fn playlist(&self) -> Vec<u8> {
+        let count = self.track_count();
+        match &self.nsf.playlist {
+            Some(playlist) => playlist
+                .iter()
+                .copied()
+                .filter(|&track| usize::from(track) < count)
+                .collect(),
+            None => (0..count).map(|track| track as u8).collect(),
+        }
+    }
+
+    /// Time spent in the current track
+    pub fn elapsed(&self) -> Duration {
+        Duration::from_secs_f64(self.track_cycles as f64 / self.region.cpu_clock_rate())
+    }
+
+    pub fn next_track(&mut self) {
+        let playlist = self.playlist();
+        if playlist.is_empty() {
+            return;
+        }
+        self.playlist_position = (self.playlist_position + 1) % playlist.len();
+        self.set_track(playlist[self.playlist_position]);
+    }
+
+    pub fn previous_track(&mut self) {
+        let playlist = self.playlist();
+        if playlist.is_empty() {
+            return;
+        }
+        self.playlist_position = (self.playlist_position + playlist.len() - 1) % playlist.len();
+        self.set_track(playlist[self.playlist_position]);
+    }
+
+    /// Resets the machine and starts song `track` (0-based)
+    pub fn set_track(&mut self, track: u8) {
+        self.track = track;
+        if let Some(position) = self.playlist().iter().position(|&entry| entry == track) {
+            self.playlist_position = position;
+        }
+
+        self.ram.fill(0);
+        self.wram.fill(0);
+        self.banks = if self.nsf.is_bankswitched() {
+            self.nsf.bank_init
+        } else {
+            [0, 1, 2, 3, 4, 5, 6, 7]
+        };
+        self.driver = self.build_driver();
+
+        self.cpu = Cpu::new();
+        self.cpu_pins = Self::startup_pins();
+        self.apu_pins = ApuPinout::new();
+        self.apu.set_clock_rate(self.region.cpu_clock_rate());
+        self.apu.clear_samples();
+
+        let speed = f64::from(self.nsf.play_speed(self.region));
+        self.play_period = speed * self.region.cpu_clock_rate() / 1_000_000.0;
+        self.play_timer = 0.0;
+        self.play_pending = false;
+        self.initialized = false;
+        self.playing = false;
+        self.track_cycles = 0;
+    }
+
+    #[rustfmt::skip]
+    fn build_driver(&self) -> Vec<u8> {
+        let region = match self.region {
+            NsfRegion::Ntsc => 0,
+            NsfRegion::Pal => 1,
+        };
+        let [init_lo, init_hi] = self.nsf.init_address.to_le_bytes();
+        let [play_lo, play_hi] = self.nsf.play_address.to_le_bytes();
+        let [idle_lo, idle_hi] = DRIVER_IDLE.to_le_bytes();
+        vec![
+            Op::SEI_IMP,
+            Op::CLD_IMP,
+            Op::LDX_IMM, 0xFF,
+            Op::TXS_IMP,
+            // Clear $4000-$4013
+            Op::LDA_IMM, 0x00,
+            Op::LDX_IMM, 0x13,
+            Op::STA_ABS_X, 0x00, 0x40,
+            Op::DEX_IMP,
+            Op::BPL_REL, 0xFA,
+            Op::LDA_IMM, 0x0F,
+            Op::STA_ABS, 0x15, 0x40,
+            Op::LDA_IMM, 0x40,
+            Op::STA_ABS, 0x17, 0x40,
+            Op::LDA_IMM, self.track,
+            Op::LDX_IMM, region,
+            Op::JSR_ABS, init_lo, init_hi,
+            // DRIVER_IDLE
+            Op::JMP_ABS, idle_lo, idle_hi,
+            // DRIVER_NMI
+            Op::JSR_ABS, play_lo, play_hi,
+            // DRIVER_PLAY_RETURN
+            Op::RTI_IMP,
+        ]
+    }
+
+    /// Emulate one cpu cycle
+    pub fn clock(&mut self) {
+        self.play_timer += 1.0;
+        if self.play_timer >= self.play_period {
+            self.play_timer -= self.play_period;
+            self.play_pending = true;
+        }
+        if self.play_pending && self.initialized && !self.playing {
+            // Pull NMI low for a cycle so the cpu sees an edge
+            self.cpu_pins.nmi = false;
+            self.play_pending = false;
+            self.playing = true;
+        }
+
+        self.cpu_clock(false);
+        if self.cpu_pins.sync {
+            match self.cpu_pins.address_bus {
+                DRIVER_IDLE => self.initialized = true,
+                DRIVER_PLAY_RETURN => self.playing = false,
+                _ => {}
+            }
+        }
+        self.cpu_clock(true);
+
+        self.apu.clock(&mut self.apu_pins);
+        // A write only lasts for the cycle it was made in
+        self.apu_pins.cpu_rw = true;
+
+        self.cpu_pins.reset = true;
+        self.cpu_pins.irq = true;
+        self.cpu_pins.nmi = true;
+        self.track_cycles += 1;
+    }
+
+    /// Runs the tune until `out` can be filled with samples, returning how many were written
+    pub fn fill_samples(&mut self, out: &mut [f32]) -> usize {
+        while self.apu.samples_available() < out.len() {
+            self.clock();
+        }
+        self.apu.read_samples_f32(out)
+    }
+
+    fn cpu_clock(&mut self, phi: bool) {
+        self.cpu_pins.phi = phi;
+        self.cpu.clock(&mut self.cpu_pins);
+        if self.cpu_pins.address_rw && !phi {
+            if let Some(data) = self.cpu_mem_read(self.cpu_pins.address_bus) {
+                self.cpu_pins.data_bus = data;
+            }
+        } else if !self.cpu_pins.address_rw && phi {
+            self.cpu_mem_write(self.cpu_pins.address_bus, self.cpu_pins.data_bus);
+        }
+    }
+
+    // Unmapped addresses leave the data bus as it was
+    fn cpu_mem_read(&self, addr: u16) -> Option<u8> {
+        match addr {
+            0x0000..0x2000 => Some(self.ram[usize::from(addr) % 0x0800]),
+            0x2000..0x4000 => self
+                .driver
+                .get(usize::from(addr.wrapping_sub(DRIVER_ADDRESS)))
+                .copied(),
+            0x6000..0x8000 => Some(self.wram[usize::from(addr - 0x6000)]),
+            // NMI, RESET and IRQ vectors
+            0xFFFA..=0xFFFF => {
+                let vector = match addr & !1 {
+                    0xFFFA => DRIVER_NMI,
+                    0xFFFC => DRIVER_ADDRESS,
+                    _ => DRIVER_IDLE,
+                };
+                Some(vector.to_le_bytes()[usize::from(addr & 1)])
+            }
+            0x8000..=0xFFFF => {
+                let bank = usize::from(self.banks[usize::from(addr - 0x8000) / BANK_SIZE]);
+                let offset = bank * BANK_SIZE + usize::from(addr) % BANK_SIZE;
+                Some(self.rom.get(offset).copied().unwrap_or(0))
+            }
+            _ => None,
+        }
+    }
+
+    fn cpu_mem_write(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x0000..0x2000 => self.ram[usize::from(addr) % 0x0800] = data,
+            0x4000..=0x4013 | 0x4015 | 0x4017 => {
+                self.apu_pins.cpu_rw = false;
+                self.apu_pins.cpu_addr = (addr - 0x4000) as u8;
+                self.apu_pins.cpu_data = data;
+            }
+            0x5FF8..=0x5FFF if self.nsf.is_bankswitched() => {
+                self.banks[usize::from(addr - 0x5FF8)] = data;
+            }
+            0x6000..0x8000 => self.wram[usize::from(addr - 0x6000)] = data,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{NsfPlayer, BANK_SIZE};
+    use crate::{
+        apu::{mixer::ApuChannel, NTSC_CPU_CLOCK_RATE},
+        cpu::instructions::Instructions as Op,
+        nsf::Nsf,
+    };
+
+    /// A bankswitched tune whose INIT swaps bank 2 in at $9000 and whose PLAY copies the byte
+    /// there to $4000 and counts its calls in $00
+    #[rustfmt::skip]
+    fn bankswitched_nsf(speed: u16) -> Nsf {
+        let mut file = vec![0; 0x80];
+        file[..5].copy_from_slice(b"NESM\x1A");
+        file[0x06] = 1;
+        file[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x06, 0x80]);
+        file[0x6E..0x70].copy_from_slice(&speed.to_le_bytes());
+        file[0x70..0x78].copy_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+
+        let mut data = vec![0; 3 * BANK_SIZE];
+        let program = [
+            // INIT
+            Op::LDA_IMM, 0x02,
+            Op::STA_ABS, 0xF9, 0x5F,
+            Op::RTS_IMP,
+            // PLAY
+            Op::LDA_ABS, 0x00, 0x90,
+            Op::STA_ABS, 0x00, 0x40,
+            Op::INC_ZP, 0x00,
+            Op::RTS_IMP,
+        ];
+        data[..program.len()].copy_from_slice(&program);
+        // Constant volumes 10 and 5
+        data[BANK_SIZE] = 0x3A;
+        data[2 * BANK_SIZE] = 0x35;
+        file.extend_from_slice(&data);
+        Nsf::decode(&file).unwrap()
+    }
+
+    #[test]
+    fn play_runs_at_the_header_rate_after_init() {
+        let mut player = NsfPlayer::new(bankswitched_nsf(2000));
+        let period = 2000.0 * NTSC_CPU_CLOCK_RATE / 1_000_000.0;
+        let cycles = (period * 25.5) as u64;
+        for _ in 0..cycles {
+            player.clock();
+        }
+        assert_eq!(player.ram[0], 25);
+        // INIT's $5FF9 write switched the second window from bank 1 to bank 2
+        assert_eq!(player.banks[1], 2);
+        let pulse = player.apu().channel_state(ApuChannel::Pulse1);
+        assert_eq!(pulse.volume, 5);
+    }
+}
@@ -0,0 +1,57 @@
+use super::{Ppu, PpuPinout};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PpuBusAccess {
+    /// ALE is high and the whole address is on the bus, ahead of the read or write
+    Address,
+    /// /RD is low and the byte at the address is expected on the data lines
+    Read,
+    /// /WR is low with the byte from $2007 on the data lines
+    Write,
+}
+
+/// One dot of activity on the ppu's address and data bus, the way hardware on the cartridge
+/// sees it
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PpuBusEvent {
+    /// The 14-bit address on the bus. A8-A13 stay put between accesses while the low byte goes
+    /// through the address latch, so a read or write carries the address its ALE dot latched.
+    pub address: u16,
+    pub access: PpuBusAccess,
+    pub scanline: usize,
+    pub dot: usize,
+    /// Dots clocked since power on, for measuring time between events across scanlines
+    pub dots: u64,
+    /// The access was made while the ppu was rendering rather than for $2007
+    pub rendering: bool,
+}
+
+impl PpuBusEvent {
+    /// Level of PPU A12, which selects the pattern table
+    pub fn a12(&self) -> bool {
+        (self.address & 0x1000) > 0
+    }
+}
+
+impl Ppu {
+    /// What the ppu did on its bus during the last `clock`, `None` if it left the bus alone
+    pub fn bus_event(&self, pins: &PpuPinout) -> Option<PpuBusEvent> {
+        let access = if pins.ppu_ale {
+            PpuBusAccess::Address
+        } else if pins.ppu_r {
+            PpuBusAccess::Read
+        } else if pins.ppu_w {
+            PpuBusAccess::Write
+        } else {
+            return None;
+        };
+        Some(PpuBusEvent {
+            address: self.bus_address,
+            access,
+            scanline: self.scanline,
+            dot: self.cycle,
+            dots: self.dots,
+            rendering: self.is_rendering_enabled() && self.is_fetch_scanline(),
+        })
+    }
+}
@@ -0,0 +1,241 @@
+use super::Ppu;
+
+pub const PATTERN_TABLE_WIDTH: usize = 128;
+pub const PATTERN_TABLE_HEIGHT: usize = 128;
+/// All four nametables laid out as the ppu addresses them, $2000 top left and $2C00 bottom right
+pub const NAMETABLES_WIDTH: usize = 512;
+pub const NAMETABLES_HEIGHT: usize = 480;
+/// 8 columns by 8 rows of 8x16 cells, in OAM order
+pub const OAM_SHEET_WIDTH: usize = 64;
+pub const OAM_SHEET_HEIGHT: usize = 128;
+pub const OAM_LAYOUT_WIDTH: usize = 256;
+pub const OAM_LAYOUT_HEIGHT: usize = 240;
+/// One pixel per entry, background palettes on the top row and sprite palettes on the bottom
+pub const PALETTE_RAM_WIDTH: usize = 16;
+pub const PALETTE_RAM_HEIGHT: usize = 2;
+
+/// Read access to the ppu's address space, $0000-$2FFF, without side effects. Implemented by
+/// whatever owns the pattern tables and nametables so that the debug views see them through the
+/// active mapper and mirroring.
+pub trait PpuMemory {
+    fn peek(&self, address: u16) -> u8;
+}
+
+/// Renders pattern table `table` (0 or 1) into `rgba`, tiles in the order they're stored, coloured
+/// with palette `palette`: 0-3 are the background palettes and 4-7 the sprite palettes
+pub fn render_pattern_table(
+    ppu: &Ppu,
+    memory: &impl PpuMemory,
+    table: u8,
+    palette: u8,
+    rgba: &mut [u8],
+) {
+    for tile in 0..256u16 {
+        let address = (u16::from(table & 1) << 12) | (tile << 4);
+        let x = usize::from(tile % 16) * 8;
+        let y = usize::from(tile / 16) * 8;
+        draw_tile(
+            memory,
+            address,
+            false,
+            false,
+            PATTERN_TABLE_WIDTH,
+            x,
+            y,
+            rgba,
+            |value| Some(palette_rgb(ppu, palette, value)),
+        );
+    }
+}
+
+/// Renders all four nametables with their attribute colours and the background pattern table
+/// selected in PPUCTRL. With `scroll_overlay` the 256x240 window the next frame starts scrolled to
+/// is outlined by inverting the pixels under its edges.
+pub fn render_nametables(
+    ppu: &Ppu,
+    memory: &impl PpuMemory,
+    scroll_overlay: bool,
+    rgba: &mut [u8],
+) {
+    let pattern_table = u16::from(ppu.background_pattern_table()) << 12;
+    for nametable in 0..4u16 {
+        let base = 0x2000 | (nametable << 10);
+        let origin_x = usize::from(nametable & 1) * 256;
+        let origin_y = usize::from(nametable >> 1) * 240;
+        for tile_y in 0..30u16 {
+            for tile_x in 0..32u16 {
+                let tile = memory.peek(base | (tile_y << 5) | tile_x);
+                let attribute = memory.peek(base | 0x3C0 | ((tile_y >> 2) << 3) | (tile_x >> 2));
+                let shift = ((tile_y & 2) << 1) | (tile_x & 2);
+                let palette = (attribute >> shift) & 3;
+                let address = pattern_table | (u16::from(tile) << 4);
+                let x = origin_x + usize::from(tile_x) * 8;
+                let y = origin_y + usize::from(tile_y) * 8;
+                draw_tile(
+                    memory,
+                    address,
+                    false,
+                    false,
+                    NAMETABLES_WIDTH,
+                    x,
+                    y,
+                    rgba,
+                    |value| Some(palette_rgb(ppu, palette, value)),
+                );
+            }
+        }
+    }
+
+    if scroll_overlay {
+        let t = ppu.temp_address;
+        let nametable = usize::from(t >> 10) & 3;
+        let scroll_x =
+            (nametable & 1) * 256 + (usize::from(t) & 0x1F) * 8 + usize::from(ppu.fine_x_scroll);
+        let scroll_y =
+            (nametable >> 1) * 240 + (usize::from(t >> 5) & 0x1F) * 8 + (usize::from(t >> 12) & 7);
+        let mut invert = |x: usize, y: usize| {
+            let index = ((y % NAMETABLES_HEIGHT) * NAMETABLES_WIDTH + x % NAMETABLES_WIDTH) * 4;
+            for channel in &mut rgba[index..index + 3] {
+                *channel = !*channel;
+            }
+        };
+        for x in 0..256 {
+            invert(scroll_x + x, scroll_y);
+            invert(scroll_x + x, scroll_y + 239);
+        }
+        for y in 1..239 {
+            invert(scroll_x, scroll_y + y);
+            invert(scroll_x + 255, scroll_y + y);
+        }
+    }
+}
+
+/// Renders the 64 sprites in OAM order as they'd appear on screen, flips included. 8x8 sprites
+/// fill the top half of their cell; transparent pixels have an alpha of 0.
+pub fn render_oam_sheet(ppu: &Ppu, memory: &impl PpuMemory, rgba: &mut [u8]) {
+    for pixel in rgba.chunks_exact_mut(4) {
+        pixel.copy_from_slice(&[0; 4]);
+    }
+    for sprite in 0..64 {
+        let x = (sprite % 8) * 8;
+        let y = (sprite / 8) * 16;
+        draw_sprite(ppu, memory, sprite, OAM_SHEET_WIDTH, x, y, rgba);
+    }
+}
+
+/// Renders the sprites where they'd be drawn on screen, sprites earlier in OAM on top. Pixels
+/// without a sprite have an alpha of 0.
+pub fn render_oam_layout(ppu: &Ppu, memory: &impl PpuMemory, rgba: &mut [u8]) {
+    for pixel in rgba.chunks_exact_mut(4) {
+        pixel.copy_from_slice(&[0; 4]);
+    }
+    for sprite in (0..64).rev() {
+        let y = usize::from(ppu.oam_memory[sprite * 4]) + 1;
+        let x = usize::from(ppu.oam_memory[sprite * 4 + 3]);
+        draw_sprite(ppu, memory, sprite, OAM_LAYOUT_WIDTH, x, y, rgba);
+    }
+}
+
+/// Renders the 32 bytes of palette ram through the ppu's palette
+pub fn render_palette_ram(ppu: &Ppu, rgba: &mut [u8]) {
+    for (index, pixel) in rgba.chunks_exact_mut(4).take(32).enumerate() {
+        let [r, g, b] = ppu.palette.rgb(u16::from(ppu.get_frame_palette(index)));
+        pixel.copy_from_slice(&[r, g, b, 255]);
+    }
+}
+
+fn palette_rgb(ppu: &Ppu, palette: u8, value: u8) -> [u8; 3] {
+    // Colour 0 of every palette is the shared backdrop
+    let index = if value == 0 {
+        0
+    } else {
+        usize::from(palette & 7) * 4 + usize::from(value)
+    };
+    ppu.palette.rgb(u16::from(ppu.get_frame_palette(index)))
+}
+
+fn draw_sprite(
+    ppu: &Ppu,
+    memory: &impl PpuMemory,
+    sprite: usize,
+    width: usize,
+    x: usize,
+    y: usize,
+    rgba: &mut [u8],
+) {
+    let tile = ppu.oam_memory[sprite * 4 + 1];
+    let attrib = ppu.oam_memory[sprite * 4 + 2];
+    let flip_horizontally = (attrib & 0x40) > 0;
+    let flip_vertically = (attrib & 0x80) > 0;
+    let palette = 4 + (attrib & 3);
+    let colour = |value| (value > 0).then(|| palette_rgb(ppu, palette, value));
+
+    if ppu.sprite_size() {
+        let address = (u16::from(tile & 1) << 12) | (u16::from(tile & 0xFE) << 4);
+        // Flipping vertically also swaps the two halves
+        let (top, bottom) = if flip_vertically {
+            (address | 0x10, address)
+        } else {
+            (address, address | 0x10)
+        };
+        let (f_h, f_v) = (flip_horizontally, flip_vertically);
+        draw_tile(memory, top, f_h, f_v, width, x, y, rgba, colour);
+        draw_tile(memory, bottom, f_h, f_v, width, x, y + 8, rgba, colour);
+    } else {
+        let address = (u16::from(ppu.sprite_pattern_table()) << 12) | (u16::from(tile) << 4);
+        draw_tile(
+            memory,
+            address,
+            flip_horizontally,
+            flip_vertically,
+            width,
+            x,
+            y,
+            rgba,
+            colour,
+        );
+    }
+}
+
+/// Draws the 8x8 tile at `address` with its top left corner at (x, y) of an image `width` pixels
+/// wide. `colour` maps the 2-bit pixel values to RGB, `None` leaving the pixel as it was. Pixels
+/// outside the image are clipped.
+#[allow(clippy::too_many_arguments)]
+fn draw_tile(
+    memory: &impl PpuMemory,
+    address: u16,
+    flip_horizontally: bool,
+    flip_vertically: bool,
+    width: usize,
+    x: usize,
+    y: usize,
+    rgba: &mut [u8],
+    colour: impl Fn(u8) -> Option<[u8; 3]>,
+) {
+    let height = rgba.len() / 4 / width;
+    for row in 0..8 {
+        let pattern_row = if flip_vertically { 7 - row } else { row };
+        let lsb = memory.peek(address + pattern_row);
+        let msb = memory.peek(address + pattern_row + 8);
+        let py = y + usize::from(row);
+        if py >= height {
+            break;
+        }
+        for column in 0..8 {
+            let px = x + column;
+            if px >= width {
+                break;
+            }
+            let bit = if flip_horizontally {
+                column
+            } else {
+                7 - column
+            };
+            let value = ((lsb >> bit) & 1) | (((msb >> bit) & 1) << 1);
+            if let Some([r, g, b]) = colour(value) {
+                let index = (py * width + px) * 4;
+                rgba[index..index + 4].copy_from_slice(&[r, g, b, 255]);
+            }
+        }
+    }
+}
@@ -0,0 +1,132 @@
+use super::Ppu;
+use crate::region::Region;
+
+/// Width of the timing map, one pixel per dot
+pub const EVENT_MAP_WIDTH: usize = 341;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum PpuEventKind {
+    /// A cpu read of $2000-$2007, with `value` the byte the ppu returned
+    RegisterRead,
+    /// A cpu write to $2000-$2007
+    RegisterWrite,
+    /// A write to $4014, with `value` the page copied
+    OamDma,
+    /// The mapper started holding the cpu's IRQ line low
+    MapperIrq,
+    Sprite0Hit,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct PpuEvent {
+    pub scanline: usize,
+    pub dot: usize,
+    pub kind: PpuEventKind,
+    /// Register or cpu address involved, 0 when there's none
+    pub address: u16,
+    pub value: u8,
+    /// Address of the instruction that caused the event
+    pub pc: u16,
+}
+
+impl PpuEvent {
+    /// Colour the event is drawn with on the timing map
+    pub fn colour(&self) -> [u8; 3] {
+        match self.kind {
+            PpuEventKind::RegisterRead | PpuEventKind::RegisterWrite => {
+                const REGISTER_COLOURS: [[u8; 3]; 8] = [
+                    [0xFF, 0x40, 0x40],
+                    [0x40, 0xFF, 0x40],
+                    [0xFF, 0xFF, 0x40],
+                    [0x40, 0x80, 0xFF],
+                    [0x40, 0xFF, 0xFF],
+                    [0xFF, 0x80, 0x00],
+                    [0xFF, 0x40, 0xFF],
+                    [0xC0, 0x80, 0xFF],
+                ];
+                let colour = REGISTER_COLOURS[usize::from(self.address & 7)];
+                if self.kind == PpuEventKind::RegisterRead {
+                    // Reads are a darker shade of the register's colour
+                    colour.map(|channel| channel / 2)
+                } else {
+                    colour
+                }
+            }
+            PpuEventKind::OamDma => [0xFF, 0xFF, 0xFF],
+            PpuEventKind::MapperIrq => [0x80, 0xFF, 0x80],
+            PpuEventKind::Sprite0Hit => [0xFF, 0xC0, 0xC0],
+        }
+    }
+}
+
+/// Events of one frame in the order they happened
+#[derive(Clone, Default, Debug)]
+pub struct PpuEventLog {
+    events: Vec<PpuEvent>,
+}
+
+impl PpuEventLog {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Logs an event at the dot `ppu` is currently on
+    pub fn record(&mut self, ppu: &Ppu, kind: PpuEventKind, address: u16, value: u8, pc: u16) {
+        self.events.push(PpuEvent {
+            scanline: ppu.scanline,
+            dot: ppu.cycle,
+            kind,
+            address,
+            value,
+            pc,
+        });
+    }
+
+    pub fn events(&self) -> &[PpuEvent] {
+        &self.events
+    }
+
+    /// The events whose marker on the timing map covers (`scanline`, `dot`)
+    pub fn events_at(&self, scanline: usize, dot: usize) -> impl Iterator<Item = &PpuEvent> {
+        self.events.iter().filter(move |event| {
+            event.scanline.abs_diff(scanline) <= 1 && event.dot.abs_diff(dot) <= 1
+        })
+    }
+
+    pub fn clear(&mut self) {
+        self.events.clear();
+    }
+}
+
+/// Renders `log` over a map of a `region` frame, one row per scanline numbered as the ppu does so
+/// the pre-render line is at the bottom. The picture is dark grey, blanking black, and each event
+/// is drawn as a 3x3 marker in its colour.
+pub fn render_event_map(log: &PpuEventLog, region: Region, rgba: &mut [u8]) {
+    let height = region.scanlines_per_frame();
+    for (i, pixel) in rgba
+        .chunks_exact_mut(4)
+        .take(EVENT_MAP_WIDTH * height)
+        .enumerate()
+    {
+        let scanline = i / EVENT_MAP_WIDTH;
+        let dot = i % EVENT_MAP_WIDTH;
+        let shade = if scanline < 240 && (1..=256).contains(&dot) {
+            0x40
+        } else {
+            0x00
+        };
+        pixel.copy_from_slice(&[shade, shade, shade, 255]);
+    }
+
+    for event in log.events() {
+        let [r, g, b] = event.colour();
+        for scanline in event.scanline.saturating_sub(1)..=event.scanline + 1 {
+            for dot in event.dot.saturating_sub(1)..=event.dot + 1 {
+                if scanline < height && dot < EVENT_MAP_WIDTH {
+                    let index = (scanline * EVENT_MAP_WIDTH + dot) * 4;
+                    rgba[index..index + 4].copy_from_slice(&[r, g, b, 255]);
+                }
+            }
+        }
+    }
+}
@@ -1,12 +1,47 @@
+use std::ops::RangeInclusive;
+
+use crate::{
+    region::Region,
+    video::{
+        palette::{Palette, SYSTEM_COLOURS},
+        EMPHASIS_SHIFT,
+    },
+};
+
+pub mod bus;
+pub mod debug;
+pub mod events;
+pub mod state;
+pub mod variant;
+
+pub use bus::{PpuBusAccess, PpuBusEvent};
+pub use state::{PpuCtrl, PpuMask, PpuState, PpuStatus};
+pub use variant::PpuVariant;
+
 // True size of Rendering Area
 const DOTS_PER_SCANLINE: usize = 341;
-// Contains pre-scan line
-const SCANLINES_PER_FRAME: usize = 262;
 // Actual drawn Region
 const DOTS_PER_IMAGE_ROW: usize = 256;
 const SCANLINES_PER_IMAGE: usize = 240;
 
 pub const VIDEO_MEMORY_SIZE: usize = DOTS_PER_IMAGE_ROW * SCANLINES_PER_IMAGE * 4;
+/// Dots between the vblank flag being set and NMI being raised, during which reading $2002
+/// clears the flag and so suppresses the NMI
+const NMI_DELAY_DOTS: u8 = 2;
+/// How long a bit of the I/O latch holds its value for without being driven
+const IO_LATCH_DECAY_SECONDS: f64 = 0.6;
+/// Dots an 8 byte row of OAM keeps its contents without being read or written, about 3000 cpu
+/// cycles. Rendering reads every row each scanline, so only long stretches without it decay.
+const OAM_DECAY_DOTS: u64 = 9000;
+/// The 2C07 refreshes OAM for the end of vblank so it doesn't decay over its long vblank, taking
+/// OAM away from the cpu as if it were rendering
+const PAL_OAM_REFRESH_SCANLINES: RangeInclusive<usize> = 265..=310;
+
+// A dot lasts 8 of the 12 phases of the NTSC colour subcarrier
+const COLOUR_PHASES: u8 = 12;
+const COLOUR_PHASES_PER_DOT: u8 = 8;
+
+pub const PIXEL_DATA_SIZE: usize = DOTS_PER_IMAGE_ROW * SCANLINES_PER_IMAGE;
 
 #[derive(PartialEq, PartialOrd, Debug)]
 enum VRamManip {
@@ -72,6 +107,8 @@ impl PixelBuffer {
 struct EvaluatedSprite {
     x: u8,
     attrib: u8,
+    /// Position in OAM, for hiding sprites from the picture
+    index: u8,
     /// Tile will serve two purposes here:
     /// A: Will be the tile index in the relevant character table before fetch
     /// B: Will store the lsb and msb of the tile after fetch
@@ -94,6 +131,14 @@ pub struct PpuPinout {
     /// Will exist to tell the outside world that a frame should have been completed
     /// Not an official pin in the NES
     pub finished_frame: bool,
+
+    /// Pattern row of a sprite past the 8 per scanline limit, when the limit is removed. The
+    /// board answers with the row's low and high planes, from `address` and `address + 8`, in
+    /// `extra_sprite_data` without going through the ppu bus, so cartridge hardware doesn't see
+    /// the fetch.
+    /// Not an official pin in the NES
+    pub extra_sprite_address: Option<u16>,
+    pub extra_sprite_data: [u8; 2],
 }
 
 pub struct Ppu {
@@ -120,37 +165,86 @@ pub struct Ppu {
     attribute_msb_scroll: LoopyShiftRegister,
     attribute_lsb_scroll: LoopyShiftRegister,
 
+    region: Region,
+    variant: PpuVariant,
     scanline: usize,
     cycle: usize,
     is_odd_frame: bool,
+    /// Rendering enable as seen by the rendering logic, a dot behind PPUMASK
+    rendering_enabled: bool,
+    /// Dots until a newly set vblank flag is allowed to pull NMI low
+    nmi_delay: u8,
+    /// Set by a $2002 read racing the start of vblank
+    suppress_vblank: bool,
     internal_read_buffer: u8,
+    /// Value left on the ppu's side of the cpu data bus by the last register access, which reads
+    /// of write-only registers and undriven bits return
+    io_latch: u8,
+    /// Dot each bit of `io_latch` was last driven at
+    io_latch_refreshed: [u64; 8],
+    /// Dots clocked since power on
+    dots: u64,
+    /// Address last put on the ppu bus, A8-A13 as held on the pins and A0-A7 as latched by ALE
+    bus_address: u16,
 
     oam_memory: [u8; 4 * 64],
+    /// Emulate OAM corruption and decay, see `set_oam_glitches`. `None` follows the region.
+    oam_glitches: Option<bool>,
+    /// Dot each 8 byte row of OAM was last read or written at
+    oam_row_refreshed: [u64; 32],
+    /// Rows to overwrite with row 0 when rendering starts again, one bit per row
+    corrupt_oam_rows: u32,
+    /// Sprites found for the next scanline as raw OAM bytes, filled during dots 1-256
+    secondary_oam: [u8; 32],
+    secondary_oam_address: usize,
+    /// Byte last moved between primary and secondary OAM, which is what $2004 reads while
+    /// sprites are being evaluated and fetched
+    oam_latch: u8,
+    /// The Y byte last read was in range, so the following bytes are part of a visible sprite
+    sprite_in_range: bool,
+    /// The first sprite evaluated was in range; it takes part in sprite 0 hits
+    sprite_0_in_range: bool,
+    /// Bytes left to read of the sprite that overflowed
+    overflow_bytes: u8,
+    /// All 64 sprites have been looked at, or the overflow has been found
+    sprite_evaluation_done: bool,
+    /// Secondary OAM decoded at dot 257 for the sprite fetches
     secondary_oam_buffer: [Option<EvaluatedSprite>; 8],
+    /// OAM index of each sprite copied into secondary OAM
+    secondary_oam_indices: [u8; 8],
     oam_pixel_buffer: PixelBuffer,
-    secondary_oam_buffer_count: usize,
+    /// What's drawn of the sprites: `oam_pixel_buffer` without the hidden ones, plus any sprites
+    /// past the 8th when the limit is removed
+    shown_sprite_pixels: PixelBuffer,
+    /// Display only switches, see `set_background_visible`
+    show_background: bool,
+    show_sprites: bool,
+    /// One bit per OAM index
+    hidden_sprites: u64,
+    /// Draw the in range sprites evaluation dropped, see `set_remove_sprite_limit`
+    remove_sprite_limit: bool,
+    /// Sprites past the first 8 on the next scanline, fetched through `extra_sprite_address`
+    extra_sprites: Vec<EvaluatedSprite>,
+    extra_sprites_fetched: usize,
 
     frame_palette_memory: [u8; 32],
-    system_palette_memory: [u8; 64 * 3],
+    /// Colours handed to whatever turns `pixel_data` into an image; the ppu itself only outputs
+    /// palette indices
+    palette: Palette,
 
-    /// TODO: remove from the ppu
-    /// Treat ppu as a pixel-generating function
-    video_data: Vec<u8>,
+    /// Subcarrier phase of the current dot, 0-11
+    colour_phase: u8,
+    /// Subcarrier phase of the first dot of the current picture
+    frame_colour_phase: u8,
+
+    /// One entry per dot of the visible picture, see `video::PIXEL_INDEX_MASK`
+    pixel_data: Vec<u16>,
 }
 
 impl Ppu {
     pub fn dump(&self) {
         println!("\n=====PPU DUMP=====");
-        // println!("v-address: {:0>4X}", self.vram_address);
-        // println!("t-address: {:0>4X}", self.temp_address);
-        // println!("w-latch: {}", self.w_register);
-        // println!("cycle: {}, scanline: {}", self.cycle, self.scanline);
-        // println!("MSB Shift register: {:0>16b}", self.tile_msb_scroll.0);
-        // println!("LSB Shift register: {:0>16b}", self.tile_lsb_scroll.0);
-        // println!("Next Nametable: {:0>2X}", self.next_nametable);
-        // println!("Next MSB byte: {:0>2X}", self.next_tile_msb);
-        // println!("Next LSB byte: {:0>2X}", self.next_tile_lsb);
-        // println!("Next Attribute: {:0>2X}", self.next_attribute);
+        println!("{}", self.state());
         for i in 0..64 {
             let i2 = i * 4;
             println!(
@@ -188,40 +282,204 @@ impl Ppu {
 
             fine_x_scroll: 0,
 
-            scanline: 261,
+            region: Region::Ntsc,
+            variant: PpuVariant::Rp2c02,
+            scanline: Region::Ntsc.scanlines_per_frame() - 1,
             cycle: 0,
             is_odd_frame: false,
+            rendering_enabled: false,
+            nmi_delay: 0,
+            suppress_vblank: false,
             internal_read_buffer: 0,
+            io_latch: 0,
+            io_latch_refreshed: [0; 8],
+            dots: 0,
+            bus_address: 0,
             oam_memory: [0; 256],
+            oam_glitches: None,
+            oam_row_refreshed: [0; 32],
+            corrupt_oam_rows: 0,
+            secondary_oam: [0xFF; 32],
+            secondary_oam_address: 0,
+            oam_latch: 0,
+            sprite_in_range: false,
+            sprite_0_in_range: false,
+            overflow_bytes: 0,
+            sprite_evaluation_done: false,
             secondary_oam_buffer: [None; 8],
+            secondary_oam_indices: [0; 8],
             oam_pixel_buffer: PixelBuffer::new(),
-            secondary_oam_buffer_count: 0,
+            shown_sprite_pixels: PixelBuffer::new(),
+            show_background: true,
+            show_sprites: true,
+            hidden_sprites: 0,
+            remove_sprite_limit: false,
+            extra_sprites: Vec::with_capacity(64 - 8),
+            extra_sprites_fetched: 0,
             // 6-bit lookup into real palettes
             // $3FYX:
             //  Y = 0h BG, 1h Sprite
             //  X = 0h - Fh, X = 0, 4, 8, C is always 0
             frame_palette_memory: [0; 32],
-            system_palette_memory: [0; 64 * 3],
+            palette: Palette::new(),
+
+            colour_phase: 0,
+            frame_colour_phase: 0,
 
-            video_data: vec![255; VIDEO_MEMORY_SIZE],
+            pixel_data: vec![0; PIXEL_DATA_SIZE],
         }
     }
 
-    pub fn set_palette(&mut self, data: &[u8; 64 * 3]) {
-        self.system_palette_memory.clone_from_slice(data);
+    /// Loads the contents of a `.pal` file, either 64 colours or 512 colours covering every
+    /// emphasis combination. The emphasis of 64 colour files follows the current region's ppu.
+    /// Panics if the data is neither size
+    pub fn set_palette(&mut self, data: &[u8]) {
+        let palette = Palette::from_pal(data).expect("Palette must have 64 or 512 RGB entries");
+        self.palette = if data.len() == SYSTEM_COLOURS * 3 && self.region != Region::Ntsc {
+            palette.swap_red_green_emphasis()
+        } else {
+            palette
+        };
+    }
+
+    pub fn set_palette_colours(&mut self, palette: Palette) {
+        self.palette = palette;
     }
 
-    pub fn video_data(&self) -> &[u8] {
-        &self.video_data
+    /// Subcarrier phase (0-11) the first dot of `pixel_data` was output at. Each dot advances the
+    /// phase by 8, so each scanline starts 4 phases later than the one above it.
+    pub fn frame_colour_phase(&self) -> u8 {
+        self.frame_colour_phase
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Switches to the frame timing of `region` and restarts the frame from the pre-render line.
+    /// Unless `set_oam_glitches` has been called, OAM glitches are on for NTSC and off otherwise.
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+        self.scanline = region.scanlines_per_frame() - 1;
+        self.cycle = 0;
+    }
+
+    pub fn variant(&self) -> PpuVariant {
+        self.variant
+    }
+
+    /// Selects the register behaviour of `variant`. Its colours are set separately, see
+    /// `PpuVariant::palette`.
+    pub fn set_variant(&mut self, variant: PpuVariant) {
+        self.variant = variant;
+    }
+
+    pub fn scanline(&self) -> usize {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> usize {
+        self.cycle
+    }
+
+    /// The flag bits of PPUSTATUS, read without the side effects of $2002
+    pub fn status(&self) -> PpuStatus {
+        PpuStatus::from_bits_truncate(self.status_register)
+    }
+
+    pub fn oam_glitches(&self) -> bool {
+        self.oam_glitches.unwrap_or(self.region == Region::Ntsc)
+    }
+
+    /// Emulates the ways the 2C02's OAM loses data:
+    /// - OAM is DRAM that only rendering refreshes, so rows left alone for about 3000 cpu cycles
+    ///   decay. The 2C07 also refreshes OAM near the end of its long vblank, rendering or not.
+    /// - Turning rendering off while OAM is being accessed, during dots 0-63 or 256-319 of a
+    ///   rendered line, leaves a row to be overwritten with the first row once rendering resumes.
+    /// - Rendering starting with OAMADDR at 8 or above copies the row it points into over the
+    ///   first row.
+    ///
+    /// By default they follow the region, on for NTSC only. Once set here the choice is kept
+    /// across `set_region`.
+    pub fn set_oam_glitches(&mut self, enabled: bool) {
+        self.oam_glitches = Some(enabled);
+    }
+
+    pub fn remove_sprite_limit(&self) -> bool {
+        self.remove_sprite_limit
+    }
+
+    /// Also draws the sprites past the 8th on a scanline, behind the first 8. Evaluation, the
+    /// overflow flag and sprite 0 hits still only see the 8 the hardware finds, so games behave
+    /// the same; only the picture changes. Needs the board to answer `extra_sprite_address`.
+    pub fn set_remove_sprite_limit(&mut self, remove: bool) {
+        self.remove_sprite_limit = remove;
+    }
+
+    pub fn background_visible(&self) -> bool {
+        self.show_background
+    }
+
+    /// Leaves the background out of the picture. Like the other layer switches this only changes
+    /// `pixel_data`; sprite 0 hits and everything else rendering works out happen as usual.
+    pub fn set_background_visible(&mut self, visible: bool) {
+        self.show_background = visible;
+    }
+
+    pub fn sprites_visible(&self) -> bool {
+        self.show_sprites
+    }
+
+    /// Leaves every sprite out of the picture
+    pub fn set_sprites_visible(&mut self, visible: bool) {
+        self.show_sprites = visible;
+    }
+
+    /// Whether sprite `index` is drawn. OAM only holds 64 sprites, so indices past 63 are always
+    /// visible.
+    pub fn sprite_visible(&self, index: u8) -> bool {
+        index >= 64 || self.hidden_sprites & (1 << index) == 0
+    }
+
+    /// Leaves sprite `index` of OAM, 0-63, out of the picture. Sprites behind it show through.
+    /// Indices past 63 are ignored.
+    pub fn set_sprite_visible(&mut self, index: u8, visible: bool) {
+        if index >= 64 {
+            return;
+        }
+        if visible {
+            self.hidden_sprites &= !(1 << index);
+        } else {
+            self.hidden_sprites |= 1 << index;
+        }
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// The last rendered picture as 9-bit pixels: a 6-bit palette index plus the 3 emphasis bits
+    /// of PPUMASK
+    pub fn pixel_data(&self) -> &[u16] {
+        &self.pixel_data
     }
 
     pub fn clock(&mut self, pins: &mut PpuPinout) {
+        self.dots += 1;
         // Grab read byte before we manipulate the state of the ppu at all
         if self.vram_manip == VRamManip::Read && pins.ppu_r {
             self.internal_read_buffer = pins.ppu_address_data_low;
             self.vram_manip = VRamManip::None;
         }
 
+        // Extra sprite rows are asked for one a dot, in order
+        if pins.extra_sprite_address.take().is_some() {
+            let [lsb, msb] = pins.extra_sprite_data;
+            self.extra_sprites[self.extra_sprites_fetched].tile =
+                u16::from(lsb) | (u16::from(msb) << 8);
+            self.extra_sprites_fetched += 1;
+        }
+
         // Reset to reasonable defaults,
         // While it's pratically guaranteed that the ppu will always be reading during the
         // non-vblank period, the vblank period is governed by the cpu and its unclear what the ppu
@@ -243,6 +501,9 @@ impl Ppu {
         // flag; else, use the bus accordingly.
         // ppu_ale should go low every other tick as long as the ppu is not configured again
         if pins.ppu_ale {
+            self.bus_address = ((u16::from(pins.ppu_address_high) << 8)
+                | u16::from(pins.ppu_address_data_low))
+                & 0x3FFF;
         } else {
             match self.vram_manip {
                 VRamManip::Read => {
@@ -289,34 +550,50 @@ impl Ppu {
             self.shift();
         }
 
+        if self.oam_glitches() {
+            self.oam_glitch();
+        }
+
         if self.is_rendering_enabled() {
-            if self.is_sprite_evaluation_cycle() && self.scanline < 240 {
-                if self.cycle == 65 {
-                    // Restart evaluation for next scanline
-                    self.oam_address_register = 0;
-                    self.secondary_oam_buffer_count = 0;
-                    self.secondary_oam_buffer.fill(None);
-                    self.set_sprite_overflow(false);
+            if self.scanline < 240 {
+                if self.is_secondary_oam_clear_cycle() {
+                    // $2004 reads $FF while secondary OAM is being cleared
+                    self.oam_latch = 0xFF;
+                    if self.cycle.is_multiple_of(2) {
+                        self.secondary_oam[(self.cycle - 1) / 2] = 0xFF;
+                    }
+                } else if self.is_sprite_evaluation_cycle() {
+                    self.evaluate_sprites();
                 }
-                let sprite = self.evaluate_sprite();
-                if let Some(sprite) = sprite {
-                    self.oam_address_register = self.oam_address_register.wrapping_add(4);
-                    if sprite.is_some() {
-                        if self.secondary_oam_buffer_count < 8 {
-                            // Push evaluated sprites into a back-buffer so as to not replace sprites
-                            // currently in processing
-                            self.secondary_oam_buffer[self.secondary_oam_buffer_count] = sprite;
-                        } else {
-                            // TODO: Emulate buggy overflow behavior for more than 8 sprites on a scanline
-                            self.set_sprite_overflow(true);
-                        }
-                        self.secondary_oam_buffer_count += 1;
+            }
+
+            if self.is_fetch_scanline() {
+                if self.cycle == 257 {
+                    self.load_sprites();
+                    if self.remove_sprite_limit {
+                        self.load_extra_sprites();
+                    }
+                }
+                if self.remove_sprite_limit {
+                    self.fetch_extra_sprites(pins);
+                }
+                match self.cycle {
+                    257..=320 => {
+                        // The fetches read each sprite's four bytes from secondary OAM, then keep
+                        // reading its X
+                        self.oam_address_register = 0;
+                        let relative_cycle = self.cycle - 257;
+                        let byte = (relative_cycle / 8) * 4 + (relative_cycle % 8).min(3);
+                        self.oam_latch = self.secondary_oam[byte];
                     }
+                    0 | 321.. => self.oam_latch = self.secondary_oam[0],
+                    _ => {}
                 }
             }
 
             if self.cycle == 260 {
                 self.oam_pixel_buffer.clear();
+                self.shown_sprite_pixels.clear();
             }
 
             if self.cycle == 256 && self.scanline < 240 {
@@ -324,25 +601,23 @@ impl Ppu {
             }
 
             // "Transfer X" +
-            if self.cycle == 257 && (self.scanline < 240 || self.scanline == 261) {
+            if self.cycle == 257 && (self.scanline < 240 || self.is_pre_render_scanline()) {
                 const X_MASK: u16 = 0b1111101111100000;
                 self.vram_address = (self.vram_address & X_MASK) | (self.temp_address & !X_MASK);
             }
 
             // "Transfer Y"
-            if self.scanline == 261 && (280..305).contains(&self.cycle) {
+            if self.is_pre_render_scanline() && (280..305).contains(&self.cycle) {
                 const Y_MASK: u16 = 0b0111101111100000;
                 self.vram_address = (self.vram_address & !Y_MASK) | (self.temp_address & Y_MASK);
             }
 
             if self.is_render_cycle() {
-                let (sprite_palette_index, sprite_priority, sprite_opaque, sprite_0) = if self
-                    .enabled_sprite_rendering()
-                {
-                    let (pixel, priority, sprite_0) = self.oam_pixel_buffer.get(self.cycle as u8);
-                    (pixel as usize, priority as usize, (pixel & 3) > 0, sprite_0)
+                let (sprite_opaque, sprite_0) = if self.enabled_sprite_rendering() {
+                    let (pixel, _, sprite_0) = self.oam_pixel_buffer.get(self.cycle as u8);
+                    ((pixel & 3) > 0, sprite_0)
                 } else {
-                    (0, 0, false, false)
+                    (false, false)
                 };
 
                 let (bg_palette_index, bg_opaque) = if self.enabled_background_rendering() {
@@ -372,6 +647,20 @@ impl Ppu {
                     self.set_sprite_hit();
                 }
 
+                // The layer switches only change what's drawn, after sprite 0 hit has been found
+                let (bg_palette_index, bg_opaque) = if self.show_background {
+                    (bg_palette_index, bg_opaque)
+                } else {
+                    (0, false)
+                };
+                let (sprite_palette_index, sprite_priority, sprite_opaque) =
+                    if self.show_sprites && self.enabled_sprite_rendering() {
+                        let (pixel, priority, _) = self.shown_sprite_pixels.get(self.cycle as u8);
+                        (pixel as usize, priority as usize, (pixel & 3) > 0)
+                    } else {
+                        (0, 0, false)
+                    };
+
                 let calculate_winning_pixel =
                     |bg_opaque: usize, sprite_opaque: usize, priority: usize| -> usize {
                         let idx = bg_opaque | (sprite_opaque << 1);
@@ -392,36 +681,69 @@ impl Ppu {
                 let winning_page = (winning_pixel / 2) << 4;
 
                 let system_palette_index =
-                    self.get_frame_palette(winning_page | pixels[winning_pixel]) as usize;
-                // Copy range of rgb from system palette to video data; the alpha should always be
-                // 255 as the initial values in video memory are 255 and alpha is never touched
-                // again.
-                let tint = system_palette_index * 3;
-                let point = (self.scanline * 256 + self.cycle) * 4;
-                self.video_data[point..(point + 3)]
-                    .copy_from_slice(&self.system_palette_memory[tint..(tint + 3)]);
+                    self.get_frame_palette(winning_page | pixels[winning_pixel]);
+                let pixel = u16::from(system_palette_index & self.greyscale_mask())
+                    | self.emphasis_bits() << EMPHASIS_SHIFT;
+                let point = self.scanline * 256 + self.cycle;
+                self.pixel_data[point] = pixel;
             }
         }
 
         if self.is_begin_vblank_cycle() {
-            self.set_vblank_flag(true);
-            pins.nmi = !self.nmi_enabled();
+            // A $2002 read right as vblank starts keeps the flag from being set this frame
+            if !self.suppress_vblank {
+                self.set_vblank_flag(true);
+                self.nmi_delay = NMI_DELAY_DOTS;
+            }
+            self.suppress_vblank = false;
         }
 
         if self.is_end_vblank_cycle() {
             self.set_vblank_flag(false);
             self.clear_sprite_hit();
+            self.set_sprite_overflow(false);
             self.is_odd_frame = !self.is_odd_frame;
         }
 
-        pins.finished_frame = self.scanline == 261 && self.cycle == 340;
+        // Odd frames skip the last dot of the pre-render line while rendering
+        let skip_dot = self.is_pre_render_scanline()
+            && self.cycle == 339
+            && self.region.skips_odd_frame_dot()
+            && self.is_odd_frame
+            && self.is_rendering_enabled();
+        pins.finished_frame = self.is_pre_render_scanline() && (self.cycle == 340 || skip_dot);
         if pins.finished_frame && self.is_rendering_enabled() {
             self.vram_address = self.temp_address;
         }
 
+        // NMI is a level: low for as long as the vblank flag and NMI enable are both set, once
+        // the flag has been set long enough for a racing $2002 read to have cleared it
+        self.nmi_delay = self.nmi_delay.saturating_sub(1);
+        pins.nmi = !(self.get_vblank_flag() && self.nmi_enabled() && self.nmi_delay == 0);
+
+        // Changes to PPUMASK reach the rendering logic a dot later
+        let rendering_was_enabled = self.rendering_enabled;
+        self.rendering_enabled =
+            self.enabled_background_rendering() || self.enabled_sprite_rendering();
+        if self.oam_glitches() && rendering_was_enabled && !self.rendering_enabled {
+            self.flag_oam_corruption();
+        }
+
+        if self.scanline == 0 && self.cycle == 0 {
+            self.frame_colour_phase = self.colour_phase;
+        }
+        self.colour_phase = (self.colour_phase + COLOUR_PHASES_PER_DOT) % COLOUR_PHASES;
+
+        if skip_dot {
+            self.advance_dot();
+        }
+        self.advance_dot();
+    }
+
+    fn advance_dot(&mut self) {
         self.cycle = self.cycle.wrapping_add(1) % DOTS_PER_SCANLINE;
         if self.cycle == 0 {
-            self.scanline = self.scanline.wrapping_add(1) % SCANLINES_PER_FRAME;
+            self.scanline = self.scanline.wrapping_add(1) % self.region.scanlines_per_frame();
         }
     }
 
@@ -513,14 +835,16 @@ impl Ppu {
                     // have a read value before this. First msb fetch not until cycle 7
                     // INFO: This may have been solved by skipping the first dummy cycle that would
                     // have read the msb
-                    self.oam_pixel_buffer.set(
-                        sprite.x,
-                        sprite.tile as u8,
-                        data as u8,
-                        ((sprite.attrib & 0x20) > 0) as u8,
-                        sprite.attrib & 3,
-                        (sprite.attrib & 4) > 0,
-                    );
+                    let (lsb, msb) = (sprite.tile as u8, data as u8);
+                    let priority = ((sprite.attrib & 0x20) > 0) as u8;
+                    let sprite_0 = (sprite.attrib & 4) > 0;
+                    let palette = sprite.attrib & 3;
+                    self.oam_pixel_buffer
+                        .set(sprite.x, lsb, msb, priority, palette, sprite_0);
+                    if self.hidden_sprites & (1 << sprite.index) == 0 {
+                        self.shown_sprite_pixels
+                            .set(sprite.x, lsb, msb, priority, palette, sprite_0);
+                    }
                     // sprite.tile = (sprite.tile & 0x00FF) | (data << 8);
                     None
                 }
@@ -537,79 +861,287 @@ impl Ppu {
                 _ => None,
             }
         } else {
-            None
-        }
-    }
-
-    /// Each sprite is evaluated over a series of 3 cycles. During 2 of the cycles, the return will
-    /// be None. During the actual evaluation cycle, the return will be Some, but the internal
-    /// option will determine if the sprite collided with the line or was skipped.
-    /// Read in a sprite between oam_address_register and oam_address_register+3, then determine
-    /// which line of the sprite should be drawn by evaluating the scanline and attributes. If a
-    /// sprite is hidden off-screen, then it will skipped.
-    fn evaluate_sprite(&mut self) -> Option<Option<EvaluatedSprite>> {
-        // Starting from cycle 65, each sprite takes 3 cycles each
-        let c = (self.cycle - 65) % 3;
-        let s = (self.scanline) as u8;
-
-        // Evaluate only on the last cycle of each sprite,
-        // no need to perfectly emulate the evaluation in over multiple
-        // cycles as there is no outside bus interaction
-        // TODO: Check if oam[(sprite + offset) % 256] is necessary, and/or how to fix wrapping
-        // around the edge
-        if c == 2 {
-            let sprite = self.oam_address_register as usize;
-
-            let sprite_0_flag = u8::from(sprite == 0) << 2;
-            // byte 0 - y position of top of sprite
-            let y = self.oam_memory[sprite];
-            // byte 1 - tile index:
-            //  8x8 tile - 8-bit index + pattern table bit in control register
-            //  8x16 tile - 1 bit table + 7-bit (ignores the pattern bit in control register, uses
-            //  the bit in the byte). This works because the tiles are consecutive and byte 255 can
-            //  only be used in this case if the tile address is 254.
-            let tile = self.oam_memory[(sprite + 1) % 256];
-            // byte 2 - attributes
-            //  2-bit palette
-            //  3-bit unused
-            //  1-bit priority
-            //  1-bit flip horizontally
-            //  1-bit flip vertically
-            // Insert sprite-0 into unused field at bit 2
-            let attrib = self.oam_memory[(sprite + 2) % 256] | sprite_0_flag;
-            // byte 3 - left x position
-            let x = self.oam_memory[(sprite + 3) % 256];
-
-            let (table, tile, sprite_height) = if self.sprite_size() {
-                (tile & 1, tile >> 1, 16)
+            // Empty slots still fetch tile $FF, which cartridge hardware watching A12 relies on
+            let addr = if self.sprite_size() {
+                0x1FF0
             } else {
-                (self.sprite_pattern_table(), tile, 8)
+                (u16::from(self.sprite_pattern_table()) << 12) | 0x0FF0
             };
+            match c {
+                4 => Some(addr),
+                6 => Some(addr | 0x08),
+                _ => None,
+            }
+        }
+    }
 
-            let flip_vertically = (attrib & 0x80) > 0;
-            let y_s = s.wrapping_sub(y);
-            // TODO: find way to not need to compare y < 240
-            // Sprites stored off-screen at 255 are wrapping around in the comparison and comparing at 0
-            if y < 240 && y_s < sprite_height {
-                // we got a hit!
-                let y_t = if flip_vertically { 7 - y_s } else { y_s } as u16;
-                let tile = ((table as u16) << 12) | ((tile as u16) << 4) | y_t;
-                // Sprite can be drawn
-                return Some(Some(EvaluatedSprite { x, attrib, tile }));
+    /// One dot of sprite evaluation for the next scanline, dots 65-256.
+    ///
+    /// Odd dots read primary OAM at OAMADDR, even dots write to secondary OAM and step OAMADDR,
+    /// whose high 6 bits are the sprite being looked at (`n`) and low 2 bits the byte (`m`).
+    /// Evaluation starts wherever OAMADDR was left, so a misaligned address makes it treat other
+    /// bytes as Y. Once eight sprites have been found secondary OAM writes turn into reads, and
+    /// sprites that aren't in range step both `n` and `m`, so the overflow check reads tiles,
+    /// attributes and X positions as if they were Y.
+    fn evaluate_sprites(&mut self) {
+        if self.cycle == 65 {
+            self.secondary_oam_address = 0;
+            self.sprite_in_range = false;
+            self.sprite_0_in_range = false;
+            self.overflow_bytes = 0;
+            self.sprite_evaluation_done = false;
+        }
+        if !self.cycle.is_multiple_of(2) {
+            self.oam_latch = self.read_oam(self.oam_address_register);
+            return;
+        }
+
+        let mut n = self.oam_address_register >> 2;
+        let mut m = self.oam_address_register & 3;
+        if self.sprite_evaluation_done {
+            // Keeps trying, and failing, to copy the next sprite until hblank
+            n = (n + 1) & 0x3F;
+            if self.secondary_oam_address >= 32 {
+                self.oam_latch = self.secondary_oam[self.secondary_oam_address & 0x1F];
+            }
+        } else {
+            if !self.sprite_in_range && self.is_sprite_on_scanline(self.oam_latch) {
+                self.sprite_in_range = true;
+                if self.secondary_oam_address < 32 {
+                    self.secondary_oam_indices[self.secondary_oam_address / 4] = n;
+                }
+                if self.cycle == 66 {
+                    self.sprite_0_in_range = true;
+                }
+            }
+            if self.secondary_oam_address < 32 {
+                self.secondary_oam[self.secondary_oam_address] = self.oam_latch;
+                if self.sprite_in_range {
+                    m = (m + 1) & 3;
+                    self.secondary_oam_address += 1;
+                    if self.secondary_oam_address.is_multiple_of(4) {
+                        // All four bytes copied
+                        self.sprite_in_range = false;
+                        m = 0;
+                        n = (n + 1) & 0x3F;
+                        self.sprite_evaluation_done = n == 0;
+                    }
+                } else {
+                    n = (n + 1) & 0x3F;
+                    self.sprite_evaluation_done = n == 0;
+                }
             } else {
-                // Sprite was skipped
-                return Some(None);
+                self.oam_latch = self.secondary_oam[self.secondary_oam_address & 0x1F];
+                if self.sprite_in_range {
+                    self.set_sprite_overflow(true);
+                    m += 1;
+                    if m == 4 {
+                        m = 0;
+                        n = (n + 1) & 0x3F;
+                    }
+                    // Read the rest of the overflowing sprite, then stop looking
+                    if self.overflow_bytes == 0 {
+                        self.overflow_bytes = 3;
+                    } else {
+                        self.overflow_bytes -= 1;
+                        if self.overflow_bytes == 0 {
+                            self.sprite_evaluation_done = true;
+                            m = 0;
+                        }
+                    }
+                } else {
+                    // The hardware bug: m is incremented along with n
+                    n = (n + 1) & 0x3F;
+                    m = (m + 1) & 3;
+                    self.sprite_evaluation_done = n == 0;
+                }
+            }
+        }
+        self.oam_address_register = (n << 2) | m;
+    }
+
+    fn is_sprite_on_scanline(&self, y: u8) -> bool {
+        let height = if self.sprite_size() { 16 } else { 8 };
+        // Lines above the sprite wrap around and fail the comparison
+        self.scanline.wrapping_sub(usize::from(y)) < height
+    }
+
+    /// Decodes the sprites in secondary OAM for the fetches of dots 257-320. Nothing is evaluated
+    /// on the pre-render line, so sprites never show up on the first line of the picture.
+    fn load_sprites(&mut self) {
+        self.secondary_oam_buffer = [None; 8];
+        if self.scanline >= 240 {
+            return;
+        }
+        let count = self.secondary_oam_address / 4;
+        for slot in 0..count {
+            let bytes = &self.secondary_oam[slot * 4..slot * 4 + 4];
+            let sprite = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            let sprite_0 = slot == 0 && self.sprite_0_in_range;
+            self.secondary_oam_buffer[slot] =
+                Some(self.decode_sprite(sprite, self.secondary_oam_indices[slot], sprite_0));
+        }
+    }
+
+    /// The parts of `set_oam_glitches` that happen as the frame runs
+    fn oam_glitch(&mut self) {
+        // Rendered lines and the 2C07's refresh lines both go through every row
+        if self.is_oam_busy() && self.cycle == 0 {
+            self.oam_row_refreshed = [self.dots; 32];
+        }
+        if !self.is_rendering_enabled() {
+            return;
+        }
+
+        if self.corrupt_oam_rows != 0 && self.is_fetch_scanline() {
+            for row in 1..32 {
+                if self.corrupt_oam_rows & (1 << row) > 0 {
+                    self.oam_memory.copy_within(0..8, row * 8);
+                }
+            }
+            self.corrupt_oam_rows = 0;
+        }
+
+        if self.is_pre_render_scanline() && self.cycle == 0 && self.oam_address_register >= 8 {
+            let row = usize::from(self.oam_address_register & 0xF8);
+            self.oam_memory.copy_within(row..row + 8, 0);
+        }
+    }
+
+    /// Works out which row of OAM the ppu was addressing when rendering was turned off
+    fn flag_oam_corruption(&mut self) {
+        if !self.is_fetch_scanline() {
+            return;
+        }
+        let row = match self.cycle {
+            0..=63 => self.cycle / 2,
+            256..=319 => {
+                let relative_cycle = self.cycle - 256;
+                (relative_cycle / 8) * 4 + (relative_cycle % 8).min(3)
+            }
+            _ => return,
+        };
+        self.corrupt_oam_rows |= 1 << row;
+    }
+
+    fn read_oam(&mut self, address: u8) -> u8 {
+        self.refresh_oam_row(address);
+        self.oam_memory[address as usize]
+    }
+
+    fn write_oam(&mut self, address: u8, data: u8) {
+        self.refresh_oam_row(address);
+        self.oam_memory[address as usize] = data;
+    }
+
+    /// Accessing a row of OAM refreshes it, unless it's been left long enough to decay first
+    fn refresh_oam_row(&mut self, address: u8) {
+        let row = usize::from(address >> 3);
+        if self.oam_glitches() && self.dots - self.oam_row_refreshed[row] > OAM_DECAY_DOTS {
+            // What decayed DRAM settles to differs between consoles, $10 is a common pattern
+            self.oam_memory[row * 8..row * 8 + 8].fill(0x10);
+        }
+        self.oam_row_refreshed[row] = self.dots;
+    }
+
+    /// Finds the in range sprites after the 8 that evaluation kept, for when the sprite limit is
+    /// removed
+    fn load_extra_sprites(&mut self) {
+        self.extra_sprites.clear();
+        self.extra_sprites_fetched = 0;
+        if self.scanline >= 240 || self.secondary_oam_address / 4 < 8 {
+            return;
+        }
+        for index in 0..64 {
+            let bytes = &self.oam_memory[index * 4..index * 4 + 4];
+            let sprite = [bytes[0], bytes[1], bytes[2], bytes[3]];
+            if self.is_sprite_on_scanline(sprite[0]) {
+                self.extra_sprites
+                    .push(self.decode_sprite(sprite, index as u8, false));
+            }
+        }
+        // The first 8 are already in secondary OAM
+        self.extra_sprites.drain(..8.min(self.extra_sprites.len()));
+    }
+
+    /// Turns a sprite's four OAM bytes into its pattern row address for the current scanline and
+    /// the attributes the pixel buffer expects
+    fn decode_sprite(
+        &self,
+        [y, tile, attrib, x]: [u8; 4],
+        index: u8,
+        sprite_0: bool,
+    ) -> EvaluatedSprite {
+        let sprite_height: u8 = if self.sprite_size() { 16 } else { 8 };
+        let flip_vertically = (attrib & 0x80) > 0;
+        let mut row = (self.scanline as u8).wrapping_sub(y) & (sprite_height - 1);
+        if flip_vertically {
+            row = sprite_height - 1 - row;
+        }
+        // byte 1 - tile index:
+        //  8x8 tile - 8-bit index + pattern table bit in control register
+        //  8x16 tile - 1 bit table + 7-bit index of the top tile, the bottom tile follows it
+        let tile = if self.sprite_size() {
+            (u16::from(tile & 1) << 12)
+                | (u16::from(tile & 0xFE) << 4)
+                | (u16::from(row & 8) << 1)
+                | u16::from(row & 7)
+        } else {
+            (u16::from(self.sprite_pattern_table()) << 12) | (u16::from(tile) << 4) | u16::from(row)
+        };
+        // byte 2 - attributes
+        //  2-bit palette
+        //  3-bit unused
+        //  1-bit priority
+        //  1-bit flip horizontally
+        //  1-bit flip vertically
+        // Insert sprite-0 into unused field at bit 2
+        let attrib = (attrib & 0xE3) | (u8::from(sprite_0) << 2);
+        EvaluatedSprite {
+            x,
+            attrib,
+            index,
+            tile,
+        }
+    }
+
+    /// Asks the board for the next extra sprite's pattern row, then once they're all in and the
+    /// 8 evaluated sprites are in the pixel buffers, draws the extras behind them
+    fn fetch_extra_sprites(&mut self, pins: &mut PpuPinout) {
+        if self.extra_sprites_fetched < self.extra_sprites.len() && self.cycle >= 261 {
+            pins.extra_sprite_address = Some(self.extra_sprites[self.extra_sprites_fetched].tile);
+        } else if self.cycle == 322 {
+            for sprite in &self.extra_sprites[..self.extra_sprites_fetched] {
+                let [mut lsb, mut msb] = sprite.tile.to_le_bytes();
+                if (sprite.attrib & 0x40) > 0 {
+                    lsb = lsb.reverse_bits();
+                    msb = msb.reverse_bits();
+                }
+                let priority = ((sprite.attrib & 0x20) > 0) as u8;
+                let palette = sprite.attrib & 3;
+                // Only drawn, so hits and priority are decided by the 8 real sprites alone
+                if self.hidden_sprites & (1 << sprite.index) == 0 {
+                    self.shown_sprite_pixels
+                        .set(sprite.x, lsb, msb, priority, palette, false);
+                }
             }
         }
-        // Not a valid cycle
-        None
     }
 
     fn handle_cpu_io(&mut self, pins: &mut PpuPinout) {
-        match pins.cpu_addr {
+        if !pins.cpu_rw {
+            // Writes to any register fill the whole latch
+            self.drive_io_latch(pins.cpu_data, 0xFF);
+        }
+        let register = if self.variant.swaps_control_and_mask() && pins.cpu_addr < 2 {
+            pins.cpu_addr ^ 1
+        } else {
+            pins.cpu_addr
+        };
+        match register {
             0 => {
                 if pins.cpu_rw {
-                    // pins.cpu_data = self.internal_read_buffer;
+                    pins.cpu_data = self.io_latch();
                 } else {
                     // going to assume write is intended.. for now
                     self.control_register = pins.cpu_data;
@@ -619,35 +1151,63 @@ impl Ppu {
             }
             1 => {
                 if pins.cpu_rw {
-                    // pins.cpu_data = self.internal_read_buffer;
+                    pins.cpu_data = self.io_latch();
                 } else {
                     // going to assume write is intended.. for now
                     self.mask_register = pins.cpu_data;
                 }
             }
             2 => {
-                pins.cpu_data = (pins.cpu_data & 0b00011111) | (self.status_register & 0b11100000);
+                let mut status = self.status_register;
+                if self.scanline == self.region.vblank_scanline() && self.cycle == 0 {
+                    // One dot early: reads clear and the flag never gets set
+                    self.suppress_vblank = true;
+                } else if self.is_begin_vblank_cycle() {
+                    // The same dot: reads set, but is cleared before it can raise NMI
+                    status |= 0b10000000;
+                    self.suppress_vblank = true;
+                }
+                pins.cpu_data = match self.variant.status_id() {
+                    Some((id, mask)) => {
+                        self.drive_io_latch((status & !mask) | id, 0b11100000 | mask)
+                    }
+                    None => self.drive_io_latch(status, 0b11100000),
+                };
                 self.set_vblank_flag(false);
                 self.w_register = false;
             }
             3 => {
                 if pins.cpu_rw {
-                    // pins.cpu_data = self.internal_read_buffer;
+                    pins.cpu_data = self.io_latch();
                 } else {
                     self.oam_address_register = pins.cpu_data;
                 }
             }
             4 => {
                 if pins.cpu_rw {
-                    pins.cpu_data = self.oam_memory[self.oam_address_register as usize];
+                    let data = if self.is_oam_busy() {
+                        self.oam_latch
+                    } else {
+                        self.read_oam(self.oam_address_register)
+                    };
+                    pins.cpu_data = self.drive_io_latch(data, 0xFF);
+                } else if self.is_oam_busy() {
+                    // Writes during rendering don't reach OAM, but bump the sprite half of OAMADDR
+                    self.oam_address_register = self.oam_address_register.wrapping_add(4);
                 } else {
-                    self.oam_memory[self.oam_address_register as usize] = pins.cpu_data;
+                    // Bits 2-4 of the attribute byte don't exist in OAM
+                    let data = if self.oam_address_register & 3 == 2 {
+                        pins.cpu_data & 0xE3
+                    } else {
+                        pins.cpu_data
+                    };
+                    self.write_oam(self.oam_address_register, data);
                     self.oam_address_register = self.oam_address_register.wrapping_add(1);
                 }
             }
             5 => {
                 if pins.cpu_rw {
-                    // pins.cpu_data = self.internal_read_buffer;
+                    pins.cpu_data = self.io_latch();
                 } else {
                     if !self.w_register {
                         self.set_fine_x(pins.cpu_data & 0x07);
@@ -661,7 +1221,7 @@ impl Ppu {
             }
             6 => {
                 if pins.cpu_rw {
-                    // pins.cpu_data = self.internal_read_buffer;
+                    pins.cpu_data = self.io_latch();
                 } else {
                     if !self.w_register {
                         self.temp_address = ((pins.cpu_data & 0x3F) as u16) << 8;
@@ -675,11 +1235,16 @@ impl Ppu {
             _ => {
                 // assume 7
                 if pins.cpu_rw {
+                    // Palette reads still go out on the bus, where $3Fxx mirrors the nametables
+                    // at $2Fxx, so the buffer picks up the nametable byte underneath
                     pins.cpu_data = if (0x3F00..=0x3FFF).contains(&self.vram_address) {
+                        // Palette entries are 6 bits, the top 2 come from the latch
                         let palette_address = (self.vram_address - 0x3F00) % 0x20;
-                        self.get_frame_palette(palette_address as usize)
+                        let colour = self.get_frame_palette(palette_address as usize)
+                            & self.greyscale_mask();
+                        self.drive_io_latch(colour, 0x3F)
                     } else {
-                        self.internal_read_buffer
+                        self.drive_io_latch(self.internal_read_buffer, 0xFF)
                     };
                     // tell ppu to read from addr
                     self.vram_manip = VRamManip::Read;
@@ -692,17 +1257,48 @@ impl Ppu {
                         self.vram_data = pins.cpu_data;
                         self.vram_manip = VRamManip::Write;
                     };
-                    // Attempt to emulate open-bus
-                    pins.cpu_data = self.internal_read_buffer;
                 }
                 pins.ppu_address_high = (self.vram_address >> 8) as u8;
                 pins.ppu_address_data_low = self.vram_address as u8;
                 pins.ppu_ale = true;
-                self.vram_address = self
-                    .vram_address
-                    .wrapping_add(self.vram_address_increment() as u16);
+                if self.is_rendering_enabled() && self.is_fetch_scanline() {
+                    // The access is taken as a rendering increment, bumping coarse X and Y at once
+                    self.increment_x();
+                    self.increment_y();
+                } else {
+                    self.vram_address = self
+                        .vram_address
+                        .wrapping_add(self.vram_address_increment() as u16);
+                }
+            }
+        }
+    }
+
+    /// The I/O latch once any bits that haven't been driven for a while have decayed to 0
+    fn io_latch(&mut self) -> u8 {
+        for (bit, refreshed) in self.io_latch_refreshed.iter().enumerate() {
+            if self.dots - refreshed > self.io_latch_decay_dots() {
+                self.io_latch &= !(1 << bit);
             }
         }
+        self.io_latch
+    }
+
+    /// `IO_LATCH_DECAY_SECONDS` in dots of the current region
+    fn io_latch_decay_dots(&self) -> u64 {
+        (IO_LATCH_DECAY_SECONDS * self.region.ppu_clock_rate()) as u64
+    }
+
+    /// Drives the bits of `mask` with `value`, refreshing their decay, and returns what the cpu
+    /// sees: the driven bits along with whatever the rest of the latch still holds
+    fn drive_io_latch(&mut self, value: u8, mask: u8) -> u8 {
+        self.io_latch = (self.io_latch() & !mask) | (value & mask);
+        for (bit, refreshed) in self.io_latch_refreshed.iter_mut().enumerate() {
+            if mask & (1 << bit) > 0 {
+                *refreshed = self.dots;
+            }
+        }
+        self.io_latch
     }
 
     // -- Rendering helper functions --
@@ -734,8 +1330,12 @@ impl Ppu {
         }
     }
 
+    fn is_pre_render_scanline(&self) -> bool {
+        self.scanline == self.region.scanlines_per_frame() - 1
+    }
+
     fn is_fetch_scanline(&self) -> bool {
-        (0..240).contains(&self.scanline) || self.scanline == 261
+        (0..240).contains(&self.scanline) || self.is_pre_render_scanline()
     }
 
     // -- Rendering timing functions --
@@ -748,8 +1348,20 @@ impl Ppu {
         (0..240).contains(&self.scanline) && (0..256).contains(&self.cycle)
     }
 
+    fn is_secondary_oam_clear_cycle(&self) -> bool {
+        (1..=64).contains(&self.cycle)
+    }
+
     fn is_sprite_evaluation_cycle(&self) -> bool {
-        (65..=257).contains(&self.cycle)
+        (65..=256).contains(&self.cycle)
+    }
+
+    /// OAM is being used by sprite evaluation and fetching, or the 2C07's refresh, so the cpu
+    /// can't access it
+    fn is_oam_busy(&self) -> bool {
+        let refreshing_oam =
+            self.region == Region::Pal && PAL_OAM_REFRESH_SCANLINES.contains(&self.scanline);
+        (self.is_rendering_enabled() && self.scanline < 240) || refreshing_oam
     }
 
     /// This starts at cycle 258 to account for the fact that the MSB byte is *not* addressed
@@ -759,15 +1371,15 @@ impl Ppu {
     }
 
     fn is_begin_vblank_cycle(&self) -> bool {
-        self.cycle == 1 && self.scanline == 241
+        self.cycle == 1 && self.scanline == self.region.vblank_scanline()
     }
 
     fn is_end_vblank_cycle(&self) -> bool {
-        self.cycle == 1 && self.scanline == 261
+        self.cycle == 1 && self.is_pre_render_scanline()
     }
 
     fn is_rendering_enabled(&self) -> bool {
-        self.enabled_background_rendering() || self.enabled_sprite_rendering()
+        self.rendering_enabled
     }
 
     // -- loopy address helpers --
@@ -834,14 +1446,17 @@ impl Ppu {
     fn enabled_sprite_rendering(&self) -> bool {
         (self.mask_register & 0b10000) > 0
     }
-    fn emphasize_red(&self) -> bool {
-        (self.mask_register & 0b100000) > 0
-    }
-    fn emphasize_green(&self) -> bool {
-        (self.mask_register & 0b1000000) > 0
+    /// Greyscale keeps only the luma column of the palette
+    fn greyscale_mask(&self) -> u8 {
+        if self.greyscale() {
+            0x30
+        } else {
+            0x3F
+        }
     }
-    fn emphasize_blue(&self) -> bool {
-        (self.mask_register & 0b10000000) > 0
+    /// PPUMASK bits 5-7, which colour each emphasizes is up to the palette
+    fn emphasis_bits(&self) -> u16 {
+        u16::from(self.mask_register >> 5)
     }
 
     fn set_sprite_overflow(&mut self, status: bool) {
@@ -856,6 +1471,9 @@ impl Ppu {
     fn clear_sprite_hit(&mut self) {
         self.status_register &= 0b10111111
     }
+    fn get_vblank_flag(&self) -> bool {
+        (self.status_register & 0b10000000) > 0
+    }
     fn set_vblank_flag(&mut self, status: bool) {
         self.status_register = (self.status_register & 0b01111111) | ((status as u8) << 7)
     }
@@ -877,3 +1495,374 @@ impl Default for Ppu {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{Ppu, PpuBusAccess, PpuPinout, PpuStatus, OAM_DECAY_DOTS};
+    use crate::region::Region;
+
+    /// A ppu with pattern tables and nametables on its bus, $3000-$3FFF mirroring $2000-$2FFF
+    struct TestBus {
+        ppu: Ppu,
+        pins: PpuPinout,
+        memory: Vec<u8>,
+    }
+
+    impl TestBus {
+        fn new() -> Self {
+            Self {
+                ppu: Ppu::new(),
+                pins: PpuPinout {
+                    nmi: true,
+                    cpu_control: false,
+                    cpu_rw: true,
+                    cpu_addr: 0,
+                    cpu_data: 0,
+                    ppu_address_data_low: 0,
+                    ppu_address_high: 0,
+                    ppu_r: false,
+                    ppu_w: false,
+                    ppu_sync: false,
+                    ppu_ale: false,
+                    finished_frame: false,
+                    extra_sprite_address: None,
+                    extra_sprite_data: [0; 2],
+                },
+                memory: vec![0; 0x3000],
+            }
+        }
+
+        fn clock(&mut self) {
+            self.ppu.clock(&mut self.pins);
+            self.pins.cpu_control = false;
+            if let Some(event) = self.ppu.bus_event(&self.pins) {
+                let address = usize::from(event.address);
+                let address = if address >= 0x3000 {
+                    address - 0x1000
+                } else {
+                    address
+                };
+                match event.access {
+                    PpuBusAccess::Read => self.pins.ppu_address_data_low = self.memory[address],
+                    PpuBusAccess::Write => self.memory[address] = self.pins.ppu_address_data_low,
+                    PpuBusAccess::Address => {}
+                }
+            }
+        }
+
+        /// Accesses a register and clocks out the rest of the cpu cycle
+        fn access(&mut self, register: u8, rw: bool, data: u8) -> u8 {
+            self.pins.cpu_control = true;
+            self.pins.cpu_rw = rw;
+            self.pins.cpu_addr = register;
+            self.pins.cpu_data = data;
+            self.clock();
+            let data = self.pins.cpu_data;
+            self.clock();
+            self.clock();
+            data
+        }
+
+        fn write(&mut self, register: u8, data: u8) {
+            self.access(register, false, data);
+        }
+
+        fn read(&mut self, register: u8) -> u8 {
+            self.access(register, true, 0)
+        }
+
+        fn set_vram_address(&mut self, address: u16) {
+            self.write(6, (address >> 8) as u8);
+            self.write(6, address as u8);
+        }
+
+        fn vram_address(&self) -> u16 {
+            self.ppu.state().vram_address
+        }
+
+        fn run_to(&mut self, scanline: usize, dot: usize) {
+            while self.ppu.scanline() != scanline || self.ppu.dot() != dot {
+                self.clock();
+            }
+        }
+
+        /// Turns on rendering during vblank and runs to `dot` of `scanline`
+        fn render_to(&mut self, scanline: usize, dot: usize) {
+            self.run_to(241, 10);
+            self.write(1, 0x18);
+            self.run_to(scanline, dot);
+        }
+
+        /// Runs to `dot` of `scanline`, returning whether NMI was pulled low on the way
+        fn nmi_before(&mut self, scanline: usize, dot: usize) -> bool {
+            let mut nmi = false;
+            while self.ppu.scanline() != scanline || self.ppu.dot() != dot {
+                self.clock();
+                nmi |= !self.pins.nmi;
+            }
+            nmi
+        }
+
+        /// Dots from the start of one frame to the start of the next
+        fn frame_length(&mut self) -> usize {
+            self.run_to(0, 0);
+            let mut dots = 0;
+            loop {
+                self.clock();
+                dots += 1;
+                if self.ppu.scanline() == 0 && self.ppu.dot() == 0 {
+                    return dots;
+                }
+            }
+        }
+
+        /// Fills OAM with sprites below the picture, then places `sprites` from OAM address 0
+        fn load_oam(&mut self, sprites: &[[u8; 4]]) {
+            self.write(3, 0);
+            for index in 0..64 {
+                let sprite = sprites.get(index).copied().unwrap_or([0xFF; 4]);
+                for byte in sprite {
+                    self.write(4, byte);
+                }
+            }
+        }
+
+        /// Runs until sprite 0 hit is set, returning the scanline and dot it was set on, or None
+        /// if the picture ends first
+        fn sprite_0_hit(&mut self) -> Option<(usize, usize)> {
+            while !self.ppu.status().contains(PpuStatus::SPRITE_0_HIT) {
+                if self.ppu.scanline() == 240 {
+                    return None;
+                }
+                self.clock();
+            }
+            Some((self.ppu.scanline(), self.ppu.dot()))
+        }
+
+        /// Reads the 8 bytes of OAM `row` through $2004
+        fn read_oam_row(&mut self, row: u8) -> [u8; 8] {
+            std::array::from_fn(|offset| {
+                self.write(3, row * 8 + offset as u8);
+                self.read(4)
+            })
+        }
+    }
+
+    #[test]
+    fn vram_access_outside_rendering_increments_by_ppuctrl() {
+        let mut bus = TestBus::new();
+        bus.render_to(245, 0);
+        bus.set_vram_address(0x2000);
+        bus.read(7);
+        assert_eq!(bus.vram_address(), 0x2001);
+        bus.write(0, 0x04);
+        bus.write(7, 0x55);
+        assert_eq!(bus.vram_address(), 0x2021);
+    }
+
+    #[test]
+    fn vram_access_during_rendering_increments_coarse_x_and_y() {
+        let mut bus = TestBus::new();
+        // Dots 258-320 leave v alone, so only the access moves it
+        bus.render_to(10, 270);
+        // Fine Y 2, coarse Y 5, coarse X 3
+        bus.set_vram_address(0x20A3);
+        bus.read(7);
+        assert_eq!(bus.vram_address(), 0x30A4);
+        // The increments carry into the nametable bits like rendering's do
+        bus.set_vram_address(0x33BF);
+        bus.write(7, 0x55);
+        assert_eq!(bus.vram_address(), 0x47A0);
+    }
+
+    #[test]
+    fn vram_access_during_rendering_wraps_coarse_y() {
+        let mut bus = TestBus::new();
+        bus.render_to(100, 270);
+        // Fine Y 3, coarse Y 29
+        bus.set_vram_address(0x33A0);
+        for _ in 0..5 {
+            bus.read(7);
+        }
+        assert_eq!(bus.vram_address(), 0x0805);
+    }
+
+    #[test]
+    fn palette_read_buffers_the_nametable_underneath() {
+        let mut bus = TestBus::new();
+        bus.memory[0x2F05] = 0xAB;
+        bus.set_vram_address(0x3F05);
+        bus.write(7, 0x21);
+        bus.set_vram_address(0x3F05);
+        assert_eq!(bus.read(7) & 0x3F, 0x21);
+        bus.set_vram_address(0x2000);
+        assert_eq!(bus.read(7), 0xAB);
+    }
+
+    #[test]
+    fn status_read_before_vblank_suppresses_flag_and_nmi() {
+        let mut bus = TestBus::new();
+        bus.write(0, 0x80);
+        bus.run_to(241, 0);
+        assert_eq!(bus.read(2) & 0x80, 0);
+        assert!(!bus.nmi_before(250, 0));
+        assert!(!bus.ppu.status().contains(PpuStatus::VBLANK));
+
+        // Without the racing read the next frame sets both
+        bus.run_to(240, 0);
+        assert!(bus.nmi_before(250, 0));
+        assert!(bus.ppu.status().contains(PpuStatus::VBLANK));
+    }
+
+    #[test]
+    fn odd_frames_are_short_only_while_rendering() {
+        let mut bus = TestBus::new();
+        assert_eq!([bus.frame_length(), bus.frame_length()], [89342, 89342]);
+
+        bus.render_to(0, 0);
+        let mut lengths = [bus.frame_length(), bus.frame_length()];
+        lengths.sort();
+        assert_eq!(lengths, [89341, 89342]);
+    }
+
+    #[test]
+    fn ninth_sprite_sets_overflow() {
+        let mut bus = TestBus::new();
+        bus.render_to(245, 0);
+        bus.load_oam(&[[40, 0, 0, 0]; 9]);
+        bus.run_to(40, 0);
+        assert!(!bus.ppu.status().contains(PpuStatus::SPRITE_OVERFLOW));
+        bus.run_to(41, 0);
+        assert!(bus.ppu.status().contains(PpuStatus::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn overflow_check_misses_sprites_after_a_miss() {
+        let mut bus = TestBus::new();
+        bus.render_to(245, 0);
+        // After sprite 8 misses, m steps along with n and sprite 9's tile is compared as Y
+        let mut sprites = [[40, 0, 0, 0]; 10];
+        sprites[8] = [0xFF; 4];
+        sprites[9] = [40, 0xFF, 0, 0];
+        bus.load_oam(&sprites);
+        bus.run_to(41, 0);
+        assert!(!bus.ppu.status().contains(PpuStatus::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn overflow_check_finds_sprites_that_are_not_there() {
+        let mut bus = TestBus::new();
+        bus.render_to(245, 0);
+        // Sprite 9 is off screen, but its tile number is in range of the line
+        let mut sprites = [[40, 0, 0, 0]; 10];
+        sprites[8] = [0xFF; 4];
+        sprites[9] = [0xFF, 36, 0, 0];
+        bus.load_oam(&sprites);
+        bus.run_to(41, 0);
+        assert!(bus.ppu.status().contains(PpuStatus::SPRITE_OVERFLOW));
+    }
+
+    #[test]
+    fn io_latch_decays_unless_refreshed() {
+        let mut bus = TestBus::new();
+        let decay = bus.ppu.io_latch_decay_dots();
+        bus.write(3, 0xA5);
+        assert_eq!(bus.read(0), 0xA5);
+
+        // A write half way through starts the decay over
+        for _ in 0..decay / 2 {
+            bus.clock();
+        }
+        bus.write(3, 0x5A);
+        for _ in 0..decay / 2 + 10 {
+            bus.clock();
+        }
+        assert_eq!(bus.read(0), 0x5A);
+
+        for _ in 0..decay / 2 {
+            bus.clock();
+        }
+        assert_eq!(bus.read(0), 0);
+    }
+
+    #[test]
+    fn disabling_rendering_mid_line_corrupts_an_oam_row() {
+        let mut bus = TestBus::new();
+        bus.render_to(245, 0);
+        let sprites: Vec<[u8; 4]> = (0..64).map(|n| [n; 4]).collect();
+        bus.load_oam(&sprites);
+        let [first_row, row_20, row_21] = [0, 20, 21].map(|row| bus.read_oam_row(row));
+        assert_ne!(first_row, row_20);
+        // Left pointing past the first row, OAMADDR would have it overwritten as rendering starts
+        bus.write(3, 0);
+
+        // Dot 40 of a rendered line is evaluation clearing secondary OAM, addressing row 20
+        bus.run_to(20, 40);
+        bus.write(1, 0x00);
+        bus.run_to(20, 100);
+        bus.write(1, 0x18);
+        bus.run_to(245, 0);
+        assert_eq!(bus.read_oam_row(20), first_row);
+        assert_eq!(bus.read_oam_row(21), row_21);
+    }
+
+    #[test]
+    fn oam_decays_without_rendering_only_with_glitches_on() {
+        for glitches in [true, false] {
+            let mut bus = TestBus::new();
+            bus.ppu.set_oam_glitches(glitches);
+            bus.load_oam(&[[0x44; 4]; 64]);
+            let row = bus.read_oam_row(5);
+            assert_eq!(row, [0x44, 0x44, 0x40, 0x44, 0x44, 0x44, 0x40, 0x44]);
+
+            for _ in 0..OAM_DECAY_DOTS + 1 {
+                bus.clock();
+            }
+            let decayed = if glitches { [0x10; 8] } else { row };
+            assert_eq!(bus.read_oam_row(5), decayed);
+        }
+    }
+
+    #[test]
+    fn hidden_layers_still_hit_sprite_0() {
+        let hit = |background: bool, sprite_0: bool| {
+            let mut bus = TestBus::new();
+            // Tile 0 is solid for both the background and sprites
+            bus.memory[..16].fill(0xFF);
+            bus.ppu.set_background_visible(background);
+            bus.ppu.set_sprite_visible(0, sprite_0);
+            bus.render_to(245, 0);
+            bus.load_oam(&[[30, 0, 0, 50]]);
+            bus.sprite_0_hit()
+        };
+        let shown = hit(true, true).unwrap();
+        // Sprites are drawn a line below their Y
+        assert_eq!(shown.0, 31);
+        assert_eq!(hit(false, false), Some(shown));
+    }
+
+    #[test]
+    fn sprite_indices_past_63_are_ignored() {
+        let mut ppu = Ppu::new();
+        ppu.set_sprite_visible(64, false);
+        ppu.set_sprite_visible(255, false);
+        assert!(ppu.sprite_visible(64));
+        assert_eq!(ppu.hidden_sprites, 0);
+        ppu.set_sprite_visible(63, false);
+        assert!(!ppu.sprite_visible(63));
+    }
+
+    #[test]
+    fn region_only_sets_the_default_oam_glitches() {
+        let mut ppu = Ppu::new();
+        assert!(ppu.oam_glitches());
+        ppu.set_region(Region::Pal);
+        assert!(!ppu.oam_glitches());
+        ppu.set_oam_glitches(true);
+        ppu.set_region(Region::Dendy);
+        assert!(ppu.oam_glitches());
+        ppu.set_oam_glitches(false);
+        ppu.set_region(Region::Ntsc);
+        assert!(!ppu.oam_glitches());
+    }
+}
@@ -0,0 +1,149 @@
+use std::fmt;
+
+use bitflags::{bitflags, Flags};
+
+use super::Ppu;
+
+bitflags! {
+    /// $2000
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PpuCtrl: u8 {
+        const NAMETABLE_X = 0b00000001;
+        const NAMETABLE_Y = 0b00000010;
+        const INCREMENT_32 = 0b00000100;
+        const SPRITE_TABLE = 0b00001000;
+        const BACKGROUND_TABLE = 0b00010000;
+        const SPRITE_SIZE_16 = 0b00100000;
+        const MASTER_SLAVE = 0b01000000;
+        const NMI_ENABLE = 0b10000000;
+    }
+}
+
+bitflags! {
+    /// $2001. The 2C07 and Dendy ppus emphasize green with bit 5 and red with bit 6; the names
+    /// follow the 2C02.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PpuMask: u8 {
+        const GREYSCALE = 0b00000001;
+        const SHOW_BACKGROUND_LEFT = 0b00000010;
+        const SHOW_SPRITES_LEFT = 0b00000100;
+        const SHOW_BACKGROUND = 0b00001000;
+        const SHOW_SPRITES = 0b00010000;
+        const EMPHASIZE_RED = 0b00100000;
+        const EMPHASIZE_GREEN = 0b01000000;
+        const EMPHASIZE_BLUE = 0b10000000;
+    }
+}
+
+bitflags! {
+    /// $2002, without the low bits a read fills from the I/O latch
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct PpuStatus: u8 {
+        const SPRITE_OVERFLOW = 0b00100000;
+        const SPRITE_0_HIT = 0b01000000;
+        const VBLANK = 0b10000000;
+    }
+}
+
+/// A copy of the ppu's internal state taken by `Ppu::state`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PpuState {
+    pub control: PpuCtrl,
+    pub mask: PpuMask,
+    pub status: PpuStatus,
+    pub oam_address: u8,
+    /// Loopy v, the current vram address
+    pub vram_address: u16,
+    /// Loopy t, the address v is reloaded from
+    pub temp_address: u16,
+    /// Loopy x
+    pub fine_x_scroll: u8,
+    /// Loopy w, set after the first write to $2005 or $2006
+    pub write_toggle: bool,
+    pub scanline: usize,
+    pub dot: usize,
+    pub odd_frame: bool,
+    /// What the next $2007 read returns below the palette
+    pub read_buffer: u8,
+    pub secondary_oam: [u8; 32],
+    /// Background shift registers, the pixel being output at the top bit
+    pub pattern_low_shifter: u16,
+    pub pattern_high_shifter: u16,
+    pub attribute_low_shifter: u16,
+    pub attribute_high_shifter: u16,
+}
+
+impl Ppu {
+    pub fn state(&self) -> PpuState {
+        PpuState {
+            control: PpuCtrl::from_bits_retain(self.control_register),
+            mask: PpuMask::from_bits_retain(self.mask_register),
+            status: PpuStatus::from_bits_truncate(self.status_register),
+            oam_address: self.oam_address_register,
+            vram_address: self.vram_address,
+            temp_address: self.temp_address,
+            fine_x_scroll: self.fine_x_scroll,
+            write_toggle: self.w_register,
+            scanline: self.scanline,
+            dot: self.cycle,
+            odd_frame: self.is_odd_frame,
+            read_buffer: self.internal_read_buffer,
+            secondary_oam: self.secondary_oam,
+            pattern_low_shifter: self.tile_lsb_scroll.0,
+            pattern_high_shifter: self.tile_msb_scroll.0,
+            attribute_low_shifter: self.attribute_lsb_scroll.0,
+            attribute_high_shifter: self.attribute_msb_scroll.0,
+        }
+    }
+}
+
+fn write_flags(
+    f: &mut fmt::Formatter<'_>,
+    name: &str,
+    flags: impl Flags<Bits = u8>,
+) -> fmt::Result {
+    write!(f, "{name}: ${:0>2X}", flags.bits())?;
+    for (i, (flag, _)) in flags.iter_names().enumerate() {
+        write!(f, "{}{flag}", if i == 0 { " " } else { " | " })?;
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for PpuState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_flags(f, "PPUCTRL", self.control)?;
+        write_flags(f, "PPUMASK", self.mask)?;
+        write_flags(f, "PPUSTATUS", self.status)?;
+        writeln!(f, "OAMADDR: ${:0>2X}", self.oam_address)?;
+        writeln!(
+            f,
+            "v: ${:0>4X} t: ${:0>4X} x: {} w: {}",
+            self.vram_address, self.temp_address, self.fine_x_scroll, self.write_toggle
+        )?;
+        writeln!(
+            f,
+            "scanline: {} dot: {} odd frame: {}",
+            self.scanline, self.dot, self.odd_frame
+        )?;
+        writeln!(f, "read buffer: ${:0>2X}", self.read_buffer)?;
+        writeln!(
+            f,
+            "pattern shifters: {:0>16b} {:0>16b}",
+            self.pattern_high_shifter, self.pattern_low_shifter
+        )?;
+        writeln!(
+            f,
+            "attribute shifters: {:0>16b} {:0>16b}",
+            self.attribute_high_shifter, self.attribute_low_shifter
+        )?;
+        write!(f, "secondary OAM:")?;
+        for sprite in self.secondary_oam.chunks_exact(4) {
+            write!(
+                f,
+                " {:0>2X}{:0>2X}{:0>2X}{:0>2X}",
+                sprite[0], sprite[1], sprite[2], sprite[3]
+            )?;
+        }
+        Ok(())
+    }
+}
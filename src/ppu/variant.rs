@@ -0,0 +1,63 @@
+use crate::video::palette::{Palette, PaletteGenerator};
+
+/// The chip behind the ppu interface. Region timing is chosen separately with `Region`; every
+/// RGB variant runs at NTSC timing.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum PpuVariant {
+    /// The composite video ppus: 2C02, 2C07 and the Dendy clones
+    #[default]
+    Rp2c02,
+    /// RP2C03B/G and RC2C03B/C, the RGB ppus of the PlayChoice-10 and some Vs. System games
+    Rp2c03,
+    /// RP2C04-0001 to -0004, each with its own scrambled palette to deter swapping boards
+    Rp2c04(u8),
+    /// RC2C05-01 to -05, which swap $2000 with $2001 and identify themselves through $2002
+    Rc2c05(u8),
+}
+
+impl PpuVariant {
+    /// Decodes the Vs. System ppu type from byte 13 of an NES 2.0 header
+    pub fn from_vs_ppu_type(ppu_type: u8) -> Option<Self> {
+        match ppu_type {
+            0x0 | 0x1 | 0x6 | 0x7 => Some(PpuVariant::Rp2c03),
+            0x2..=0x5 => Some(PpuVariant::Rp2c04(ppu_type - 1)),
+            0x8..=0xC => Some(PpuVariant::Rc2c05(ppu_type - 7)),
+            _ => None,
+        }
+    }
+
+    /// Outputs RGB instead of a composite signal, so emphasis turns channels fully on instead of
+    /// darkening the others
+    pub fn is_rgb(self) -> bool {
+        self != PpuVariant::Rp2c02
+    }
+
+    /// The 2C05 decodes PPUCTRL at $2001 and PPUMASK at $2000
+    pub fn swaps_control_and_mask(self) -> bool {
+        matches!(self, PpuVariant::Rc2c05(_))
+    }
+
+    /// Value and mask of the bits the 2C05 drives in the low part of PPUSTATUS in place of the
+    /// I/O latch. The -05's value isn't known.
+    pub fn status_id(self) -> Option<(u8, u8)> {
+        match self {
+            PpuVariant::Rc2c05(1) | PpuVariant::Rc2c05(4) => Some((0x1B, 0x1F)),
+            // Also forces the sprite overflow bit on
+            PpuVariant::Rc2c05(2) => Some((0x3D, 0x3F)),
+            PpuVariant::Rc2c05(3) => Some((0x1C, 0x1F)),
+            _ => None,
+        }
+    }
+
+    /// The colours the variant outputs. Unknown 2C04 revisions get the 2C03's.
+    pub fn palette(self) -> Palette {
+        match self {
+            PpuVariant::Rp2c02 => PaletteGenerator::new().generate(),
+            PpuVariant::Rp2c04(revision) => {
+                Palette::rp2c04(revision).unwrap_or_else(Palette::rp2c03)
+            }
+            PpuVariant::Rp2c03 | PpuVariant::Rc2c05(_) => Palette::rp2c03(),
+        }
+    }
+}
+
@@ -0,0 +1,70 @@
+/// The console variant being emulated, which sets the speed of every chip and the shape of the
+/// ppu's frame
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Region {
+    /// RP2A03 and RP2C02
+    Ntsc,
+    /// RP2A07 and RP2C07
+    Pal,
+    /// UA6527P and UA6538, the PAL famiclone most widely sold in Russia
+    Dendy,
+}
+
+impl Region {
+    /// Frequency of the crystal every other clock is divided down from, in Hz
+    pub fn master_clock_rate(self) -> f64 {
+        match self {
+            Region::Ntsc => 21_477_272.0,
+            Region::Pal | Region::Dendy => 26_601_712.0,
+        }
+    }
+
+    /// Master clocks per cpu cycle
+    pub fn cpu_divider(self) -> u32 {
+        match self {
+            Region::Ntsc => 12,
+            Region::Pal => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    /// Master clocks per ppu dot
+    pub fn ppu_divider(self) -> u32 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    /// Rate of the cpu, which also clocks the apu
+    pub fn cpu_clock_rate(self) -> f64 {
+        self.master_clock_rate() / f64::from(self.cpu_divider())
+    }
+
+    pub fn ppu_clock_rate(self) -> f64 {
+        self.master_clock_rate() / f64::from(self.ppu_divider())
+    }
+
+    /// Scanlines per frame including vblank and the pre-render line
+    pub fn scanlines_per_frame(self) -> usize {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// Scanline whose second dot sets the vblank flag
+    pub fn vblank_scanline(self) -> usize {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            // Dendy keeps PAL's line count but NTSC's 20 lines of vblank, moving the rest to
+            // the post-render period
+            Region::Dendy => 291,
+        }
+    }
+
+    /// Whether the pre-render line is a dot shorter on odd frames while rendering
+    pub fn skips_odd_frame_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+}
@@ -0,0 +1,12 @@
+pub mod ntsc;
+pub mod overscan;
+pub mod palette;
+pub mod png;
+pub mod scale;
+
+/// Pixels coming out of the ppu are 9 bits wide: the low 6 bits select one of the 64 system
+/// colours and the 3 bits above them are PPUMASK bits 5-7. Those are red, green, blue on the
+/// 2C02; the 2C07 and Dendy ppus swap red and green, which their palettes account for.
+pub const PIXEL_INDEX_MASK: u16 = 0x3F;
+pub const EMPHASIS_SHIFT: u16 = 6;
+pub const EMPHASIS_MASK: u16 = 0b111 << EMPHASIS_SHIFT;
@@ -0,0 +1,330 @@
+use super::palette::{composite_level, Palette, PaletteGenerator, PALETTE_ENTRIES};
+
+/// Width of the picture Blargg's nes_ntsc produces for the full 256 dot line
+pub const NTSC_DEFAULT_WIDTH: usize = 602;
+
+const PICTURE_WIDTH: usize = 256;
+const PICTURE_HEIGHT: usize = 240;
+/// The signal is modelled at one sample per subcarrier phase, 12 per colour cycle
+const PHASES: usize = 12;
+const SAMPLES_PER_DOT: usize = 8;
+const LINE_SAMPLES: usize = PICTURE_WIDTH * SAMPLES_PER_DOT;
+/// A scanline is 341 dots of 8 phases each, so every line starts 4 phases after the previous
+const PHASE_STEP_PER_LINE: usize = 4;
+/// Share of the half-cycle luma filter used at full artifacts
+const MAX_CARRIER_LEAK: f32 = 0.5;
+const GAMMA_TABLE_SIZE: usize = 1024;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum NtscSignal {
+    /// Luma and chroma share one wire, so each bleeds into the other
+    Composite,
+    /// Luma and chroma are carried separately; only the chroma bandwidth limit remains
+    SVideo,
+    /// The palette colours of each dot, no signal modelling at all
+    Rgb,
+}
+
+/// Knobs follow nes_ntsc's convention of -1.0 to 1.0 with 0.0 being a typical composite
+/// connection
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct NtscSettings {
+    pub signal: NtscSignal,
+    /// Negative blurs luma, positive sharpens it
+    pub sharpness: f32,
+    /// How much of the colour carrier is left in luma, seen as dot crawl and checkerboards
+    pub artifacts: f32,
+    /// How much luma detail is decoded as colour, seen as rainbows on sharp edges
+    pub fringing: f32,
+    /// How far colour smears along the line
+    pub bleed: f32,
+    /// Picture controls and decoder used to turn the signal back into RGB
+    pub colour: PaletteGenerator,
+}
+
+impl NtscSettings {
+    pub fn composite() -> Self {
+        Self {
+            signal: NtscSignal::Composite,
+            sharpness: 0.0,
+            artifacts: 0.0,
+            fringing: 0.0,
+            bleed: 0.0,
+            colour: PaletteGenerator::new(),
+        }
+    }
+
+    pub fn svideo() -> Self {
+        Self {
+            signal: NtscSignal::SVideo,
+            sharpness: 0.2,
+            artifacts: -1.0,
+            fringing: -1.0,
+            ..Self::composite()
+        }
+    }
+
+    pub fn rgb() -> Self {
+        Self {
+            signal: NtscSignal::Rgb,
+            sharpness: 0.2,
+            artifacts: -1.0,
+            fringing: -1.0,
+            bleed: -1.0,
+            ..Self::composite()
+        }
+    }
+}
+
+impl Default for NtscSettings {
+    fn default() -> Self {
+        Self::composite()
+    }
+}
+
+/// Turns the ppu's 9-bit pixels into an RGBA picture the way a television decodes the NES's
+/// video signal.
+///
+/// Every dot is expanded into the 8 subcarrier phases it is output over, starting from the phase
+/// the ppu reports for the frame, so the colour artifacts move between frames like they do on
+/// hardware. Each line is then separated back into luma and chroma with box filters one colour
+/// cycle wide, demodulated, and resampled to the output width.
+pub struct NtscFilter {
+    settings: NtscSettings,
+    output_width: usize,
+
+    /// Composite level of every pixel value at every phase
+    levels: Vec<[f32; PHASES]>,
+    /// Level of every pixel value averaged over a colour cycle
+    luma: Vec<f32>,
+    /// Demodulation carrier per phase, (cos, sin)
+    carrier: [(f32, f32); PHASES],
+    /// `PaletteGenerator::gamma_encode` sampled over 0.0-1.0
+    gamma_table: Vec<u8>,
+    palette: Palette,
+
+    // Per-line work buffers
+    signal: Vec<f32>,
+    ideal_luma: Vec<f32>,
+    line_luma: Vec<f32>,
+    line_u: Vec<f32>,
+    line_v: Vec<f32>,
+    scratch: Vec<f32>,
+    scratch_2: Vec<f32>,
+    /// Running sums for `box_filter`, one longer than a line
+    sums: Vec<f32>,
+}
+
+impl NtscFilter {
+    pub fn new(settings: NtscSettings, output_width: usize) -> Self {
+        let levels: Vec<[f32; PHASES]> = (0..PALETTE_ENTRIES as u16)
+            .map(|pixel| std::array::from_fn(|phase| composite_level(pixel, phase as u8)))
+            .collect();
+        let luma = levels
+            .iter()
+            .map(|levels| levels.iter().sum::<f32>() / PHASES as f32)
+            .collect();
+        let mut filter = Self {
+            settings,
+            output_width,
+            levels,
+            luma,
+            carrier: [(0.0, 0.0); PHASES],
+            gamma_table: Vec::new(),
+            palette: Palette::new(),
+            signal: vec![0.0; LINE_SAMPLES],
+            ideal_luma: vec![0.0; LINE_SAMPLES],
+            line_luma: vec![0.0; LINE_SAMPLES],
+            line_u: vec![0.0; LINE_SAMPLES],
+            line_v: vec![0.0; LINE_SAMPLES],
+            scratch: vec![0.0; LINE_SAMPLES],
+            scratch_2: vec![0.0; LINE_SAMPLES],
+            sums: vec![0.0; LINE_SAMPLES + 1],
+        };
+        filter.set_settings(settings);
+        filter
+    }
+
+    pub fn settings(&self) -> &NtscSettings {
+        &self.settings
+    }
+
+    pub fn set_settings(&mut self, settings: NtscSettings) {
+        self.settings = settings;
+        self.carrier = std::array::from_fn(|phase| {
+            let angle = settings.colour.phase_angle(phase as u8);
+            (angle.cos(), angle.sin())
+        });
+        self.gamma_table = (0..GAMMA_TABLE_SIZE)
+            .map(|index| {
+                let channel = index as f32 / (GAMMA_TABLE_SIZE - 1) as f32;
+                settings.colour.gamma_encode(channel)
+            })
+            .collect();
+        self.palette = settings.colour.generate();
+    }
+
+    pub fn output_width(&self) -> usize {
+        self.output_width
+    }
+
+    pub fn output_height(&self) -> usize {
+        PICTURE_HEIGHT
+    }
+
+    /// Filters a frame of ppu pixels into `rgba`, which must hold `output_width * 240` pixels.
+    /// `frame_phase` is the subcarrier phase of the first dot, see `Ppu::frame_colour_phase`.
+    pub fn filter_frame(&mut self, pixels: &[u16], frame_phase: u8, rgba: &mut [u8]) {
+        let out_row_size = self.output_width * 4;
+        for (line, (row, out)) in pixels
+            .chunks_exact(PICTURE_WIDTH)
+            .zip(rgba.chunks_exact_mut(out_row_size))
+            .enumerate()
+        {
+            if self.settings.signal == NtscSignal::Rgb {
+                self.resample_rgb(row, out);
+                continue;
+            }
+            let line_phase = (usize::from(frame_phase) + line * PHASE_STEP_PER_LINE) % PHASES;
+            self.decode_line(row, line_phase);
+            self.resample_yuv(out);
+        }
+    }
+
+    /// Leaves the demodulated line in `line_luma`, `line_u` and `line_v`
+    fn decode_line(&mut self, row: &[u16], line_phase: usize) {
+        let settings = self.settings;
+        for (sample, (signal, ideal)) in self
+            .signal
+            .iter_mut()
+            .zip(self.ideal_luma.iter_mut())
+            .enumerate()
+        {
+            let pixel = usize::from(row[sample / SAMPLES_PER_DOT]);
+            *signal = self.levels[pixel][(line_phase + sample) % PHASES];
+            *ideal = self.luma[pixel];
+        }
+
+        // Luma
+        match settings.signal {
+            NtscSignal::Composite => {
+                // A filter one colour cycle wide removes the carrier completely; a narrower one
+                // lets part of it through
+                box_filter(&self.signal, PHASES, &mut self.sums, &mut self.scratch);
+                box_filter(
+                    &self.signal,
+                    PHASES / 2,
+                    &mut self.sums,
+                    &mut self.line_luma,
+                );
+                let leak = knob(settings.artifacts) * MAX_CARRIER_LEAK;
+                for (luma, separated) in self.line_luma.iter_mut().zip(&self.scratch) {
+                    *luma = separated + (*luma - separated) * leak;
+                }
+            }
+            _ => self.line_luma.copy_from_slice(&self.ideal_luma),
+        }
+        if settings.sharpness != 0.0 {
+            box_filter(
+                &self.line_luma,
+                SAMPLES_PER_DOT,
+                &mut self.sums,
+                &mut self.scratch_2,
+            );
+            for (luma, blurred) in self.line_luma.iter_mut().zip(&self.scratch_2) {
+                *luma += (*luma - blurred) * settings.sharpness;
+            }
+        }
+
+        // Chroma; `scratch` still holds the fully separated luma for composite signals
+        let fringing = match settings.signal {
+            NtscSignal::Composite => knob(settings.fringing),
+            _ => 0.0,
+        };
+        for sample in 0..LINE_SAMPLES {
+            let clean = self.signal[sample] - self.ideal_luma[sample];
+            let chroma = if fringing > 0.0 {
+                let crosstalk = self.signal[sample] - self.scratch[sample];
+                clean + (crosstalk - clean) * fringing
+            } else {
+                clean
+            };
+            let (cos, sin) = self.carrier[(line_phase + sample) % PHASES];
+            self.line_u[sample] = chroma * cos * 2.0;
+            self.line_v[sample] = chroma * sin * 2.0;
+        }
+        let chroma_width = PHASES * (2.0 + settings.bleed).round().max(1.0) as usize;
+        box_filter(
+            &self.line_u,
+            chroma_width,
+            &mut self.sums,
+            &mut self.scratch_2,
+        );
+        self.line_u.copy_from_slice(&self.scratch_2);
+        box_filter(
+            &self.line_v,
+            chroma_width,
+            &mut self.sums,
+            &mut self.scratch_2,
+        );
+        self.line_v.copy_from_slice(&self.scratch_2);
+    }
+
+    fn resample_yuv(&self, out: &mut [u8]) {
+        let scale = LINE_SAMPLES as f32 / self.output_width as f32;
+        for (x, out) in out.chunks_exact_mut(4).enumerate() {
+            let position = ((x as f32 + 0.5) * scale - 0.5).max(0.0);
+            let index = (position as usize).min(LINE_SAMPLES - 2);
+            let fraction = position - index as f32;
+            let lerp = |line: &[f32]| line[index] + (line[index + 1] - line[index]) * fraction;
+            let rgb = self.settings.colour.yuv_to_unencoded_rgb(
+                lerp(&self.line_luma),
+                lerp(&self.line_u),
+                lerp(&self.line_v),
+            );
+            for (out, channel) in out.iter_mut().zip(rgb) {
+                let index = (channel.clamp(0.0, 1.0) * (GAMMA_TABLE_SIZE - 1) as f32) as usize;
+                *out = self.gamma_table[index];
+            }
+            out[3] = 255;
+        }
+    }
+
+    fn resample_rgb(&self, row: &[u16], out: &mut [u8]) {
+        let scale = PICTURE_WIDTH as f32 / self.output_width as f32;
+        for (x, out) in out.chunks_exact_mut(4).enumerate() {
+            let position = ((x as f32 + 0.5) * scale - 0.5).max(0.0);
+            let index = (position as usize).min(PICTURE_WIDTH - 2);
+            let fraction = position - index as f32;
+            let left = self.palette.rgb(row[index]);
+            let right = self.palette.rgb(row[index + 1]);
+            for channel in 0..3 {
+                let value = f32::from(left[channel])
+                    + (f32::from(right[channel]) - f32::from(left[channel])) * fraction;
+                out[channel] = value.round() as u8;
+            }
+            out[3] = 255;
+        }
+    }
+}
+
+/// Maps a -1.0 to 1.0 knob onto 0.0 to 1.0
+fn knob(value: f32) -> f32 {
+    ((value + 1.0) / 2.0).clamp(0.0, 1.0)
+}
+
+/// Moving average of `width` samples centred on each sample; edges average what's available.
+/// `sums` is scratch space for running sums and must be at least one longer than `input`.
+fn box_filter(input: &[f32], width: usize, sums: &mut [f32], output: &mut [f32]) {
+    let before = width / 2;
+    let after = width - before;
+    sums[0] = 0.0;
+    for (index, value) in input.iter().enumerate() {
+        sums[index + 1] = sums[index] + value;
+    }
+    for (index, out) in output.iter_mut().enumerate() {
+        let start = index.saturating_sub(before);
+        let end = (index + after).min(input.len());
+        *out = (sums[end] - sums[start]) / (end - start) as f32;
+    }
+}
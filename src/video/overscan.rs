@@ -0,0 +1,54 @@
+use crate::ppu::PIXEL_DATA_SIZE;
+
+const FRAME_WIDTH: usize = 256;
+const FRAME_HEIGHT: usize = PIXEL_DATA_SIZE / FRAME_WIDTH;
+
+/// Margins cut off the edges of the 256x240 picture, in pixels
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Overscan {
+    pub top: usize,
+    pub bottom: usize,
+    pub left: usize,
+    pub right: usize,
+}
+
+impl Overscan {
+    /// Keeps the whole picture
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The top and bottom 8 lines and the left 8 pixels, where TVs hid most of the scroll
+    /// artifacts and the left column masking
+    pub fn tv() -> Self {
+        Self {
+            top: 8,
+            bottom: 8,
+            left: 8,
+            right: 0,
+        }
+    }
+
+    pub fn width(&self) -> usize {
+        FRAME_WIDTH.saturating_sub(self.left + self.right)
+    }
+
+    pub fn height(&self) -> usize {
+        FRAME_HEIGHT.saturating_sub(self.top + self.bottom)
+    }
+
+    /// Copies what's left of a 256x240 `frame` into `cropped`, `width` by `height` pixels.
+    /// Pixels are `components` elements long, so 1 for ppu pixels and 4 for RGBA bytes.
+    pub fn crop<T: Copy>(&self, frame: &[T], components: usize, cropped: &mut Vec<T>) {
+        cropped.clear();
+        let left = self.left.min(FRAME_WIDTH);
+        for row in frame
+            .chunks_exact(FRAME_WIDTH * components)
+            .skip(self.top)
+            .take(self.height())
+        {
+            let start = left * components;
+            cropped.extend_from_slice(&row[start..start + self.width() * components]);
+        }
+    }
+}
@@ -0,0 +1,450 @@
+use super::{EMPHASIS_MASK, EMPHASIS_SHIFT, PIXEL_INDEX_MASK};
+
+pub const SYSTEM_COLOURS: usize = 64;
+/// Every system colour under every emphasis combination
+pub const PALETTE_ENTRIES: usize = SYSTEM_COLOURS * 8;
+
+/// How much an emphasis bit darkens the colours it doesn't emphasize, used when a palette only
+/// provides the 64 base colours
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+
+/// The RP2C03's colours with 3 bits per channel, written as octal RGB triples
+/// https://www.nesdev.org/wiki/PPU_palettes#2C03_and_2C05
+#[rustfmt::skip]
+const RP2C03_COLOURS: [u16; SYSTEM_COLOURS] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420, 0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630, 0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750, 0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772, 0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+/// The 2C03 colour each RP2C04-0001 to -0004 outputs for every palette index. The 2C04s have the
+/// same colours as the 2C03 in a different order per revision.
+#[rustfmt::skip]
+const RP2C04_PERMUTATIONS: [[u8; SYSTEM_COLOURS]; 4] = [
+    [
+        0x35, 0x23, 0x16, 0x22, 0x1C, 0x09, 0x1D, 0x15, 0x20, 0x00, 0x27, 0x05, 0x04, 0x28, 0x08, 0x20,
+        0x21, 0x3E, 0x1F, 0x29, 0x3C, 0x32, 0x36, 0x12, 0x3F, 0x2B, 0x2E, 0x1E, 0x3D, 0x2D, 0x24, 0x01,
+        0x0E, 0x31, 0x33, 0x2A, 0x2C, 0x0C, 0x1B, 0x14, 0x2E, 0x07, 0x34, 0x06, 0x13, 0x02, 0x26, 0x2E,
+        0x2E, 0x19, 0x10, 0x0A, 0x39, 0x03, 0x37, 0x17, 0x0F, 0x11, 0x0B, 0x0D, 0x38, 0x25, 0x18, 0x3A,
+    ],
+    [
+        0x2E, 0x27, 0x18, 0x39, 0x3A, 0x25, 0x1C, 0x31, 0x16, 0x13, 0x38, 0x34, 0x20, 0x23, 0x3C, 0x0B,
+        0x0F, 0x21, 0x06, 0x3D, 0x1B, 0x29, 0x1E, 0x22, 0x1D, 0x24, 0x0E, 0x2B, 0x32, 0x08, 0x2E, 0x03,
+        0x04, 0x36, 0x26, 0x33, 0x11, 0x1F, 0x10, 0x02, 0x14, 0x3F, 0x00, 0x09, 0x12, 0x2E, 0x28, 0x20,
+        0x3E, 0x0D, 0x2A, 0x17, 0x0C, 0x01, 0x15, 0x19, 0x2E, 0x2C, 0x07, 0x37, 0x35, 0x05, 0x0A, 0x2D,
+    ],
+    [
+        0x14, 0x25, 0x3A, 0x10, 0x0B, 0x20, 0x31, 0x09, 0x01, 0x2E, 0x36, 0x08, 0x15, 0x3D, 0x3E, 0x3C,
+        0x22, 0x1C, 0x05, 0x12, 0x19, 0x18, 0x17, 0x1B, 0x00, 0x03, 0x2E, 0x02, 0x16, 0x06, 0x34, 0x35,
+        0x23, 0x0F, 0x0E, 0x37, 0x0D, 0x27, 0x26, 0x20, 0x29, 0x04, 0x21, 0x24, 0x11, 0x2D, 0x2E, 0x1F,
+        0x2C, 0x1E, 0x39, 0x33, 0x07, 0x2A, 0x28, 0x1D, 0x0A, 0x2E, 0x32, 0x38, 0x13, 0x2B, 0x3F, 0x0C,
+    ],
+    [
+        0x18, 0x03, 0x1C, 0x28, 0x2E, 0x35, 0x01, 0x17, 0x10, 0x1F, 0x2A, 0x0E, 0x36, 0x37, 0x0B, 0x39,
+        0x25, 0x1E, 0x12, 0x34, 0x2E, 0x1D, 0x06, 0x26, 0x3E, 0x1B, 0x22, 0x19, 0x04, 0x2E, 0x3A, 0x21,
+        0x05, 0x0A, 0x07, 0x02, 0x13, 0x14, 0x00, 0x15, 0x0C, 0x3D, 0x11, 0x0F, 0x0D, 0x38, 0x2D, 0x24,
+        0x33, 0x20, 0x08, 0x16, 0x3F, 0x2B, 0x20, 0x3C, 0x2E, 0x27, 0x23, 0x31, 0x29, 0x32, 0x2C, 0x09,
+    ],
+];
+
+/// Maps the ppu's 9-bit pixels onto RGB colours
+#[derive(Clone, Debug, PartialEq)]
+pub struct Palette {
+    colours: Vec<[u8; 3]>,
+}
+
+impl Palette {
+    /// An all-black palette
+    pub fn new() -> Self {
+        Self {
+            colours: vec![[0; 3]; PALETTE_ENTRIES],
+        }
+    }
+
+    /// Builds a palette from the contents of a `.pal` file holding either the 64 base colours or
+    /// all 512 emphasis variants. Emphasis is approximated for 64-colour files.
+    pub fn from_pal(data: &[u8]) -> Option<Self> {
+        let colours: Vec<[u8; 3]> = data
+            .chunks_exact(3)
+            .map(|rgb| [rgb[0], rgb[1], rgb[2]])
+            .collect();
+        match data.len() {
+            len if len == SYSTEM_COLOURS * 3 => Some(Self::with_emphasis(&colours)),
+            len if len == PALETTE_ENTRIES * 3 => Some(Self { colours }),
+            _ => None,
+        }
+    }
+
+    /// Extends 64 base colours to all emphasis combinations by attenuating the channels that
+    /// aren't emphasized
+    fn with_emphasis(base: &[[u8; 3]]) -> Self {
+        let mut colours = Vec::with_capacity(PALETTE_ENTRIES);
+        for emphasis in 0..8 {
+            for rgb in base {
+                let mut rgb = *rgb;
+                if emphasis != 0 {
+                    for (channel, value) in rgb.iter_mut().enumerate() {
+                        if emphasis & (1 << channel) == 0 {
+                            *value = (f32::from(*value) * EMPHASIS_ATTENUATION) as u8;
+                        }
+                    }
+                }
+                colours.push(rgb);
+            }
+        }
+        Self { colours }
+    }
+
+    /// The palette of the RGB ppus. Their emphasis bits turn a channel fully on rather than
+    /// darkening the other two.
+    pub fn rp2c03() -> Self {
+        Self::rgb_ppu(&RP2C03_COLOURS)
+    }
+
+    /// The palette of the RP2C04-0001 to -0004, given 1 to 4
+    pub fn rp2c04(revision: u8) -> Option<Self> {
+        let permutation = RP2C04_PERMUTATIONS.get(usize::from(revision).checked_sub(1)?)?;
+        Some(Self::rgb_ppu(
+            &permutation.map(|index| RP2C03_COLOURS[usize::from(index)]),
+        ))
+    }
+
+    /// The palette with the entries of the red and green emphasis bits exchanged, for the 2C07
+    /// and Dendy ppus whose PPUMASK bit 5 emphasizes green and bit 6 red
+    pub fn swap_red_green_emphasis(&self) -> Self {
+        let mut colours = self.colours.clone();
+        for emphasis in 0..8 {
+            let swapped = (emphasis & 0b100) | (emphasis & 0b01) << 1 | (emphasis & 0b10) >> 1;
+            let from = emphasis * SYSTEM_COLOURS..(emphasis + 1) * SYSTEM_COLOURS;
+            colours[swapped * SYSTEM_COLOURS..(swapped + 1) * SYSTEM_COLOURS]
+                .copy_from_slice(&self.colours[from]);
+        }
+        Self { colours }
+    }
+
+    /// Expands 3 bits per channel colours, applying the RGB ppus' emphasis
+    fn rgb_ppu(base: &[u16; SYSTEM_COLOURS]) -> Self {
+        let mut colours = Vec::with_capacity(PALETTE_ENTRIES);
+        for emphasis in 0..8 {
+            for &colour in base {
+                let mut rgb = [(colour >> 6) & 7, (colour >> 3) & 7, colour & 7];
+                for (channel, value) in rgb.iter_mut().enumerate() {
+                    if emphasis & (1 << channel) > 0 {
+                        *value = 7;
+                    }
+                }
+                colours.push(rgb.map(|value| (value * 255 / 7) as u8));
+            }
+        }
+        Self { colours }
+    }
+
+    pub fn rgb(&self, pixel: u16) -> [u8; 3] {
+        let emphasis = (pixel & EMPHASIS_MASK) >> EMPHASIS_SHIFT;
+        let index = usize::from(emphasis) * SYSTEM_COLOURS + usize::from(pixel & PIXEL_INDEX_MASK);
+        self.colours[index]
+    }
+
+    /// The palette as the contents of a 512-entry `.pal` file
+    pub fn to_pal(&self) -> Vec<u8> {
+        self.colours.iter().flatten().copied().collect()
+    }
+
+    /// Converts a frame of ppu pixels into RGBA bytes, 4 per pixel with alpha always 255
+    pub fn write_rgba(&self, pixels: &[u16], rgba: &mut [u8]) {
+        for (pixel, out) in pixels.iter().zip(rgba.chunks_exact_mut(4)) {
+            let [r, g, b] = self.rgb(*pixel);
+            out.copy_from_slice(&[r, g, b, 255]);
+        }
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Matrix turning the decoded chroma back into RGB
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColourDecoder {
+    /// The textbook FCC YIQ matrix
+    FccYiq,
+    /// Sony CXA2025AS, as found in many US televisions, decoding YIQ
+    SonyCxa2025As,
+    /// Plain YUV decoding as done by PAL televisions
+    PalYuv,
+}
+
+impl ColourDecoder {
+    fn to_rgb(self, y: f32, u: f32, v: f32) -> [f32; 3] {
+        let matrix = match self {
+            ColourDecoder::FccYiq => [
+                0.946882, 0.623557, -0.274788, -0.635691, -1.108545, 1.709007,
+            ],
+            ColourDecoder::SonyCxa2025As => [1.630, 0.317, -0.378, -0.466, -1.089, 1.677],
+            ColourDecoder::PalYuv => {
+                return [y + 1.140 * v, y - 0.395 * u - 0.581 * v, y + 2.032 * u];
+            }
+        };
+        // YIQ is YUV rotated by 33 degrees
+        const SIN_33: f32 = 0.544639;
+        const COS_33: f32 = 0.838671;
+        let i = v * COS_33 - u * SIN_33;
+        let q = v * SIN_33 + u * COS_33;
+        [
+            y + matrix[0] * i + matrix[1] * q,
+            y + matrix[2] * i + matrix[3] * q,
+            y + matrix[4] * i + matrix[5] * q,
+        ]
+    }
+}
+
+/// The ppu generating the composite signal
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ColourEncoder {
+    /// The NTSC 2C02
+    Rp2c02,
+    /// The PAL 2C07. It steps through its 12 chroma phases in the opposite direction on every
+    /// other line, giving the V inversion PAL expects, but the ±135° swinging burst falls between
+    /// two of its phases. Measured against the burst, each line's hues come out 15° off, in
+    /// opposite directions on alternate lines; a PAL decoder averages the chroma of neighbouring
+    /// lines, cancelling the hue error at the cost of a little saturation. PPUMASK bit 5
+    /// emphasizes green and bit 6 red.
+    Rp2c07,
+}
+
+impl ColourEncoder {
+    /// Hue error of each line of a pair that the decoder averages, in degrees
+    fn line_hue_errors(self) -> &'static [f32] {
+        match self {
+            ColourEncoder::Rp2c02 => &[0.0],
+            ColourEncoder::Rp2c07 => &[15.0, -15.0],
+        }
+    }
+
+    /// The hue whose half of the colour cycle each emphasis bit attenuates, in the order of
+    /// PPUMASK bits 5-7
+    fn emphasis_hues(self) -> [u8; 3] {
+        match self {
+            ColourEncoder::Rp2c02 => EMPHASIS_HUES,
+            ColourEncoder::Rp2c07 => [EMPHASIS_HUES[1], EMPHASIS_HUES[0], EMPHASIS_HUES[2]],
+        }
+    }
+}
+
+/// Composite signal levels of the 2C02 relative to sync, for luma 0-3 with the square wave low
+/// and high
+/// https://www.nesdev.org/wiki/NTSC_video#Brightness_Levels
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = SIGNAL_LOW[1];
+const SIGNAL_WHITE: f32 = SIGNAL_HIGH[3];
+/// Signal attenuation applied by an active emphasis bit
+const SIGNAL_EMPHASIS_ATTENUATION: f32 = 0.746;
+/// Emphasis bits for red, green and blue each attenuate the half of the colour cycle in phase
+/// with these hues, the ones opposite the colour being emphasized. The 2C02 orders the bits
+/// red, green, blue.
+const EMPHASIS_HUES: [u8; 3] = [0x0C, 0x04, 0x08];
+
+/// Synthesizes a full 512-entry palette from the ppu's composite signal.
+///
+/// Each colour is a square wave between two voltage levels, 12 phases per colour cycle, whose
+/// phase is set by the low 4 bits of the index. The wave is sampled at all 12 phases and
+/// demodulated into luma and chroma, then turned into RGB by the selected decoder.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct PaletteGenerator {
+    pub encoder: ColourEncoder,
+    pub decoder: ColourDecoder,
+    /// Hue rotation in degrees
+    pub hue: f32,
+    pub saturation: f32,
+    pub contrast: f32,
+    /// Added to luma, 0.0 leaves black at black
+    pub brightness: f32,
+    /// Gamma of the emulated display; 2.2 matches sRGB so leaves colours unchanged
+    pub gamma: f32,
+}
+
+impl PaletteGenerator {
+    /// Defaults for a 2C02, chosen to closely match the nesdev wiki's composite palette
+    pub fn new() -> Self {
+        Self {
+            encoder: ColourEncoder::Rp2c02,
+            decoder: ColourDecoder::FccYiq,
+            hue: 0.0,
+            saturation: 0.75,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.0,
+        }
+    }
+
+    /// Defaults for a 2C07 on a PAL television, still using the 2C02's measured signal levels
+    pub fn pal() -> Self {
+        Self {
+            encoder: ColourEncoder::Rp2c07,
+            decoder: ColourDecoder::PalYuv,
+            ..Self::new()
+        }
+    }
+
+    pub fn generate(&self) -> Palette {
+        let mut colours = Vec::with_capacity(PALETTE_ENTRIES);
+        for pixel in 0..PALETTE_ENTRIES as u16 {
+            colours.push(self.colour(pixel));
+        }
+        Palette { colours }
+    }
+
+    fn colour(&self, pixel: u16) -> [u8; 3] {
+        let (y, u, v) = self.demodulate(pixel);
+        self.yuv_to_rgb(y, u, v)
+    }
+
+    /// Luma and chroma of a pixel, averaged over the lines the decoder combines
+    fn demodulate(&self, pixel: u16) -> (f32, f32, f32) {
+        let hue_errors = self.encoder.line_hue_errors();
+        let (mut y, mut u, mut v) = (0.0, 0.0, 0.0);
+        for error in hue_errors {
+            for phase in 0..12u8 {
+                let level = encoded_level(self.encoder, pixel, phase);
+                let angle = self.phase_angle(phase) + error.to_radians();
+                y += level;
+                u += level * angle.cos();
+                v += level * angle.sin();
+            }
+        }
+        let samples = (12 * hue_errors.len()) as f32;
+        (y / samples, u * 2.0 / samples, v * 2.0 / samples)
+    }
+
+    /// Angle on the UV plane that colour phase `phase` (0-11) demodulates against. Phase 0 lines
+    /// up with +U, which puts colour 2 at blue and colour 8, the colour burst, roughly opposite it
+    pub(crate) fn phase_angle(&self, phase: u8) -> f32 {
+        (self.hue - 30.0 * f32::from(phase)).to_radians()
+    }
+
+    /// Applies the picture controls to demodulated luma and chroma and decodes them into RGB
+    pub(crate) fn yuv_to_rgb(&self, y: f32, u: f32, v: f32) -> [u8; 3] {
+        self.yuv_to_unencoded_rgb(y, u, v)
+            .map(|channel| self.gamma_encode(channel))
+    }
+
+    /// `yuv_to_rgb` before gamma, with channels nominally in 0.0-1.0
+    pub(crate) fn yuv_to_unencoded_rgb(&self, y: f32, u: f32, v: f32) -> [f32; 3] {
+        let y = y * self.contrast + self.brightness;
+        let chroma_scale = self.saturation * self.contrast;
+        self.decoder.to_rgb(y, u * chroma_scale, v * chroma_scale)
+    }
+
+    pub(crate) fn gamma_encode(&self, channel: f32) -> u8 {
+        let linear = channel.clamp(0.0, 1.0).powf(self.gamma / 2.2);
+        (linear * 255.0).round() as u8
+    }
+}
+
+/// Composite voltage of a 9-bit 2C02 pixel at colour phase `phase` (0-11), scaled so that black
+/// is 0.0 and white 1.0
+pub(crate) fn composite_level(pixel: u16, phase: u8) -> f32 {
+    encoded_level(ColourEncoder::Rp2c02, pixel, phase)
+}
+
+/// `composite_level` for the ppu `encoder`
+fn encoded_level(encoder: ColourEncoder, pixel: u16, phase: u8) -> f32 {
+    let index = (pixel & PIXEL_INDEX_MASK) as u8;
+    let emphasis = ((pixel & EMPHASIS_MASK) >> EMPHASIS_SHIFT) as u8;
+    let hue = index & 0x0F;
+    // Colours $xE and $xF are forced to black
+    let luma = if hue > 0x0D {
+        1
+    } else {
+        usize::from(index >> 4)
+    };
+    let mut low = SIGNAL_LOW[luma];
+    let mut high = SIGNAL_HIGH[luma];
+    if hue == 0x00 {
+        low = high;
+    } else if hue > 0x0C {
+        high = low;
+    }
+
+    let in_phase = |colour: u8| (colour + phase) % 12 < 6;
+    let mut signal = if in_phase(hue) { high } else { low };
+    let attenuated = encoder
+        .emphasis_hues()
+        .iter()
+        .enumerate()
+        .any(|(bit, &colour)| emphasis & (1 << bit) > 0 && in_phase(colour));
+    if attenuated && hue < 0x0E {
+        signal *= SIGNAL_EMPHASIS_ATTENUATION;
+    }
+    (signal - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+impl Default for PaletteGenerator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ColourDecoder, Palette, PaletteGenerator, SYSTEM_COLOURS};
+    use crate::video::EMPHASIS_SHIFT;
+
+    #[test]
+    fn rp2c07_swaps_red_and_green_emphasis() {
+        let ntsc = PaletteGenerator::new().generate();
+        let pal = PaletteGenerator::pal().generate();
+        let grey = 0x10;
+        // PPUMASK bit 5
+        let [r, g, _] = ntsc.rgb(grey | 0b001 << EMPHASIS_SHIFT);
+        assert!(r > g);
+        let [r, g, _] = pal.rgb(grey | 0b001 << EMPHASIS_SHIFT);
+        assert!(g > r);
+        // PPUMASK bit 6
+        let [r, g, _] = ntsc.rgb(grey | 0b010 << EMPHASIS_SHIFT);
+        assert!(g > r);
+        let [r, g, _] = pal.rgb(grey | 0b010 << EMPHASIS_SHIFT);
+        assert!(r > g);
+    }
+
+    #[test]
+    fn rp2c07_line_averaging_keeps_hue_and_lowers_saturation() {
+        let ntsc = PaletteGenerator {
+            decoder: ColourDecoder::PalYuv,
+            ..PaletteGenerator::new()
+        };
+        let pal = PaletteGenerator::pal();
+        let cos_15 = 15f32.to_radians().cos();
+        for pixel in [0x02, 0x16, 0x2A, 0x38] {
+            let (ntsc_y, ntsc_u, ntsc_v) = ntsc.demodulate(pixel);
+            let (pal_y, pal_u, pal_v) = pal.demodulate(pixel);
+            assert!((pal_y - ntsc_y).abs() < 1e-5);
+            assert!((pal_u - ntsc_u * cos_15).abs() < 1e-5);
+            assert!((pal_v - ntsc_v * cos_15).abs() < 1e-5);
+        }
+        assert_ne!(ntsc.generate(), pal.generate());
+    }
+
+    #[test]
+    fn swapping_emphasis_moves_whole_entries() {
+        let palette = PaletteGenerator::new().generate();
+        let swapped = palette.swap_red_green_emphasis();
+        for index in 0..SYSTEM_COLOURS as u16 {
+            for (from, to) in [
+                (0b001, 0b010),
+                (0b010, 0b001),
+                (0b101, 0b110),
+                (0b100, 0b100),
+            ] {
+                let colour = palette.rgb(index | from << EMPHASIS_SHIFT);
+                assert_eq!(swapped.rgb(index | to << EMPHASIS_SHIFT), colour);
+            }
+        }
+        assert_eq!(swapped.swap_red_green_emphasis(), palette);
+        assert_ne!(swapped, Palette::new());
+    }
+}
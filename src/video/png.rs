@@ -0,0 +1,216 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+};
+
+use super::scale::Scaler;
+use crate::ppu::{Ppu, PIXEL_DATA_SIZE};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+/// Largest amount of data a stored deflate block can hold
+const STORED_BLOCK_SIZE: usize = 0xFFFF;
+
+const CRC_TABLE: [u32; 256] = crc_table();
+
+const fn crc_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut n = 0;
+    while n < 256 {
+        let mut c = n as u32;
+        let mut k = 0;
+        while k < 8 {
+            c = if c & 1 > 0 {
+                0xEDB88320 ^ (c >> 1)
+            } else {
+                c >> 1
+            };
+            k += 1;
+        }
+        table[n] = c;
+        n += 1;
+    }
+    table
+}
+
+fn crc32(chunks: &[&[u8]]) -> u32 {
+    let mut crc = 0xFFFFFFFF;
+    for byte in chunks.iter().flat_map(|chunk| chunk.iter()) {
+        crc = CRC_TABLE[((crc ^ u32::from(*byte)) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc ^ 0xFFFFFFFF
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MOD_ADLER: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // 5552 bytes is the most that can be summed before b could overflow
+    for chunk in data.chunks(5552) {
+        for byte in chunk {
+            a += u32::from(*byte);
+            b += a;
+        }
+        a %= MOD_ADLER;
+        b %= MOD_ADLER;
+    }
+    (b << 16) | a
+}
+
+/// Wraps `data` in a zlib stream of stored, uncompressed deflate blocks
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let block_count = data.len().div_ceil(STORED_BLOCK_SIZE).max(1);
+    let mut stream = Vec::with_capacity(data.len() + block_count * 5 + 6);
+    // 32K window, no dictionary, fastest compression; 0x7801 is a multiple of 31 as required
+    stream.extend_from_slice(&[0x78, 0x01]);
+    let mut blocks = data.chunks(STORED_BLOCK_SIZE).peekable();
+    if blocks.peek().is_none() {
+        stream.extend_from_slice(&[0x01, 0x00, 0x00, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        // BFINAL in bit 0, BTYPE 00 for stored, then padding to the byte boundary
+        stream.push(u8::from(blocks.peek().is_none()));
+        let length = block.len() as u16;
+        stream.extend_from_slice(&length.to_le_bytes());
+        stream.extend_from_slice(&(!length).to_le_bytes());
+        stream.extend_from_slice(block);
+    }
+    stream.extend_from_slice(&adler32(data).to_be_bytes());
+    stream
+}
+
+fn write_chunk(writer: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    writer.write_all(&(data.len() as u32).to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    writer.write_all(&crc32(&[kind, data]).to_be_bytes())
+}
+
+/// Writes `width` by `height` pixels of `rgba` as an 8-bit RGBA PNG. Pixel data is stored without
+/// compression, which keeps the encoder small and the output byte for byte the same for the same
+/// picture. Fails with `InvalidInput` for an empty picture or when `rgba` is too short for it.
+pub fn write_png(
+    writer: &mut impl Write,
+    rgba: &[u8],
+    width: usize,
+    height: usize,
+) -> io::Result<()> {
+    let size = width
+        .checked_mul(height)
+        .and_then(|pixels| pixels.checked_mul(4));
+    if width == 0 || height == 0 || size.is_none_or(|size| rgba.len() < size) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "PNG dimensions don't match the pixel data",
+        ));
+    }
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // Bit depth 8, colour type 6 (RGBA), deflate, adaptive filtering, no interlacing
+    header.extend_from_slice(&[8, 6, 0, 0, 0]);
+
+    // Every row starts with its filter type, 0 for none
+    let mut scanlines = Vec::with_capacity((width * 4 + 1) * height);
+    for row in rgba.chunks_exact(width * 4).take(height) {
+        scanlines.push(0);
+        scanlines.extend_from_slice(row);
+    }
+
+    writer.write_all(&SIGNATURE)?;
+    write_chunk(writer, b"IHDR", &header)?;
+    write_chunk(writer, b"IDAT", &zlib_stored(&scanlines))?;
+    write_chunk(writer, b"IEND", &[])
+}
+
+pub fn save_png<P: AsRef<Path>>(
+    path: P,
+    rgba: &[u8],
+    width: usize,
+    height: usize,
+) -> io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write_png(&mut file, rgba, width, height)?;
+    file.flush()
+}
+
+/// Writes the last frame `ppu` finished through its palette, upscaled by `scaler` if given
+pub fn write_frame(writer: &mut impl Write, ppu: &Ppu, scaler: Option<Scaler>) -> io::Result<()> {
+    const WIDTH: usize = 256;
+    const HEIGHT: usize = PIXEL_DATA_SIZE / WIDTH;
+    let mut rgba = vec![0; PIXEL_DATA_SIZE * 4];
+    ppu.palette().write_rgba(ppu.pixel_data(), &mut rgba);
+    match scaler {
+        Some(scaler) => {
+            let mut scaled = Vec::new();
+            let (width, height) = scaler.scale(&rgba, WIDTH, HEIGHT, &mut scaled);
+            write_png(writer, &scaled, width, height)
+        }
+        None => write_png(writer, &rgba, WIDTH, HEIGHT),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{adler32, crc32, write_png, zlib_stored, STORED_BLOCK_SIZE};
+
+    #[test]
+    fn checksums_match_known_values() {
+        assert_eq!(crc32(&[b"IEND"]), 0xAE426082);
+        assert_eq!(crc32(&[b"IE", b"ND"]), 0xAE426082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E60398);
+    }
+
+    #[test]
+    fn stored_blocks_split_past_65535_bytes() {
+        let data = vec![0xAB; STORED_BLOCK_SIZE];
+        let stream = zlib_stored(&data);
+        assert_eq!(stream.len(), 2 + 5 + STORED_BLOCK_SIZE + 4);
+        // A single final block
+        assert_eq!(&stream[2..7], &[0x01, 0xFF, 0xFF, 0x00, 0x00]);
+
+        let data = vec![0xAB; STORED_BLOCK_SIZE + 1];
+        let stream = zlib_stored(&data);
+        assert_eq!(stream.len(), 2 + 5 + STORED_BLOCK_SIZE + 5 + 1 + 4);
+        // A full block that isn't final, then a final one holding the last byte
+        assert_eq!(&stream[2..7], &[0x00, 0xFF, 0xFF, 0x00, 0x00]);
+        let second = 7 + STORED_BLOCK_SIZE;
+        assert_eq!(
+            &stream[second..second + 6],
+            &[0x01, 0x01, 0x00, 0xFE, 0xFF, 0xAB]
+        );
+        assert_eq!(&stream[second + 6..], &adler32(&data).to_be_bytes());
+    }
+
+    #[test]
+    fn single_pixel_matches_reference() {
+        let mut png = Vec::new();
+        write_png(&mut png, &[0x12, 0x34, 0x56, 0xFF], 1, 1).unwrap();
+        #[rustfmt::skip]
+        let expected = [
+            0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A,
+            // IHDR: 1x1, 8-bit RGBA
+            0x00, 0x00, 0x00, 0x0D, 0x49, 0x48, 0x44, 0x52,
+            0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00,
+            0x1F, 0x15, 0xC4, 0x89,
+            // IDAT: zlib header, one stored block of the filter byte and pixel, Adler-32
+            0x00, 0x00, 0x00, 0x10, 0x49, 0x44, 0x41, 0x54,
+            0x78, 0x01, 0x01, 0x05, 0x00, 0xFA, 0xFF, 0x00, 0x12, 0x34, 0x56, 0xFF,
+            0x02, 0x94, 0x01, 0x9C,
+            0xD7, 0x04, 0xCA, 0xBD,
+            // IEND
+            0x00, 0x00, 0x00, 0x00, 0x49, 0x45, 0x4E, 0x44, 0xAE, 0x42, 0x60, 0x82,
+        ];
+        assert_eq!(png, expected);
+    }
+
+    #[test]
+    fn rejects_empty_or_short_pictures() {
+        for (rgba, width, height) in [(&[0u8; 4][..], 0, 1), (&[0; 4], 1, 0), (&[0; 7], 2, 1)] {
+            let error = write_png(&mut Vec::new(), rgba, width, height).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+}
@@ -0,0 +1,953 @@
+/// Pixel art upscalers for RGBA images. Everything is done in integer maths, so the same input
+/// always gives the same bytes on every platform.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Scaler {
+    /// Repeats every pixel into a square of the given size
+    Nearest(usize),
+    /// Scale2x, also known as EPX or AdvMAME2x
+    Scale2x,
+    /// Scale3x, also known as AdvMAME3x
+    Scale3x,
+    /// Maxim Stepin's hq2x. Each neighbour is compared with the pixel in YUV, and the pattern of
+    /// which ones differ picks the blend for each quarter, following FFmpeg's hqx rules.
+    Hq2x,
+    /// hq3x, the same for 3x3 blocks
+    Hq3x,
+    /// Hyllian's xBR level 1 as FFmpeg implements it. Colour distances along both diagonals of
+    /// each corner decide whether an edge crosses it, and its slope how far the blend reaches.
+    Xbr2x,
+    /// xBR level 1 at 3x
+    Xbr3x,
+    /// Resamples the width by 8:7 so that pixels come out with the shape they have on an NTSC TV,
+    /// area averaging the columns that fall between pixels
+    PixelAspect,
+}
+
+impl Scaler {
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        match self {
+            Scaler::Nearest(factor) => (width * factor, height * factor),
+            Scaler::Scale2x | Scaler::Hq2x | Scaler::Xbr2x => (width * 2, height * 2),
+            Scaler::Scale3x | Scaler::Hq3x | Scaler::Xbr3x => (width * 3, height * 3),
+            Scaler::PixelAspect => ((width * 16 + 7) / 14, height),
+        }
+    }
+
+    /// Scales `width` by `height` pixels of `rgba` into `scaled`, returning its size
+    pub fn scale(
+        &self,
+        rgba: &[u8],
+        width: usize,
+        height: usize,
+        scaled: &mut Vec<u8>,
+    ) -> (usize, usize) {
+        let image = Image {
+            rgba,
+            width,
+            height,
+        };
+        let (scaled_width, scaled_height) = self.output_size(width, height);
+        scaled.clear();
+        scaled.resize(scaled_width * scaled_height * 4, 0);
+        let mut output = Output {
+            rgba: scaled,
+            width: scaled_width,
+        };
+        match self {
+            Scaler::Nearest(factor) => {
+                for y in 0..scaled_height {
+                    for x in 0..scaled_width {
+                        let pixel = image.get((x / factor) as isize, (y / factor) as isize);
+                        output.set(x, y, pixel);
+                    }
+                }
+            }
+            Scaler::Scale2x => image.for_each(2, &mut output, scale2x),
+            Scaler::Scale3x => image.for_each(3, &mut output, scale3x),
+            Scaler::Hq2x => image.for_each(2, &mut output, hq2x),
+            Scaler::Hq3x => image.for_each(3, &mut output, hq3x),
+            Scaler::Xbr2x => image.for_each(2, &mut output, |image, x, y, block| {
+                xbr(image, x, y, 2, block)
+            }),
+            Scaler::Xbr3x => image.for_each(3, &mut output, |image, x, y, block| {
+                xbr(image, x, y, 3, block)
+            }),
+            Scaler::PixelAspect => pixel_aspect(&image, &mut output),
+        }
+        (scaled_width, scaled_height)
+    }
+}
+
+type Pixel = [u8; 4];
+
+struct Image<'a> {
+    rgba: &'a [u8],
+    width: usize,
+    height: usize,
+}
+
+impl Image<'_> {
+    /// The pixel at (x, y), repeating the edges outwards
+    fn get(&self, x: isize, y: isize) -> Pixel {
+        let x = x.clamp(0, self.width as isize - 1) as usize;
+        let y = y.clamp(0, self.height as isize - 1) as usize;
+        let index = (y * self.width + x) * 4;
+        [
+            self.rgba[index],
+            self.rgba[index + 1],
+            self.rgba[index + 2],
+            self.rgba[index + 3],
+        ]
+    }
+
+    /// Replaces every pixel with the `factor` by `factor` block `block` fills in for it, given
+    /// in rows from the top left
+    fn for_each(
+        &self,
+        factor: usize,
+        output: &mut Output,
+        block: impl Fn(&Image, isize, isize, &mut [Pixel]),
+    ) {
+        let mut pixels = vec![[0; 4]; factor * factor];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                block(self, x as isize, y as isize, &mut pixels);
+                for (i, pixel) in pixels.iter().enumerate() {
+                    output.set(x * factor + i % factor, y * factor + i / factor, *pixel);
+                }
+            }
+        }
+    }
+}
+
+struct Output<'a> {
+    rgba: &'a mut Vec<u8>,
+    width: usize,
+}
+
+impl Output<'_> {
+    fn set(&mut self, x: usize, y: usize, pixel: Pixel) {
+        let index = (y * self.width + x) * 4;
+        self.rgba[index..index + 4].copy_from_slice(&pixel);
+    }
+}
+
+/// The 3x3 neighbourhood of (x, y), named the way the Scale2x description does:
+/// ```text
+/// A B C
+/// D E F
+/// G H I
+/// ```
+fn neighbours(image: &Image, x: isize, y: isize) -> [Pixel; 9] {
+    let mut pixels = [[0; 4]; 9];
+    for (i, pixel) in pixels.iter_mut().enumerate() {
+        *pixel = image.get(x + i as isize % 3 - 1, y + i as isize / 3 - 1);
+    }
+    pixels
+}
+
+fn scale2x(image: &Image, x: isize, y: isize, block: &mut [Pixel]) {
+    let [_, b, _, d, e, f, _, h, _] = neighbours(image, x, y);
+    let pixels = if b != h && d != f {
+        [
+            if d == b { d } else { e },
+            if b == f { f } else { e },
+            if d == h { d } else { e },
+            if h == f { f } else { e },
+        ]
+    } else {
+        [e; 4]
+    };
+    block.copy_from_slice(&pixels);
+}
+
+fn scale3x(image: &Image, x: isize, y: isize, block: &mut [Pixel]) {
+    let [a, b, c, d, e, f, g, h, i] = neighbours(image, x, y);
+    let pixels = if b != h && d != f {
+        [
+            if d == b { d } else { e },
+            if (d == b && e != c) || (b == f && e != a) {
+                b
+            } else {
+                e
+            },
+            if b == f { f } else { e },
+            if (d == b && e != g) || (d == h && e != a) {
+                d
+            } else {
+                e
+            },
+            e,
+            if (b == f && e != i) || (h == f && e != c) {
+                f
+            } else {
+                e
+            },
+            if d == h { d } else { e },
+            if (d == h && e != i) || (h == f && e != g) {
+                h
+            } else {
+                e
+            },
+            if h == f { f } else { e },
+        ]
+    } else {
+        [e; 9]
+    };
+    block.copy_from_slice(&pixels);
+}
+
+/// Weighted average of `pixels`, rounded to nearest
+fn mix(pixels: &[(Pixel, u32)]) -> Pixel {
+    let total: u32 = pixels.iter().map(|(_, weight)| weight).sum();
+    let mut mixed = [0; 4];
+    for (channel, value) in mixed.iter_mut().enumerate() {
+        let sum: u32 = pixels
+            .iter()
+            .map(|(pixel, weight)| u32::from(pixel[channel]) * weight)
+            .sum();
+        *value = ((sum + total / 2) / total) as u8;
+    }
+    mixed
+}
+
+/// Weighted average of `pixels` whose weights add up to `1 << shift`, rounded down the way hqx
+/// and xBR do
+fn interpolate(pixels: &[(Pixel, u32)], shift: u32) -> Pixel {
+    let mut mixed = [0; 4];
+    for (channel, value) in mixed.iter_mut().enumerate() {
+        let sum: u32 = pixels
+            .iter()
+            .map(|(pixel, weight)| u32::from(pixel[channel]) * weight)
+            .sum();
+        *value = (sum >> shift) as u8;
+    }
+    mixed
+}
+
+fn yuv([r, g, b, _]: Pixel) -> [i32; 3] {
+    let [r, g, b] = [i32::from(r), i32::from(g), i32::from(b)];
+    [
+        (299 * r + 587 * g + 114 * b) / 1000,
+        (-169 * r - 331 * g + 500 * b) / 1000 + 128,
+        (500 * r - 419 * g - 81 * b) / 1000 + 128,
+    ]
+}
+
+/// hqx's test for two colours being close enough to count as the same
+fn similar(a: Pixel, b: Pixel) -> bool {
+    let [ya, ua, va] = yuv(a);
+    let [yb, ub, vb] = yuv(b);
+    (ya - yb).abs() <= 48 && (ua - ub).abs() <= 7 && (va - vb).abs() <= 6
+}
+
+/// The neighbourhood as hqx sees it from one corner of the middle pixel. `w` is the 3x3
+/// neighbourhood reordered so that the corner being filled in is always the top left, numbered
+/// like hqx does:
+/// ```text
+/// w0 w1 w2
+/// w3 w4 w5
+/// w6 w7 w8
+/// ```
+/// Bit n of `pattern` is set when the nth neighbour, skipping w4, isn't `similar` to w4.
+struct HqxCorner {
+    w: [Pixel; 9],
+    pattern: u8,
+}
+
+impl HqxCorner {
+    /// Views `neighbours` with `order[n]` as wn
+    fn new(neighbours: &[Pixel; 9], order: [usize; 9]) -> Self {
+        let w = order.map(|position| neighbours[position]);
+        let pattern = [0, 1, 2, 3, 5, 6, 7, 8]
+            .iter()
+            .enumerate()
+            .filter(|&(_, &position)| !similar(w[position], w[4]))
+            .fold(0, |pattern, (bit, _)| pattern | 1 << bit);
+        Self { w, pattern }
+    }
+
+    /// Whether the pattern masked by any of the rules' masks equals that rule's bits
+    fn matches(&self, rules: &[(u8, u8)]) -> bool {
+        rules
+            .iter()
+            .any(|&(mask, bits)| self.pattern & mask == bits)
+    }
+
+    /// Whether neighbours wa and wb aren't `similar`
+    fn differ(&self, a: usize, b: usize) -> bool {
+        !similar(self.w[a], self.w[b])
+    }
+}
+
+/// Orders viewing each quarter of an hq2x block as the top left, by mirroring
+const HQ2X_ORDERS: [[usize; 9]; 4] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8],
+    [2, 1, 0, 5, 4, 3, 8, 7, 6],
+    [6, 7, 8, 3, 4, 5, 0, 1, 2],
+    [8, 7, 6, 5, 4, 3, 2, 1, 0],
+];
+
+/// Orders viewing each corner of an hq3x block as the top left, by rotating clockwise. The
+/// side after each corner is filled in along with it.
+const HQ3X_ORDERS: [[usize; 9]; 4] = [
+    [0, 1, 2, 3, 4, 5, 6, 7, 8],
+    [2, 5, 8, 1, 4, 7, 0, 3, 6],
+    [8, 7, 6, 5, 4, 3, 2, 1, 0],
+    [6, 3, 0, 7, 4, 1, 8, 5, 2],
+];
+
+/// Patterns where hqx blends a corner lightly towards the diagonal neighbour when its sides
+/// differ, shared by hq2x and hq3x
+const HQX_CORNER_DIAGONAL: [(u8, u8); 13] = [
+    (0x6F, 0x2A),
+    (0x5B, 0x0A),
+    (0xBF, 0x3A),
+    (0xDF, 0x5A),
+    (0x9F, 0x8A),
+    (0xCF, 0x8A),
+    (0xEF, 0x4E),
+    (0x3F, 0x0E),
+    (0xFB, 0x5A),
+    (0xBB, 0x8A),
+    (0x7F, 0x5A),
+    (0xAF, 0x8A),
+    (0xEB, 0x8A),
+];
+
+/// The top left quarter of an hq2x block
+fn hq2x_corner(corner: &HqxCorner) -> Pixel {
+    let [w0, w1, _, w3, w4, ..] = corner.w;
+    let matches = |rules: &[(u8, u8)]| corner.matches(rules);
+    if matches(&[(0xBF, 0x37), (0xDB, 0x13)]) && corner.differ(1, 5) {
+        interpolate(&[(w4, 3), (w3, 1)], 2)
+    } else if matches(&[(0xDB, 0x49), (0xEF, 0x6D)]) && corner.differ(7, 3) {
+        interpolate(&[(w4, 3), (w1, 1)], 2)
+    } else if matches(&[(0x0B, 0x0B), (0xFE, 0x4A), (0xFE, 0x1A)]) && corner.differ(3, 1) {
+        w4
+    } else if matches(&HQX_CORNER_DIAGONAL) && corner.differ(3, 1) {
+        interpolate(&[(w4, 3), (w0, 1)], 2)
+    } else if matches(&[(0x0B, 0x08)]) {
+        interpolate(&[(w4, 2), (w0, 1), (w1, 1)], 2)
+    } else if matches(&[(0x0B, 0x02)]) {
+        interpolate(&[(w4, 2), (w0, 1), (w3, 1)], 2)
+    } else if matches(&[(0x2F, 0x2F)]) {
+        interpolate(&[(w4, 14), (w3, 1), (w1, 1)], 4)
+    } else if matches(&[(0xBF, 0x37), (0xDB, 0x13)]) {
+        interpolate(&[(w4, 5), (w1, 2), (w3, 1)], 3)
+    } else if matches(&[(0xDB, 0x49), (0xEF, 0x6D)]) {
+        interpolate(&[(w4, 5), (w3, 2), (w1, 1)], 3)
+    } else if matches(&[(0x1B, 0x03), (0x4F, 0x43), (0x8B, 0x83), (0x6B, 0x43)]) {
+        interpolate(&[(w4, 3), (w3, 1)], 2)
+    } else if matches(&[(0x4B, 0x09), (0x8B, 0x89), (0x1F, 0x19), (0x3B, 0x19)]) {
+        interpolate(&[(w4, 3), (w1, 1)], 2)
+    } else if matches(&[(0x7E, 0x2A), (0xEF, 0xAB), (0xBF, 0x8F), (0x7E, 0x0E)]) {
+        interpolate(&[(w4, 2), (w3, 3), (w1, 3)], 3)
+    } else if matches(&[
+        (0xFB, 0x6A),
+        (0x6F, 0x6E),
+        (0x3F, 0x3E),
+        (0xFB, 0xFA),
+        (0xDF, 0xDE),
+        (0xDF, 0x1E),
+    ]) {
+        interpolate(&[(w4, 6), (w3, 1), (w1, 1)], 3)
+    } else if matches(&[
+        (0x0A, 0x00),
+        (0x4F, 0x4B),
+        (0x9F, 0x1B),
+        (0x2F, 0x0B),
+        (0xBE, 0x0A),
+        (0xEE, 0x0A),
+        (0x7E, 0x0A),
+        (0xEB, 0x4B),
+        (0x3B, 0x1B),
+    ]) {
+        interpolate(&[(w4, 2), (w3, 1), (w1, 1)], 2)
+    } else {
+        interpolate(&[(w4, 6), (w3, 1), (w1, 1)], 3)
+    }
+}
+
+/// The top left corner of an hq3x block and the middle of its top side
+fn hq3x_corner(corner: &HqxCorner) -> (Pixel, Pixel) {
+    let [w0, w1, _, w3, w4, ..] = corner.w;
+    let matches = |rules: &[(u8, u8)]| corner.matches(rules);
+    let top_left = if matches(&[(0xBF, 0x37), (0xDB, 0x13)]) && corner.differ(1, 5) {
+        interpolate(&[(w4, 3), (w3, 1)], 2)
+    } else if matches(&[(0xDB, 0x49), (0xEF, 0x6D)]) && corner.differ(7, 3) {
+        interpolate(&[(w4, 3), (w1, 1)], 2)
+    } else if matches(&[(0x0B, 0x0B), (0xFE, 0x4A), (0xFE, 0x1A)]) && corner.differ(3, 1) {
+        w4
+    } else if matches(&HQX_CORNER_DIAGONAL) && corner.differ(3, 1) {
+        interpolate(&[(w4, 3), (w0, 1)], 2)
+    } else if matches(&[(0x4B, 0x09), (0x8B, 0x89), (0x1F, 0x19), (0x3B, 0x19)]) {
+        interpolate(&[(w4, 3), (w1, 1)], 2)
+    } else if matches(&[(0x1B, 0x03), (0x4F, 0x43), (0x8B, 0x83), (0x6B, 0x43)]) {
+        interpolate(&[(w4, 3), (w3, 1)], 2)
+    } else if matches(&[(0x7E, 0x2A), (0xEF, 0xAB), (0xBF, 0x8F), (0x7E, 0x0E)]) {
+        interpolate(&[(w3, 1), (w1, 1)], 1)
+    } else if matches(&[
+        (0x4F, 0x4B),
+        (0x9F, 0x1B),
+        (0x2F, 0x0B),
+        (0xBE, 0x0A),
+        (0xEE, 0x0A),
+        (0x7E, 0x0A),
+        (0xEB, 0x4B),
+        (0x3B, 0x1B),
+    ]) {
+        interpolate(&[(w4, 2), (w3, 7), (w1, 7)], 4)
+    } else if matches(&[
+        (0x0B, 0x08),
+        (0xF9, 0x68),
+        (0xF3, 0x62),
+        (0x6D, 0x6C),
+        (0x67, 0x66),
+        (0x3D, 0x3C),
+        (0x37, 0x36),
+        (0xF9, 0xF8),
+        (0xDD, 0xDC),
+        (0xF3, 0xF2),
+        (0xD7, 0xD6),
+        (0xDD, 0x1C),
+        (0xD7, 0x16),
+        (0x0B, 0x02),
+    ]) {
+        interpolate(&[(w4, 3), (w0, 1)], 2)
+    } else {
+        interpolate(&[(w4, 2), (w3, 1), (w1, 1)], 2)
+    };
+
+    // The side stays sharp where an edge runs past either end of it
+    let sharp_right = matches(&[
+        (0xFE, 0xDE),
+        (0x9E, 0x16),
+        (0xDA, 0x12),
+        (0x17, 0x16),
+        (0x5B, 0x12),
+        (0xBB, 0x12),
+    ]) && corner.differ(1, 5);
+    let sharp_left = matches(&[
+        (0x0F, 0x0B),
+        (0x5E, 0x0A),
+        (0xFB, 0x7B),
+        (0x3B, 0x0B),
+        (0xBE, 0x0A),
+        (0x7A, 0x0A),
+    ]) && corner.differ(3, 1);
+    let top = if sharp_right || sharp_left {
+        w4
+    } else if matches(&[(0xBF, 0x8F), (0x7E, 0x0E), (0xBF, 0x37), (0xDB, 0x13)]) {
+        interpolate(&[(w1, 3), (w4, 1)], 2)
+    } else if matches(&[
+        (0x02, 0x00),
+        (0x7C, 0x28),
+        (0xED, 0xA9),
+        (0xF5, 0xB4),
+        (0xD9, 0x90),
+    ]) {
+        interpolate(&[(w4, 3), (w1, 1)], 2)
+    } else if matches(&[
+        (0x4F, 0x4B),
+        (0xFB, 0x7B),
+        (0xFE, 0x7E),
+        (0x9F, 0x1B),
+        (0x2F, 0x0B),
+        (0xBE, 0x0A),
+        (0x7E, 0x0A),
+        (0xFB, 0x4B),
+        (0xFB, 0xDB),
+        (0xFE, 0xDE),
+        (0xFE, 0x56),
+        (0x57, 0x56),
+        (0x97, 0x16),
+        (0x3F, 0x1E),
+        (0xDB, 0x12),
+        (0xBB, 0x12),
+    ]) {
+        interpolate(&[(w4, 7), (w1, 1)], 3)
+    } else {
+        w4
+    };
+    (top_left, top)
+}
+
+fn hq2x(image: &Image, x: isize, y: isize, block: &mut [Pixel]) {
+    let neighbours = neighbours(image, x, y);
+    for (pixel, order) in block.iter_mut().zip(HQ2X_ORDERS) {
+        *pixel = hq2x_corner(&HqxCorner::new(&neighbours, order));
+    }
+}
+
+fn hq3x(image: &Image, x: isize, y: isize, block: &mut [Pixel]) {
+    let neighbours = neighbours(image, x, y);
+    block[4] = neighbours[4];
+    for order in HQ3X_ORDERS {
+        let (corner, side) = hq3x_corner(&HqxCorner::new(&neighbours, order));
+        block[order[0]] = corner;
+        block[order[1]] = side;
+    }
+}
+
+/// xBR's distance between two colours
+fn xbr_distance(a: Pixel, b: Pixel) -> i32 {
+    let [ya, ua, va] = yuv(a);
+    let [yb, ub, vb] = yuv(b);
+    (ya - yb).abs() + (ua - ub).abs() + (va - vb).abs()
+}
+
+/// xBR's test for two colours being close enough to count as the same
+fn xbr_equal(a: Pixel, b: Pixel) -> bool {
+    xbr_distance(a, b) < 155
+}
+
+/// Blends `weight` out of `1 << shift` of `b` into `a`
+fn xbr_blend(a: Pixel, b: Pixel, weight: u32, shift: u32) -> Pixel {
+    interpolate(&[(a, (1 << shift) - weight), (b, weight)], shift)
+}
+
+/// Rotations taking offsets around the bottom right corner, the one xBR's rules are written
+/// for, to each corner in the order xBR fills them in: bottom right, top right, top left and
+/// bottom left. (x, y) goes to (x * r[0] + y * r[1], x * r[2] + y * r[3]).
+const XBR_ROTATIONS: [[isize; 4]; 4] = [[1, 0, 0, 1], [0, 1, -1, 0], [-1, 0, 0, -1], [0, -1, 1, 0]];
+
+fn rotate(rotation: [isize; 4], x: isize, y: isize) -> (isize, isize) {
+    (
+        x * rotation[0] + y * rotation[1],
+        x * rotation[2] + y * rotation[3],
+    )
+}
+
+/// The part of the 5x5 neighbourhood around E that xBR level 1 looks at for its bottom right
+/// corner:
+/// ```text
+///    A  B  C
+///    D  E  F  F4
+///    G  H  I  I4
+///       H5 I5
+/// ```
+struct XbrNeighbours {
+    b: Pixel,
+    c: Pixel,
+    d: Pixel,
+    e: Pixel,
+    f: Pixel,
+    g: Pixel,
+    h: Pixel,
+    i: Pixel,
+    f4: Pixel,
+    i4: Pixel,
+    h5: Pixel,
+    i5: Pixel,
+}
+
+impl XbrNeighbours {
+    fn new(image: &Image, x: isize, y: isize, rotation: [isize; 4]) -> Self {
+        let at = |dx: isize, dy: isize| {
+            let (dx, dy) = rotate(rotation, dx, dy);
+            image.get(x + dx, y + dy)
+        };
+        Self {
+            b: at(0, -1),
+            c: at(1, -1),
+            d: at(-1, 0),
+            e: at(0, 0),
+            f: at(1, 0),
+            g: at(-1, 1),
+            h: at(0, 1),
+            i: at(1, 1),
+            f4: at(2, 0),
+            i4: at(2, 1),
+            h5: at(0, 2),
+            i5: at(1, 2),
+        }
+    }
+}
+
+/// What xBR level 1 finds across the bottom right corner of E
+enum XbrCorner {
+    /// Nothing to smooth
+    Flat,
+    /// An edge whose shape isn't clear, only the corner is blended halfway towards `px`
+    Unclear(Pixel),
+    /// An edge towards `px`. `left` means it's shallow and carries on along the bottom, `up`
+    /// that it's steep and carries on up the side, both that it's a 45° diagonal.
+    Edge { px: Pixel, left: bool, up: bool },
+}
+
+/// Compares the weighted distances along both diagonals of the corner; the smaller one runs
+/// along an edge. The 2x and 3x versions differ in what makes the edge clear.
+fn xbr_corner(n: &XbrNeighbours, factor: usize) -> XbrCorner {
+    let df = xbr_distance;
+    let eq = xbr_equal;
+    if n.e == n.h || n.e == n.f {
+        return XbrCorner::Flat;
+    }
+    let along_e_i = df(n.e, n.c) + df(n.e, n.g) + df(n.i, n.h5) + df(n.i, n.f4) + 4 * df(n.h, n.f);
+    let along_h_f = df(n.h, n.d) + df(n.h, n.i5) + df(n.f, n.i4) + df(n.f, n.b) + 4 * df(n.e, n.i);
+    if along_e_i > along_h_f {
+        return XbrCorner::Flat;
+    }
+    let px = if df(n.e, n.f) <= df(n.e, n.h) {
+        n.f
+    } else {
+        n.h
+    };
+    let clear = if factor == 2 {
+        (!eq(n.f, n.b) && !eq(n.h, n.d))
+            || (eq(n.e, n.i) && !eq(n.f, n.i4) && !eq(n.h, n.i5))
+            || eq(n.e, n.g)
+            || eq(n.e, n.c)
+    } else {
+        (!eq(n.f, n.b) && !eq(n.f, n.c))
+            || (!eq(n.h, n.d) && !eq(n.h, n.g))
+            || (eq(n.e, n.i)
+                && ((!eq(n.f, n.f4) && !eq(n.f, n.i4)) || (!eq(n.h, n.h5) && !eq(n.h, n.i5))))
+            || eq(n.e, n.g)
+            || eq(n.e, n.c)
+    };
+    if along_e_i < along_h_f && clear {
+        let ke = df(n.f, n.g);
+        let ki = df(n.h, n.c);
+        XbrCorner::Edge {
+            px,
+            left: 2 * ke <= ki && n.e != n.g && n.d != n.g,
+            up: ke >= 2 * ki && n.e != n.c && n.b != n.c,
+        }
+    } else {
+        XbrCorner::Unclear(px)
+    }
+}
+
+/// xBR level 1, filling `block` in a corner at a time. Corners can blend over sub-pixels earlier
+/// ones already blended.
+fn xbr(image: &Image, x: isize, y: isize, factor: usize, block: &mut [Pixel]) {
+    block.fill(image.get(x, y));
+    for rotation in XBR_ROTATIONS {
+        let corner = xbr_corner(&XbrNeighbours::new(image, x, y, rotation), factor);
+        // The sub-pixel at (sx, sy), counted from the middle of the block in half pixels for
+        // 2x and whole ones for 3x, of the rotated block
+        let sub = |sx: isize, sy: isize| {
+            let (sx, sy) = rotate(rotation, sx, sy);
+            if factor == 2 {
+                ((sy + 1) / 2 * 2 + (sx + 1) / 2) as usize
+            } else {
+                ((sy + 1) * 3 + sx + 1) as usize
+            }
+        };
+        let mut blend = |sx: isize, sy: isize, px: Pixel, weight: u32, shift: u32| {
+            let index = sub(sx, sy);
+            block[index] = xbr_blend(block[index], px, weight, shift);
+        };
+        match (factor, corner) {
+            (_, XbrCorner::Flat) => {}
+            (2, XbrCorner::Unclear(px)) => blend(1, 1, px, 1, 1),
+            (2, XbrCorner::Edge { px, left, up }) => match (left, up) {
+                (true, true) => {
+                    blend(1, 1, px, 7, 3);
+                    blend(-1, 1, px, 1, 2);
+                    block[sub(1, -1)] = block[sub(-1, 1)];
+                }
+                (true, false) => {
+                    blend(1, 1, px, 3, 2);
+                    blend(-1, 1, px, 1, 2);
+                }
+                (false, true) => {
+                    blend(1, 1, px, 3, 2);
+                    blend(1, -1, px, 1, 2);
+                }
+                (false, false) => blend(1, 1, px, 1, 1),
+            },
+            (_, XbrCorner::Unclear(px)) => blend(1, 1, px, 1, 1),
+            (_, XbrCorner::Edge { px, left, up }) => match (left, up) {
+                (true, true) => {
+                    blend(0, 1, px, 3, 2);
+                    blend(-1, 1, px, 1, 2);
+                    block[sub(1, 0)] = block[sub(0, 1)];
+                    block[sub(1, -1)] = block[sub(-1, 1)];
+                    block[sub(1, 1)] = px;
+                }
+                (true, false) => {
+                    blend(0, 1, px, 3, 2);
+                    blend(1, 0, px, 1, 2);
+                    blend(-1, 1, px, 1, 2);
+                    block[sub(1, 1)] = px;
+                }
+                (false, true) => {
+                    blend(1, 0, px, 3, 2);
+                    blend(0, 1, px, 1, 2);
+                    blend(1, -1, px, 1, 2);
+                    block[sub(1, 1)] = px;
+                }
+                (false, false) => {
+                    blend(1, 1, px, 7, 3);
+                    blend(1, 0, px, 1, 3);
+                    blend(0, 1, px, 1, 3);
+                }
+            },
+        }
+    }
+}
+
+fn pixel_aspect(image: &Image, output: &mut Output) {
+    // Positions are kept in units of 1/(width * scaled_width) of the image, so every boundary
+    // falls on an integer and the averages come out exact
+    let width = image.width;
+    let scaled_width = output.width;
+    for y in 0..image.height {
+        for column in 0..scaled_width {
+            let start = column * width;
+            let end = start + width;
+            // Output columns are narrower than input ones, so at most two overlap each
+            let mut pixels = [([0; 4], 0); 2];
+            let mut count = 0;
+            for source in start / scaled_width..end.div_ceil(scaled_width).min(width) {
+                let overlap =
+                    end.min((source + 1) * scaled_width) - start.max(source * scaled_width);
+                if overlap > 0 {
+                    pixels[count] = (image.get(source as isize, y as isize), overlap as u32);
+                    count += 1;
+                }
+            }
+            output.set(column, y, mix(&pixels[..count]));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Scaler;
+
+    const WHITE: [u8; 4] = [0xFF; 4];
+    const BLACK: [u8; 4] = [0, 0, 0, 0xFF];
+
+    /// Scales a picture drawn with `#` for white and `.` for black, returning it in the same form
+    fn scale_art(scaler: Scaler, art: &[&str]) -> Vec<String> {
+        let rgba: Vec<u8> = art
+            .iter()
+            .flat_map(|row| row.chars())
+            .flat_map(|pixel| if pixel == '#' { WHITE } else { BLACK })
+            .collect();
+        let mut scaled = Vec::new();
+        let (width, _) = scaler.scale(&rgba, art[0].len(), art.len(), &mut scaled);
+        scaled
+            .chunks_exact(width * 4)
+            .map(|row| {
+                row.chunks_exact(4)
+                    .map(|pixel| if pixel == WHITE { '#' } else { '.' })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Like `scale_art`, but shows the red channel of every pixel in hex to check blends
+    fn scale_red(scaler: Scaler, art: &[&str]) -> Vec<String> {
+        let rgba: Vec<u8> = art
+            .iter()
+            .flat_map(|row| row.chars())
+            .flat_map(|pixel| if pixel == '#' { WHITE } else { BLACK })
+            .collect();
+        let mut scaled = Vec::new();
+        let (width, _) = scaler.scale(&rgba, art[0].len(), art.len(), &mut scaled);
+        scaled
+            .chunks_exact(width * 4)
+            .map(|row| {
+                row.chunks_exact(4)
+                    .map(|pixel| format!("{:02X}", pixel[0]))
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect()
+    }
+
+    const CHECKER: [&str; 2] = ["#.", ".#"];
+    const DIAGONAL: [&str; 4] = ["#...", ".#..", "..#.", "...#"];
+    const LONE: [&str; 3] = ["...", ".#.", "..."];
+    const SLOPE: [&str; 4] = ["....", "..##", "####", "####"];
+
+    #[test]
+    fn scale2x_checker() {
+        assert_eq!(
+            scale_art(Scaler::Scale2x, &CHECKER),
+            ["##..", "#.#.", ".#.#", "..##"]
+        );
+    }
+
+    #[test]
+    fn scale3x_checker() {
+        assert_eq!(
+            scale_art(Scaler::Scale3x, &CHECKER),
+            ["###...", "##.#..", "#..##.", ".##..#", "..#.##", "...###"]
+        );
+    }
+
+    #[test]
+    fn scale2x_diagonal() {
+        assert_eq!(
+            scale_art(Scaler::Scale2x, &DIAGONAL),
+            [
+                "##......", "#.#.....", ".###....", "..###...", "...###..", "....###.", ".....#.#",
+                "......##",
+            ]
+        );
+    }
+
+    #[test]
+    fn scale3x_diagonal() {
+        assert_eq!(
+            scale_art(Scaler::Scale3x, &DIAGONAL),
+            [
+                "###.........",
+                "##.#........",
+                "#..#........",
+                ".#####......",
+                "...###......",
+                "...####.....",
+                ".....####...",
+                "......###...",
+                "......#####.",
+                "........#..#",
+                "........#.##",
+                ".........###",
+            ]
+        );
+    }
+
+    #[test]
+    fn hq2x_lone_pixel() {
+        assert_eq!(
+            scale_red(Scaler::Hq2x, &LONE),
+            [
+                "00 00 00 00 00 00",
+                "00 00 00 00 00 00",
+                "00 00 DF DF 00 00",
+                "00 00 DF DF 00 00",
+                "00 00 00 00 00 00",
+                "00 00 00 00 00 00",
+            ]
+        );
+    }
+
+    #[test]
+    fn hq2x_diagonal() {
+        assert_eq!(
+            scale_red(Scaler::Hq2x, &DIAGONAL),
+            [
+                "FF FF 3F 00 00 00 00 00",
+                "FF BF BF 00 00 00 00 00",
+                "3F BF BF 7F 00 00 00 00",
+                "00 00 7F BF 7F 00 00 00",
+                "00 00 00 7F BF 7F 00 00",
+                "00 00 00 00 7F BF BF 3F",
+                "00 00 00 00 00 BF BF FF",
+                "00 00 00 00 00 3F FF FF",
+            ]
+        );
+    }
+
+    #[test]
+    fn hq3x_diagonal() {
+        assert_eq!(
+            scale_red(Scaler::Hq3x, &DIAGONAL),
+            [
+                "FF FF FF 3F 00 00 00 00 00 00 00 00",
+                "FF FF FF BF 00 00 00 00 00 00 00 00",
+                "FF FF 7F FF 3F 00 00 00 00 00 00 00",
+                "3F BF FF FF DF 1F 00 00 00 00 00 00",
+                "00 00 3F DF FF DF 1F 00 00 00 00 00",
+                "00 00 00 1F DF FF DF 1F 00 00 00 00",
+                "00 00 00 00 1F DF FF DF 1F 00 00 00",
+                "00 00 00 00 00 1F DF FF DF 3F 00 00",
+                "00 00 00 00 00 00 1F DF FF FF BF 3F",
+                "00 00 00 00 00 00 00 3F FF 7F FF FF",
+                "00 00 00 00 00 00 00 00 BF FF FF FF",
+                "00 00 00 00 00 00 00 00 3F FF FF FF",
+            ]
+        );
+    }
+
+    #[test]
+    fn xbr2x_diagonal() {
+        assert_eq!(
+            scale_red(Scaler::Xbr2x, &DIAGONAL),
+            [
+                "FF FF 3F 00 00 00 00 00",
+                "FF FF BF 00 00 00 00 00",
+                "3F BF FF 7F 00 00 00 00",
+                "00 00 7F FF 7F 00 00 00",
+                "00 00 00 7F FF 7F 00 00",
+                "00 00 00 00 7F FF BF 3F",
+                "00 00 00 00 00 BF FF FF",
+                "00 00 00 00 00 3F FF FF",
+            ]
+        );
+    }
+
+    #[test]
+    fn xbr2x_slope() {
+        assert_eq!(
+            scale_red(Scaler::Xbr2x, &SLOPE),
+            [
+                "00 00 00 00 00 00 00 00",
+                "00 00 00 00 00 00 00 00",
+                "00 00 00 00 3F BF FF FF",
+                "00 00 3F BF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF",
+            ]
+        );
+    }
+
+    #[test]
+    fn xbr3x_slope() {
+        assert_eq!(
+            scale_red(Scaler::Xbr3x, &SLOPE),
+            [
+                "00 00 00 00 00 00 00 00 00 00 00 00",
+                "00 00 00 00 00 00 00 00 00 00 00 00",
+                "00 00 00 00 00 00 00 00 00 00 00 00",
+                "00 00 00 00 00 00 00 3F BF FF FF FF",
+                "00 00 00 00 00 3F BF FF FF FF FF FF",
+                "00 00 00 3F BF FF FF FF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF FF FF FF FF",
+                "FF FF FF FF FF FF FF FF FF FF FF FF",
+            ]
+        );
+    }
+
+    #[test]
+    fn pixel_aspect_weights_columns_by_overlap() {
+        assert_eq!(Scaler::PixelAspect.output_size(256, 240), (293, 240));
+        // Measured in 1/293ths of a source column, output column c covers [256c, 256c + 256)
+        let impulse = |source: usize| {
+            let rgba: Vec<u8> = (0..256)
+                .flat_map(|x| if x == source { WHITE } else { BLACK })
+                .collect();
+            let mut scaled = Vec::new();
+            Scaler::PixelAspect.scale(&rgba, 256, 1, &mut scaled);
+            scaled
+                .chunks_exact(4)
+                .map(|pixel| pixel[0])
+                .collect::<Vec<u8>>()
+        };
+
+        // Source 1 covers [293, 586): 219 of column 1 and 74 of column 2
+        let spread = impulse(1);
+        assert_eq!(&spread[..4], &[0, 218, 74, 0]);
+        // Column 0 lies entirely within source 0, and column 292 within source 255
+        assert_eq!(impulse(0)[0], 0xFF);
+        assert_eq!(impulse(255)[292], 0xFF);
+        // Every source spreads one column's worth of weight, 293/256 of an output column
+        for source in [0, 1, 100, 128, 200, 255] {
+            let total: u32 = impulse(source).iter().map(|&value| u32::from(value)).sum();
+            assert!(total.abs_diff(292) <= 2, "source {source} spreads {total}");
+        }
+    }
+}